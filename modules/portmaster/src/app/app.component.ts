@@ -12,7 +12,7 @@ import { fadeInAnimation, fadeOutAnimation } from './shared/animations';
 import { ExitService } from './shared/exit-screen';
 import { SfngNetquerySearchOverlayComponent } from './shared/netquery/search-overlay';
 import { INTEGRATION_SERVICE, IntegrationService } from './integration';
-import { TauriIntegrationService } from './integration/taur-app';
+import { Navigation, TauriIntegrationService } from './integration/taur-app';
 
 @Component({
   selector: 'app-root',
@@ -211,6 +211,24 @@ export class AppComponent implements OnInit, AfterViewInit {
             tauri.openApp();
           }
         });
+
+      const navigate = (navigation: Navigation | null) => {
+        if (!navigation) {
+          return;
+        }
+
+        this.ngZone.run(() => {
+          this.router.navigate([navigation.path], {
+            queryParams: navigation.query,
+          });
+        });
+      }
+
+      tauri.takePendingNavigation().then(navigate);
+      tauri.onNavigate(() => {
+        // take the pending navigation so it's not applied again after a reload.
+        tauri.takePendingNavigation().then(navigate);
+      });
     }
   }
 
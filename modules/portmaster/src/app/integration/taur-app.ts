@@ -43,6 +43,12 @@ function asyncInvoke<T>(method: string, args: object): Promise<T> {
   })
 }
 
+/** A route of the application that tauri wants us to navigate to. */
+export interface Navigation {
+  path: string;
+  query: { [key: string]: string };
+}
+
 export type ServiceManagerStatus = 'Running' | 'Stopped' | 'NotFound' | 'unsupported service manager' | 'unsupported operating system';
 
 export class TauriIntegrationService implements IntegrationService {
@@ -130,6 +136,38 @@ export class TauriIntegrationService implements IntegrationService {
     }
   }
 
+  /**
+   * Returns the route tauri asked us to navigate to while we were still
+   * bootstrapping, if any.
+   */
+  async takePendingNavigation(): Promise<Navigation | null> {
+    try {
+      const response = await invoke<string>("plugin:portmaster|take_pending_navigation");
+      return response ? JSON.parse(response) : null;
+    } catch (err) {
+      console.error(err);
+      return null;
+    }
+  }
+
+  /**
+   * Registers a listener for navigation requests, for example when the user
+   * selected an action of a system notification.
+   */
+  onNavigate(cb: (navigation: Navigation) => void): () => void {
+    let unlisten: () => void = () => { };
+
+    listen<Navigation>('portmaster:navigate', (event) => {
+      cb(event.payload);
+    }).then(cleanup => {
+      unlisten = cleanup;
+    })
+
+    return () => {
+      unlisten();
+    }
+  }
+
   get_state(key: string): Promise<string> {
     return invoke<string>("plugin:portmaster|get_state");
   }
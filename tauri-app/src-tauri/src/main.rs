@@ -16,7 +16,7 @@ mod portmaster;
 mod traymenu;
 mod window;
 
-use log::{debug, error, info, trace, warn};
+use log::{debug, error};
 use portmaster::PortmasterExt;
 use traymenu::setup_tray_menu;
 use window::{close_splash_window, create_main_window};
@@ -38,7 +38,7 @@ struct WsHandler {
 }
 
 impl portmaster::Handler for WsHandler {
-    fn on_connect(&mut self, cli: portapi::client::PortAPI) -> () {
+    fn on_connect(&mut self, cli: portapi::client::PortAPI) {
         // we successfully connected to Portmaster. Set is_first_connect to false
         // so we don't show the splash-screen when we loose connection.
         self.is_first_connect = false;
@@ -115,30 +115,21 @@ fn main() {
                     debug!("cli matches={:?}", matches);
 
                     if let Some(bg_flag) = matches.args.get("background") {
-                        match bg_flag.value.as_bool() {
-                            Some(value) => {
-                                background = value;
-                                app.portmaster().set_show_after_bootstrap(!background);
-                            }
-                            None => {}
+                        if let Some(value) = bg_flag.value.as_bool() {
+                            background = value;
+                            app.portmaster().set_show_after_bootstrap(!background);
                         }
                     }
 
                     if let Some(nf_flag) = matches.args.get("with-notifications") {
-                        match nf_flag.value.as_bool() {
-                            Some(v) => {
-                                app.portmaster().with_notification_support(v);
-                            }
-                            None => {}
+                        if let Some(v) = nf_flag.value.as_bool() {
+                            app.portmaster().with_notification_support(v);
                         }
                     }
 
                     if let Some(pf_flag) = matches.args.get("with-prompts") {
-                        match pf_flag.value.as_bool() {
-                            Some(v) => {
-                                app.portmaster().with_connection_prompts(v);
-                            }
-                            None => {}
+                        if let Some(v) = pf_flag.value.as_bool() {
+                            app.portmaster().with_connection_prompts(v);
                         }
                     }
                 }
@@ -180,19 +171,16 @@ fn main() {
             // Note: the above javascript does NOT trigger the CloseRequested event so
             // there's no need to handle that case here.
             //
-            match event {
-                WindowEvent::CloseRequested { api, .. } => {
-                    debug!(
-                        "window (label={}) close request received, forwarding to user-interface.",
-                        label
-                    );
-
-                    api.prevent_close();
-                    if let Some(window) = handle.get_window(label.as_str()) {
-                        let _ = window.emit("exit-requested", "");
-                    }
+            if let WindowEvent::CloseRequested { api, .. } = event {
+                debug!(
+                    "window (label={}) close request received, forwarding to user-interface.",
+                    label
+                );
+
+                api.prevent_close();
+                if let Some(window) = handle.get_window(label.as_str()) {
+                    let _ = window.emit("exit-requested", "");
                 }
-                _ => {}
             }
         }
 
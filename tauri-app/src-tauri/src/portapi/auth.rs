@@ -0,0 +1,141 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The name of the cookie portbase uses for API sessions.
+pub const SESSION_COOKIE: &str = "Portmaster-API-Session";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CredentialsError {
+    #[error("no API key or session token provided")]
+    Empty,
+
+    #[error("API keys and session tokens must only contain visible ASCII characters")]
+    InvalidCharacters,
+}
+
+/// Credentials used to authenticate against a Portmaster API that requires
+/// API keys.
+///
+/// Credentials are parsed from their textual representation which is either the
+/// plain API key or `Portmaster-API-Session=<token>` for an existing session.
+/// Surrounding whitespace is ignored so they can be read from files as-is.
+#[derive(Clone, PartialEq)]
+pub enum Credentials {
+    /// An API key sent as `Authorization: Bearer <key>`.
+    ApiKey(String),
+
+    /// A portbase session token sent as the `Portmaster-API-Session` cookie.
+    Session(String),
+}
+
+impl Credentials {
+    /// Reads credentials from a file. Returns None if the file does not exist.
+    pub fn from_file(path: &Path) -> std::io::Result<Option<Credentials>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        content
+            .parse()
+            .map(Some)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    /// Returns the name of the HTTP header that carries the credentials.
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            Credentials::ApiKey(_) => "Authorization",
+            Credentials::Session(_) => "Cookie",
+        }
+    }
+
+    /// Returns the value of the HTTP header that carries the credentials.
+    pub fn header_value(&self) -> String {
+        match self {
+            Credentials::ApiKey(key) => format!("Bearer {}", key),
+            Credentials::Session(token) => format!("{}={}", SESSION_COOKIE, token),
+        }
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = CredentialsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let credentials = match s
+            .strip_prefix(SESSION_COOKIE)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            Some(token) => Credentials::Session(token.to_string()),
+            None => Credentials::ApiKey(s.to_string()),
+        };
+
+        let secret = match &credentials {
+            Credentials::ApiKey(secret) | Credentials::Session(secret) => secret,
+        };
+
+        if secret.is_empty() {
+            return Err(CredentialsError::Empty);
+        }
+
+        // the secret ends up in a header value or cookie so don't allow anything
+        // that would need escaping.
+        if !secret
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != ';' && c != ',')
+        {
+            return Err(CredentialsError::InvalidCharacters);
+        }
+
+        Ok(credentials)
+    }
+}
+
+/// Debug implementation that does not leak the secret into logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::ApiKey(_) => f.write_str("ApiKey(***)"),
+            Credentials::Session(_) => f.write_str("Session(***)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_credentials() {
+        let key: Credentials = " secret-key\n".parse().unwrap();
+        assert_eq!(key, Credentials::ApiKey("secret-key".to_string()));
+        assert_eq!(key.header_name(), "Authorization");
+        assert_eq!(key.header_value(), "Bearer secret-key");
+        assert_eq!(format!("{:?}", key), "ApiKey(***)");
+
+        let session: Credentials = "Portmaster-API-Session=abc".parse().unwrap();
+        assert_eq!(session, Credentials::Session("abc".to_string()));
+        assert_eq!(session.header_name(), "Cookie");
+        assert_eq!(session.header_value(), "Portmaster-API-Session=abc");
+
+        assert_eq!("  ".parse::<Credentials>(), Err(CredentialsError::Empty));
+        assert_eq!(
+            "Portmaster-API-Session=".parse::<Credentials>(),
+            Err(CredentialsError::Empty)
+        );
+        assert_eq!(
+            "secret key".parse::<Credentials>(),
+            Err(CredentialsError::InvalidCharacters)
+        );
+        assert_eq!(
+            "a;b".parse::<Credentials>(),
+            Err(CredentialsError::InvalidCharacters)
+        );
+    }
+}
@@ -1,15 +1,105 @@
-use futures_util::{SinkExt, StreamExt};
-use http::Uri;
-use log::{debug, error, warn};
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::RwLock;
-use tokio_websockets::{ClientBuilder, Error};
+use bytes::Bytes;
+use futures_util::{ready, SinkExt, StreamExt};
+use http::{HeaderName, HeaderValue, Uri};
+use log::{debug, error, info, warn};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::sync::{watch, RwLock};
+use thiserror::Error as ThisError;
+use tokio::time::{sleep, sleep_until, Instant};
+use tokio_websockets::{upgrade, ClientBuilder, Error, WebSocketStream};
 
+use super::auth::Credentials;
+use super::key::DbKey;
 use super::message::*;
+use super::models::config::ConfigError;
+#[cfg(test)]
+use super::query::Condition;
+use super::query::Query;
+use super::recording::{Event, FrameData, Recorder};
+use super::subscription::*;
 use super::types::*;
 
+/// The error message portbase sends if a record does not exist.
+const NOT_FOUND: &str = "database entry not found";
+
+/// ClientError is returned by `PortAPI` if a request cannot be sent or, for the
+/// one-shot helpers like `PortAPI::get`, if the request failed.
+#[derive(Debug, ThisError)]
+pub enum ClientError {
+    #[error("connection to the Portmaster API has been closed")]
+    Closed,
+
+    #[error(transparent)]
+    Message(#[from] MessageError),
+
+    #[error("connection lost before the request finished")]
+    ConnectionLost,
+
+    #[error("database entry not found")]
+    NotFound,
+
+    #[error("server error: {0}")]
+    Server(String),
+
+    #[error("request timed out")]
+    Timeout,
+
+    #[error("failed to parse record {0}: {1}")]
+    Parse(String, ParseError),
+
+    #[error("unexpected response: {0:?}")]
+    Unexpected(Response),
+
+    #[error("the Portmaster API requires authentication, please configure an API key")]
+    AuthRequired,
+
+    #[error("failed to connect: {0}")]
+    Connect(Error),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error("failed to encode payload: {0}")]
+    Encode(#[from] EncodeError),
+}
+
+impl From<Error> for ClientError {
+    /// Converts a websocket error into a client error. The Portmaster API responds
+    /// with 401 or 403 to upgrade requests with missing or invalid credentials.
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Upgrade(upgrade::Error::DidNotSwitchProtocols(401 | 403)) => {
+                ClientError::AuthRequired
+            }
+            err => ClientError::Connect(err),
+        }
+    }
+}
+
+impl ClientError {
+    /// Converts a response that finished a request unexpectedly into an error.
+    fn from_response(response: Response) -> Self {
+        match response {
+            Response::Error(msg) if msg == NOT_FOUND => ClientError::NotFound,
+            Response::Error(msg) => ClientError::Server(msg),
+            Response::Malformed(err) => ClientError::Message(err),
+            Response::ConnectionLost => ClientError::ConnectionLost,
+            other => ClientError::Unexpected(other),
+        }
+    }
+}
+
 /// An internal representation of a Command that
 /// contains the PortAPI message as well as a response
 /// channel that will receive all responses sent from the
@@ -21,47 +111,482 @@ use super::types::*;
 struct Command {
     msg: Message,
     response: Sender<Response>,
+    subscription: bool,
+    cancelable: bool,
 }
 
 /// The client implementation for PortAPI.
 #[derive(Clone)]
 pub struct PortAPI {
     dispatch: Sender<Command>,
+    cancel: UnboundedSender<usize>,
+    next_id: Arc<AtomicUsize>,
+    state: watch::Receiver<ConnectionState>,
+    #[cfg(test)]
+    recorder: SharedRecorder,
+
+    // set by resilient clients if the last connection attempt has been rejected
+    // because of missing or invalid credentials.
+    auth_required: Arc<AtomicBool>,
+}
+
+/// A subscriber waiting for responses of a command.
+struct Subscriber {
+    response: Sender<Response>,
+
+    // whether the command is a `sub` or `qsub` which stays active after `done`.
+    subscription: bool,
+
+    // whether the command must be canceled if the subscriber is dropped.
+    cancelable: bool,
+
+    // the original request of `sub` and `qsub` commands. It's sent again once a
+    // resilient client re-established the connection.
+    request: Option<Message>,
 }
 
 /// The map type used to store message subscribers.
-type SubscriberMap = RwLock<HashMap<usize, Sender<Response>>>;
+type SubscriberMap = RwLock<HashMap<usize, Subscriber>>;
+
+/// The recorder shared between a `PortAPI` client and it's connection.
+type SharedRecorder = Arc<std::sync::Mutex<Option<Recorder>>>;
+
+/// ResponseReceiver is a handle for a request sent using `PortAPI::request` and receives
+/// all responses sent by the server for the request.
+///
+/// Dropping the handle (or calling `cancel`) of a streaming request (`query`, `sub`
+/// and `qsub`) that has not yet finished sends `cancel` to the server so it stops
+/// sending data nobody reads.
+pub struct ResponseReceiver {
+    id: usize,
+    rx: Receiver<Response>,
+    cancel: Option<UnboundedSender<usize>>,
+    subscription: bool,
+}
+
+impl ResponseReceiver {
+    /// Returns the command id of the request.
+    #[cfg(test)]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Receives the next response for the request. Returns None once the request
+    /// finished and all responses have been received.
+    pub async fn recv(&mut self) -> Option<Response> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Polls to receive the next response for the request.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Response>> {
+        let res = ready!(self.rx.poll_recv(cx));
+
+        let finished = match &res {
+            Some(response) => response.is_terminal(self.subscription),
+            None => true,
+        };
+
+        // there's nothing left to cancel.
+        if finished {
+            self.cancel = None;
+        }
+
+        Poll::Ready(res)
+    }
+
+    /// Cancels the request. Responses that have already been received can still be
+    /// read using `recv`.
+    pub fn cancel(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(self.id);
+        }
+
+        self.rx.close();
+    }
+}
+
+impl Drop for ResponseReceiver {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Wraps a plain response channel that is not connected to a `PortAPI` client.
+impl From<Receiver<Response>> for ResponseReceiver {
+    fn from(rx: Receiver<Response>) -> Self {
+        ResponseReceiver {
+            id: 0,
+            rx,
+            cancel: None,
+            subscription: false,
+        }
+    }
+}
+
+/// ConnectionState describes the connection of a `PortAPI` client to the server. Use
+/// `PortAPI::watch_state` to get notified whenever it changes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// The client is connecting to the server for the first time.
+    Connecting,
+
+    /// The client is connected to the server.
+    Connected,
+
+    /// A resilient client is trying to re-establish the connection. The first
+    /// attempt after the connection has been lost is 1.
+    Reconnecting { attempt: u32 },
+
+    /// The connection has been lost or could not be established. Resilient clients
+    /// wait for the backoff delay before reconnecting.
+    Disconnected { reason: String },
+}
+
+/// Keepalive configures the websocket pings a client sends to detect dead connections
+/// (e.g. half-open TCP connections after the machine resumed from sleep).
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// How long the connection may be idle before a ping is sent.
+    pub interval: Duration,
+
+    /// How long to wait for the server to answer a ping before the connection is
+    /// considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// ReconnectPolicy configures the exponential backoff a resilient client (see
+/// `connect_resilient`) uses between connection attempts.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The delay before the first reconnection attempt.
+    pub initial_delay: Duration,
+
+    /// The upper bound of the delay between two connection attempts.
+    pub max_delay: Duration,
+
+    /// The factor the delay is multiplied with after each failed attempt.
+    pub multiplier: f64,
+
+    /// The fraction of the delay (0.0 to 1.0) that is randomized so multiple
+    /// clients don't reconnect at the very same time.
+    pub jitter: f64,
+
+    /// How long a connection attempt, including the websocket upgrade, may take
+    /// before it is considered failed.
+    pub connect_timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the given (zero based) connection attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = i32::try_from(attempt).unwrap_or(i32::MAX);
+
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exp))
+            .min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+
+        Duration::from_secs_f64((base * factor).max(0.0))
+    }
+}
 
 /// Connect to PortAPI at the specified URI.
 ///
 /// This method will launch a new async thread on the `tauri::async_runtime`
 /// that will handle message to transmit and also multiplex server responses
 /// to the appropriate subscriber.
-pub async fn connect(uri: &str) -> Result<PortAPI, Error> {
-    let parsed = match uri.parse::<Uri>() {
-        Ok(u) => u,
-        Err(_e) => {
-            return Err(Error::NoUriConfigured); // TODO(ppacher): fix the return error type.
+///
+/// The returned client is closed once the connection is lost. Use `connect_resilient`
+/// for a client that automatically reconnects.
+#[cfg(test)]
+pub async fn connect(uri: &str) -> Result<PortAPI, ClientError> {
+    connect_with_credentials(uri, None).await
+}
+
+/// Like `connect` but authenticates using credentials. Returns
+/// `ClientError::AuthRequired` if the server rejected the credentials.
+#[cfg(test)]
+pub async fn connect_with_credentials(
+    uri: &str,
+    credentials: Option<&Credentials>,
+) -> Result<PortAPI, ClientError> {
+    let parsed = parse_uri(uri)?;
+
+    let (mut client, _) = client_builder(parsed, credentials).connect().await?;
+    let (api, mut conn) = PortAPI::new(None, None);
+
+    conn.set_state(ConnectionState::Connected);
+
+    tauri::async_runtime::spawn(async move {
+        let reason = conn.run(&mut client).await;
+
+        conn.set_state(ConnectionState::Disconnected { reason });
+
+        // make sure no new commands are queued and fail all commands that
+        // have been queued already.
+        conn.dispatch.close();
+        while let Ok(cmd) = conn.dispatch.try_recv() {
+            let _ = cmd.response.send(Response::ConnectionLost).await;
         }
-    };
 
-    let (mut client, _) = ClientBuilder::from_uri(parsed).connect().await?;
-    let (tx, mut dispatch) = channel::<Command>(64);
+        conn.connection_lost(false).await;
+    });
+
+    Ok(api)
+}
+
+/// Connect to PortAPI at the specified URI and keep reconnecting, using the backoff
+/// configured in policy, whenever the connection is lost.
+///
+/// Unlike `connect`, this method does not wait for the connection to be established.
+/// Requests are queued until the client is connected, use `PortAPI::is_connected` to
+/// check the current connection state.
+///
+/// Active subscriptions (`sub` and `qsub`) are sent again after the client reconnected
+/// and receive a `Response::Reconnected` followed by the current set of matching records.
+/// Any other requests that were pending when the connection was lost receive
+/// `Response::ConnectionLost`.
+///
+/// If the server rejects credentials, the client keeps trying but new requests fail
+/// with `ClientError::AuthRequired` until a connection could be established.
+///
+/// If keepalive is set, the client pings the server when the connection is idle and
+/// reconnects if the server does not answer in time.
+///
+/// If recorder is set, the whole session is recorded, starting with the first
+/// connection attempt. See `PortAPI::set_recorder` to attach a recorder later on.
+pub async fn connect_resilient(
+    uri: &str,
+    policy: ReconnectPolicy,
+    credentials: Option<Credentials>,
+    keepalive: Option<Keepalive>,
+    recorder: Option<Recorder>,
+) -> Result<PortAPI, ClientError> {
+    let parsed = parse_uri(uri)?;
+
+    let (api, mut conn) = PortAPI::new(keepalive, recorder);
+    let auth_required = api.auth_required.clone();
 
     tauri::async_runtime::spawn(async move {
-        let subscribers: SubscriberMap = RwLock::new(HashMap::new());
-        let next_id = AtomicUsize::new(0);
+        let mut attempt: u32 = 0;
+        let mut reconnect = false;
+
+        loop {
+            if attempt > 0 {
+                conn.set_state(ConnectionState::Reconnecting { attempt });
+            }
+
+            let builder = client_builder(parsed.clone(), credentials.as_ref());
+
+            let res = conn
+                .backlog_until(tokio::time::timeout(
+                    policy.connect_timeout,
+                    builder.connect(),
+                ))
+                .await;
+
+            // whether an established connection has been lost in this iteration.
+            let mut lost = false;
+
+            let res = res
+                .map_err(|_| ClientError::Timeout)
+                .and_then(|res| res.map_err(ClientError::from));
+
+            match res {
+                Ok((mut client, _)) => {
+                    attempt = 0;
+                    auth_required.store(false, Ordering::Relaxed);
+
+                    if reconnect {
+                        info!("re-established connection to portmaster");
+                    }
+
+                    let reason = match conn.resubscribe(&mut client).await {
+                        Ok(_) => {
+                            conn.set_state(ConnectionState::Connected);
+
+                            conn.run(&mut client).await
+                        }
+                        Err(err) => {
+                            error!("failed to replay subscriptions: {}", err);
+
+                            err.to_string()
+                        }
+                    };
+
+                    conn.set_state(ConnectionState::Disconnected { reason });
+
+                    reconnect = true;
+                    lost = true;
+
+                    conn.connection_lost(true).await;
+                }
+                Err(err) => {
+                    match err {
+                        ClientError::AuthRequired => {
+                            if !auth_required.swap(true, Ordering::Relaxed) {
+                                error!("{} rejected our credentials", parsed);
+                            }
+                        }
+                        _ => {
+                            debug!("failed to connect to {}: {}", parsed, err);
+                        }
+                    }
+
+                    conn.set_state(ConnectionState::Disconnected {
+                        reason: err.to_string(),
+                    });
+                }
+            }
+
+            if conn.is_abandoned().await {
+                debug!("all clients and subscriptions have been dropped, stop reconnecting");
+
+                conn.dispatch.close();
+                return;
+            }
+
+            // the first attempt after losing the connection is 1.
+            let delay = policy.delay(attempt);
+
+            // only the first failure is worth a warning, Portmaster might be down
+            // for a while.
+            match (attempt, lost) {
+                (0, true) => warn!("connection to portmaster lost, reconnecting in {:?}", delay),
+                (0, false) => warn!("failed to connect to portmaster, retrying in {:?}", delay),
+                _ => debug!(
+                    "reconnecting to portmaster in {:?} (attempt {})",
+                    delay,
+                    attempt + 1
+                ),
+            }
+
+            attempt = attempt.saturating_add(1);
+
+            conn.backlog_until(sleep(delay)).await;
+        }
+    });
+
+    Ok(api)
+}
+
+/// Returns a websocket client builder for uri that authenticates using credentials.
+fn client_builder(uri: Uri, credentials: Option<&Credentials>) -> ClientBuilder<'static> {
+    let builder = ClientBuilder::from_uri(uri);
+
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => return builder,
+    };
+
+    match (
+        HeaderName::from_bytes(credentials.header_name().as_bytes()),
+        HeaderValue::from_str(&credentials.header_value()),
+    ) {
+        (Ok(name), Ok(value)) => builder.add_header(name, value),
+        _ => {
+            error!("invalid credentials, connecting without authentication");
+
+            builder
+        }
+    }
+}
+
+fn parse_uri(uri: &str) -> Result<Uri, Error> {
+    match uri.parse::<Uri>() {
+        Ok(u) => Ok(u),
+        Err(_e) => {
+            Err(Error::NoUriConfigured) // TODO(ppacher): fix the return error type.
+        }
+    }
+}
+
+/// Connection holds the receiving side of a `PortAPI` client and multiplexes server
+/// responses to the subscribers of each command.
+struct Connection {
+    dispatch: Receiver<Command>,
+    cancel: UnboundedReceiver<usize>,
+    subscribers: SubscriberMap,
+
+    // commands that have been received while the client was disconnected.
+    backlog: VecDeque<Command>,
+
+    keepalive: Option<Keepalive>,
+    state: watch::Sender<ConnectionState>,
+    recorder: SharedRecorder,
+}
+
+impl Connection {
+    /// Publishes the connection state and records connection changes.
+    fn set_state(&self, state: ConnectionState) {
+        match &state {
+            ConnectionState::Connected => record(&self.recorder, || Event::Connected),
+            ConnectionState::Disconnected { reason } => record(&self.recorder, || {
+                Event::Disconnected {
+                    reason: reason.clone(),
+                }
+            }),
+            _ => {}
+        }
+
+        self.state.send_replace(state);
+    }
+
+    /// Handles commands and server responses until the websocket connection is lost
+    /// and returns the reason.
+    async fn run<S>(&mut self, client: &mut WebSocketStream<S>) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        while let Some(cmd) = self.backlog.pop_front() {
+            if let Err(err) = self.send_command(client, cmd).await {
+                error!("failed to dispatch command: {}", err);
+
+                return err.to_string();
+            }
+        }
+
+        // the time at which we send the next ping or, if we're already waiting for
+        // the server to answer, consider the connection dead.
+        let keepalive = self.keepalive;
+        let mut ping_at = keepalive.map(|k| Instant::now() + k.interval);
+        let mut awaiting_pong = false;
 
         loop {
             tokio::select! {
+                // prefer dispatching commands over cancellations so we don't miss
+                // to cancel a command that's still waiting in the dispatch queue.
+                biased;
+
                 msg = client.next() => {
                     let msg = match msg {
                         Some(msg) => msg,
                         None => {
                             warn!("websocket connection lost");
 
-                            dispatch.close();
-                            return;
+                            return "websocket connection lost".to_string();
                         }
                     };
 
@@ -69,47 +594,25 @@ pub async fn connect(uri: &str) -> Result<PortAPI, Error> {
                         Err(err) => {
                             error!("failed to receive frame from websocket: {}", err);
 
-                            dispatch.close();
-                            return;
+                            return err.to_string();
                         },
                         Ok(msg) => {
-                            let text = unsafe {
-                                std::str::from_utf8_unchecked(msg.as_payload())
-                            };
-
-                            match text.parse::<Message>() {
-                                Ok(msg) => {
-                                    let id = msg.id;
-                                    let map = subscribers
-                                        .read()
-                                        .await;
-
-                                    if let Some(sub) = map.get(&id) {
-                                        let res: Result<Response, MessageError> = msg.try_into();
-                                        match res {
-                                            Ok(response) => {
-                                                if let Err(err) = sub.send(response).await {
-                                                    // The receiver side has been closed already,
-                                                    // drop the read lock and remove the subscriber
-                                                    // from our hashmap
-                                                    drop(map);
-
-                                                    subscribers
-                                                        .write()
-                                                        .await
-                                                        .remove(&id);
-
-                                                    debug!("subscriber for command {} closed read side: {}", id, err);
-                                                }
-                                            },
-                                            Err(err) => {
-                                                error!("invalid command: {}", err);
-                                            }
-                                        }
-                                    }
-                                },
-                                Err(err) => {
-                                    error!("failed to deserialize message: {}", err)
+                            // any frame proves that the connection is still alive.
+                            if let Some(keepalive) = keepalive {
+                                ping_at = Some(Instant::now() + keepalive.interval);
+                                awaiting_pong = false;
+                            }
+
+                            // Ping, pong and close frames are already handled by
+                            // the websocket stream itself.
+                            if msg.is_text() || msg.is_binary() {
+                                record(&self.recorder, || Event::Received {
+                                    id: MessageRef::peek_id(msg.as_payload()),
+                                    frame: FrameData::from(&msg),
+                                });
+
+                                if let Some(id) = dispatch_frame(&self.subscribers, msg.as_payload()).await {
+                                    send_cancel(client, &self.recorder, id).await;
                                 }
                             }
                         }
@@ -117,75 +620,1232 @@ pub async fn connect(uri: &str) -> Result<PortAPI, Error> {
 
                 },
 
-                Some(mut cmd) = dispatch.recv() => {
-                    let id = next_id.fetch_add(1, Ordering::Relaxed);
-                    cmd.msg.id = id;
-                    let blob: String = cmd.msg.into();
+                Some(cmd) = self.dispatch.recv() => {
+                    if let Err(err) = self.send_command(client, cmd).await {
+                        error!("failed to dispatch command: {}", err);
 
-                    debug!("Sending websocket frame: {}", blob);
+                        return err.to_string();
+                    }
+                }
 
-                    match client.send(tokio_websockets::Message::text(blob)).await {
-                        Ok(_) => {
-                            subscribers
-                                .write()
-                                .await
-                                .insert(id, cmd.response);
-                        },
-                        Err(err) => {
-                            error!("failed to dispatch command: {}", err);
+                Some(id) = self.cancel.recv() => {
+                    // only cancel commands that are still active.
+                    if self.subscribers.write().await.remove(&id).is_some() {
+                        send_cancel(client, &self.recorder, id).await;
+                    }
+                }
 
-                            // TODO(ppacher): we should send some error to cmd.response here.
-                            // Otherwise, the sender of cmd might get stuck waiting for responses
-                            // if they don't check for PortAPI.is_closed().
+                _ = sleep_until(ping_at.unwrap_or_else(Instant::now)), if ping_at.is_some() => {
+                    let keepalive = keepalive.unwrap();
 
-                            return
-                        }
+                    if awaiting_pong {
+                        warn!("server did not answer ping within {:?}", keepalive.timeout);
+
+                        return "keepalive timeout".to_string();
+                    }
+
+                    if let Err(err) = client.send(tokio_websockets::Message::ping(Bytes::new())).await {
+                        error!("failed to send ping: {}", err);
+
+                        return err.to_string();
                     }
+
+                    ping_at = Some(Instant::now() + keepalive.timeout);
+                    awaiting_pong = true;
                 }
             }
         }
-    });
-
-    Ok(PortAPI { dispatch: tx })
-}
+    }
 
-impl PortAPI {
-    /// `request` sends a PortAPI `portapi::types::Request` to the server and returns a mpsc receiver channel
-    /// where all server responses are forwarded.
+    /// Sends cmd to the server and registers a subscriber for it's responses.
     ///
-    /// If the caller does not intend to read any responses the returned receiver may be closed or
-    /// dropped. As soon as the async-thread launched in `connect` detects a closed receiver it is remove
-    /// from the subscription map.
-    ///
-    /// The default buffer size for the channel is 64. Use `request_with_buffer_size` to specify a dedicated buffer size.
-    pub async fn request(
-        &self,
-        r: Request,
-    ) -> std::result::Result<Receiver<Response>, MessageError> {
-        self.request_with_buffer_size(r, 64).await
+    /// The subscriber is registered even if sending fails so it is notified (or
+    /// replayed) by `connection_lost`.
+    async fn send_command<S>(&self, client: &mut WebSocketStream<S>, cmd: Command) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let id = cmd.msg.id;
+
+        // the request has already been dropped.
+        if cmd.cancelable && cmd.response.is_closed() {
+            debug!("skipping command {} as it has been canceled", id);
+            return Ok(());
+        }
+
+        let subscriber = Subscriber {
+            response: cmd.response,
+            subscription: cmd.subscription,
+            cancelable: cmd.cancelable,
+            request: cmd.subscription.then(|| cmd.msg.clone()),
+        };
+
+        let res = client.send(encode_frame(&self.recorder, cmd.msg)).await;
+
+        self.subscribers.write().await.insert(id, subscriber);
+
+        res
     }
 
-    // Like `request` but supports explicitly specifying a channel buffer size.
-    pub async fn request_with_buffer_size(
-        &self,
-        r: Request,
-        buffer: usize,
-    ) -> std::result::Result<Receiver<Response>, MessageError> {
-        let (tx, rx) = channel(buffer);
+    /// Sends `Response::ConnectionLost` to all pending requests and removes their subscribers.
+    /// If keep_subscriptions is set, `sub` and `qsub` requests are kept so they can be
+    /// replayed once the connection has been re-established.
+    async fn connection_lost(&mut self, keep_subscriptions: bool) {
+        self.subscribers.write().await.retain(|_, sub| {
+            if keep_subscriptions && sub.request.is_some() {
+                return true;
+            }
+
+            // never wait for a subscriber while holding the lock. If its buffer is
+            // full, dropping the sender still ends the response stream.
+            let _ = sub.response.try_send(Response::ConnectionLost);
+
+            false
+        });
+    }
+
+    /// Sends all active subscriptions to the server after the connection has been
+    /// re-established. Each subscriber receives a `Response::Reconnected` before any
+    /// new records are forwarded.
+    async fn resubscribe<S>(&mut self, client: &mut WebSocketStream<S>) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // there's no need to replay subscriptions that have been canceled while we
+        // were disconnected.
+        while let Ok(id) = self.cancel.try_recv() {
+            self.subscribers.write().await.remove(&id);
+        }
+
+        let mut requests = Vec::new();
+
+        // never wait for subscribers while holding the lock. Subscribers that don't
+        // keep up are closed instead of blocking the connection.
+        self.subscribers.write().await.retain(|id, sub| {
+            let request = match &sub.request {
+                Some(request) => request,
+                None => return true,
+            };
+
+            match sub.response.try_send(Response::Reconnected) {
+                Ok(_) => {
+                    requests.push(request.clone());
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    warn!("subscriber for command {} is not reading, closing it", id);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
 
-        let msg: Message = r.try_into()?;
+        requests.sort_by_key(|request| request.id);
 
-        let _ = self.dispatch.send(Command { response: tx, msg }).await;
+        for request in requests {
+            debug!("replaying subscription {}", request.id);
+
+            client.send(encode_frame(&self.recorder, request)).await?;
+        }
 
-        Ok(rx)
+        Ok(())
     }
 
-    /// Reports whether or not the websocket connection to the Portmaster Database API has been closed
-    /// due to errors.
+    /// Waits for fut and moves all commands that are dispatched in the meantime to
+    /// the backlog. This keeps `PortAPI::request` from blocking while the client is
+    /// not connected.
+    async fn backlog_until<F: std::future::Future>(&mut self, fut: F) -> F::Output {
+        tokio::pin!(fut);
+
+        loop {
+            tokio::select! {
+                output = &mut fut => return output,
+
+                Some(cmd) = self.dispatch.recv() => {
+                    // drop commands whose caller already gave up.
+                    self.backlog.retain(|cmd| !(cmd.cancelable && cmd.response.is_closed()));
+                    self.backlog.push_back(cmd);
+                }
+            }
+        }
+    }
+
+    /// Reports whether all `PortAPI` handles and all subscriptions have been dropped
+    /// so there's no need to reconnect anymore.
     ///
-    /// Users are expected to check this field on a regular interval to detect any issues and perform
-    /// a clean re-connect by calling `connect` again.
-    pub fn is_closed(&self) -> bool {
-        self.dispatch.is_closed()
+    /// Commands that are already queued are moved to the backlog and are sent once the
+    /// client reconnected.
+    async fn is_abandoned(&mut self) -> bool {
+        loop {
+            match self.dispatch.try_recv() {
+                Ok(cmd) => self.backlog.push_back(cmd),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        while let Ok(id) = self.cancel.try_recv() {
+            self.subscribers.write().await.remove(&id);
+        }
+
+        self.backlog.is_empty()
+            && self
+                .subscribers
+                .read()
+                .await
+                .values()
+                .all(|sub| sub.response.is_closed())
+    }
+}
+
+/// Decodes the record metadata and the value of a record payload.
+fn decode_record<T: DeserializeOwned>(
+    key: String,
+    payload: Payload,
+) -> std::result::Result<(Record, T), ClientError> {
+    let record = Record::from_payload(key.parse()?, &payload);
+
+    match payload.parse::<T>() {
+        Ok(value) => Ok((record, value)),
+        Err(err) => Err(ClientError::Parse(key, err)),
+    }
+}
+
+/// Encodes msg as a websocket frame. Binary frames are only used if the payload
+/// cannot be represented as text.
+fn encode_frame(recorder: &SharedRecorder, msg: Message) -> tokio_websockets::Message {
+    let id = msg.id;
+    let is_binary = msg.payload.as_ref().is_some_and(|p| !p.is_text());
+
+    let frame = if is_binary {
+        let blob: Vec<u8> = msg.into();
+
+        debug!("Sending binary websocket frame for command {} ({} bytes)", id, blob.len());
+
+        tokio_websockets::Message::binary(Bytes::from(blob))
+    } else {
+        let blob: String = msg.into();
+
+        debug!("Sending websocket frame: {}", blob);
+
+        tokio_websockets::Message::text(blob)
+    };
+
+    record(recorder, || Event::Sent {
+        id: Some(id),
+        frame: FrameData::from(&frame),
+    });
+
+    frame
+}
+
+/// Records the event returned by f if a recorder is attached.
+fn record(recorder: &SharedRecorder, f: impl FnOnce() -> Event) {
+    if let Ok(recorder) = recorder.lock() {
+        if let Some(recorder) = &*recorder {
+            recorder.record(f());
+        }
+    }
+}
+
+/// Sends a `cancel` message for the command id to the server.
+async fn send_cancel<S>(client: &mut WebSocketStream<S>, recorder: &SharedRecorder, id: usize)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let msg = Message {
+        id,
+        cmd: "cancel".to_string(),
+        key: None,
+        payload: None,
+    };
+
+    if let Err(err) = client.send(encode_frame(recorder, msg)).await {
+        error!("failed to cancel command {}: {}", id, err);
+    }
+}
+
+/// Decodes a text or binary websocket frame and forwards the response to the subscriber
+/// of the command id.
+///
+/// Frames that cannot be decoded are forwarded as `Response::Malformed` if the command
+/// id is still readable, otherwise they are logged and dropped.
+///
+/// Subscribers are removed once they received the last response for their command.
+/// If the subscriber has already been dropped, the command id is returned so the
+/// caller can cancel the command.
+async fn dispatch_frame(subscribers: &SubscriberMap, frame: &[u8]) -> Option<usize> {
+    let id = match MessageRef::peek_id(frame) {
+        Some(id) => id,
+        None => {
+            error!("failed to deserialize message: {}", MessageError::InvalidID);
+            return None;
+        }
+    };
+
+    let map = subscribers.read().await;
+
+    let sub = map.get(&id)?;
+
+    let response = match Message::try_from(frame).and_then(Response::try_from) {
+        Ok(response) => response,
+        Err(err) => {
+            error!("invalid frame for command {}: {}", id, err);
+
+            Response::Malformed(err)
+        }
+    };
+
+    let terminal = response.is_terminal(sub.subscription);
+    let cancelable = sub.cancelable;
+
+    if let Err(err) = sub.response.send(response).await {
+        // The receiver side has been closed already,
+        // drop the read lock and remove the subscriber
+        // from our hashmap
+        drop(map);
+
+        subscribers.write().await.remove(&id);
+
+        debug!("subscriber for command {} closed read side: {}", id, err);
+
+        return (cancelable && !terminal).then_some(id);
+    }
+
+    if terminal {
+        drop(map);
+
+        subscribers.write().await.remove(&id);
+    }
+
+    None
+}
+
+impl PortAPI {
+    /// Creates a new client and the `Connection` that receives it's commands.
+    fn new(keepalive: Option<Keepalive>, recorder: Option<Recorder>) -> (PortAPI, Connection) {
+        let (tx, dispatch) = channel::<Command>(64);
+        let (cancel_tx, cancel) = unbounded_channel::<usize>();
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting);
+        let recorder = Arc::new(std::sync::Mutex::new(recorder));
+
+        let api = PortAPI {
+            dispatch: tx,
+            cancel: cancel_tx,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            state,
+            #[cfg(test)]
+            recorder: recorder.clone(),
+            auth_required: Arc::new(AtomicBool::new(false)),
+        };
+
+        let conn = Connection {
+            dispatch,
+            cancel,
+            subscribers: RwLock::new(HashMap::new()),
+            backlog: VecDeque::new(),
+            keepalive,
+            state: state_tx,
+            recorder,
+        };
+
+        (api, conn)
+    }
+
+    /// `request` sends a PortAPI `portapi::types::Request` to the server and returns a `ResponseReceiver`
+    /// where all server responses are forwarded.
+    ///
+    /// If the caller does not intend to read any responses the returned receiver may be dropped. For
+    /// streaming requests (`query`, `sub` and `qsub`) this cancels the request on the server.
+    ///
+    /// Returns `ClientError::Closed` if the client has been closed. Requests that are pending when
+    /// the connection is lost receive a final `Response::ConnectionLost`.
+    ///
+    /// The default buffer size for the channel is 64. Use `request_with_buffer_size` to specify a dedicated buffer size.
+    pub async fn request(
+        &self,
+        r: Request,
+    ) -> std::result::Result<ResponseReceiver, ClientError> {
+        self.request_with_buffer_size(r, 64).await
+    }
+
+    // Like `request` but supports explicitly specifying a channel buffer size.
+    pub async fn request_with_buffer_size(
+        &self,
+        r: Request,
+        buffer: usize,
+    ) -> std::result::Result<ResponseReceiver, ClientError> {
+        if self.is_auth_required() {
+            return Err(ClientError::AuthRequired);
+        }
+
+        let (tx, rx) = channel(buffer);
+
+        let subscription = r.is_subscription();
+        let cancelable = r.is_cancelable();
+
+        let mut msg: Message = r.try_into()?;
+        msg.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let id = msg.id;
+
+        self.dispatch
+            .send(Command {
+                response: tx,
+                msg,
+                subscription,
+                cancelable,
+            })
+            .await
+            .map_err(|_| ClientError::Closed)?;
+
+        Ok(ResponseReceiver {
+            id,
+            rx,
+            cancel: cancelable.then(|| self.cancel.clone()),
+            subscription,
+        })
+    }
+
+    /// `subscribe` creates a query subscription (`qsub`) and returns a `Subscription` stream
+    /// that decodes all records into T.
+    ///
+    /// The stream first yields all records that currently match query as `RecordEvent::Initial`
+    /// followed by `RecordEvent::SnapshotDone`. Afterwards, any changes to matching records are
+    /// streamed as they happen.
+    pub async fn subscribe<T: DeserializeOwned>(
+        &self,
+        query: Query,
+    ) -> std::result::Result<Subscription<T>, ClientError> {
+        let rx = self.request(Request::QuerySubscribe(query)).await?;
+
+        Ok(rx.into())
+    }
+
+    /// `get` fetches the record stored at key and decodes it into T.
+    ///
+    /// Returns `ClientError::NotFound` if the record does not exist and `ClientError::Timeout`
+    /// if the server did not answer within timeout.
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        key: &DbKey,
+        timeout: Duration,
+    ) -> std::result::Result<(Record, T), ClientError> {
+        let mut rx = self.request(Request::Get(key.clone())).await?;
+
+        let res = tokio::time::timeout(timeout, async move {
+            loop {
+                match rx.recv().await {
+                    Some(Response::Ok(key, payload)) => return decode_record(key, payload),
+                    Some(Response::Warning(msg)) => warn!("get {}: {}", key, msg),
+                    Some(other) => return Err(ClientError::from_response(other)),
+                    None => return Err(ClientError::ConnectionLost),
+                }
+            }
+        })
+        .await;
+
+        res.map_err(|_| ClientError::Timeout)?
+    }
+
+    /// `query_all` executes query and collects all matching records, decoded into T, until
+    /// the server reports `done`.
+    ///
+    /// Returns `ClientError::Timeout` if the query did not finish within timeout. In this case,
+    /// the query is canceled on the server.
+    #[cfg(test)]
+    pub async fn query_all<T: DeserializeOwned>(
+        &self,
+        query: Query,
+        timeout: Duration,
+    ) -> std::result::Result<Vec<(Record, T)>, ClientError> {
+        let mut rx = self.request(Request::Query(query.clone())).await?;
+
+        let res = tokio::time::timeout(timeout, async move {
+            let mut records = Vec::new();
+
+            loop {
+                match rx.recv().await {
+                    Some(Response::Ok(key, payload)) => records.push(decode_record(key, payload)?),
+                    Some(Response::Done) => return Ok(records),
+                    Some(Response::Warning(msg)) => warn!("{}: {}", query, msg),
+                    Some(other) => return Err(ClientError::from_response(other)),
+                    None => return Err(ClientError::ConnectionLost),
+                }
+            }
+        })
+        .await;
+
+        res.map_err(|_| ClientError::Timeout)?
+    }
+
+    /// `expect_success` sends r and waits for the server to report success.
+    ///
+    /// Returns `ClientError::Timeout` if the server did not answer within timeout.
+    pub async fn expect_success(
+        &self,
+        r: Request,
+        timeout: Duration,
+    ) -> std::result::Result<(), ClientError> {
+        let mut rx = self.request(r).await?;
+
+        let res = tokio::time::timeout(timeout, async move {
+            loop {
+                match rx.recv().await {
+                    Some(Response::Success) => return Ok(()),
+                    Some(Response::Warning(msg)) => warn!("{}", msg),
+                    Some(other) => return Err(ClientError::from_response(other)),
+                    None => return Err(ClientError::ConnectionLost),
+                }
+            }
+        })
+        .await;
+
+        res.map_err(|_| ClientError::Timeout)?
+    }
+
+    /// Reports whether or not the websocket connection to the Portmaster Database API has been closed
+    /// due to errors.
+    ///
+    /// Users are expected to check this field on a regular interval to detect any issues and perform
+    /// a clean re-connect by calling `connect` again. Clients created using `connect_resilient` are
+    /// never closed.
+    #[cfg(test)]
+    pub fn is_closed(&self) -> bool {
+        self.dispatch.is_closed()
+    }
+
+    /// Reports whether or not the client is currently connected to the Portmaster Database API.
+    pub fn is_connected(&self) -> bool {
+        *self.state.borrow() == ConnectionState::Connected
+    }
+
+    /// Attaches a recorder that records all frames sent and received by the client
+    /// from now on, as well as connection changes. Pass None to stop recording, this
+    /// waits until the previous recorder has written all events.
+    ///
+    /// Recordings that should be replayed must start with a connection, pass the
+    /// recorder to `connect_resilient` instead.
+    #[cfg(test)]
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        if let Ok(mut current) = self.recorder.lock() {
+            *current = recorder;
+        }
+    }
+
+    /// Returns a receiver for the connection state of the client.
+    pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// Reports whether or not the server rejected the credentials of the last connection
+    /// attempt. Always false for clients created using `connect`.
+    pub fn is_auth_required(&self) -> bool {
+        !self.is_connected() && self.auth_required.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_websockets::{Message as Frame, ServerBuilder, WebSocketStream};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Test {
+        a: String,
+    }
+
+    /// Accepts a single websocket connection on a random local port and passes
+    /// it to handler. Returns the URI to connect to.
+    async fn serve<F, Fut>(handler: F) -> String
+    where
+        F: FnOnce(WebSocketStream<TcpStream>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws = ServerBuilder::new().accept(stream).await.unwrap();
+
+            handler(ws).await;
+        });
+
+        format!("ws://{}/api/database/v1", addr)
+    }
+
+    /// Returns the next text or binary frame sent by the client.
+    async fn next_frame(ws: &mut WebSocketStream<TcpStream>) -> Frame {
+        loop {
+            let frame = ws
+                .next()
+                .await
+                .expect("connection closed")
+                .expect("failed to read frame");
+
+            if frame.is_text() || frame.is_binary() {
+                return frame;
+            }
+        }
+    }
+
+    /// Returns the next message sent by the client or None if the connection
+    /// has been closed.
+    async fn next_message(ws: &mut WebSocketStream<TcpStream>) -> Option<Message> {
+        loop {
+            let frame = ws.next().await?.ok()?;
+
+            if frame.is_text() || frame.is_binary() {
+                return Message::try_from(&frame.as_payload()[..]).ok();
+            }
+        }
+    }
+
+    /// Answers the first request with a single record, optionally followed by `done`, and
+    /// forwards all further messages sent by the client to tx.
+    fn answer_and_forward(
+        tx: Sender<Message>,
+        done: bool,
+    ) -> impl FnOnce(WebSocketStream<TcpStream>) -> futures_util::future::BoxFuture<'static, ()> {
+        move |mut ws| Box::pin(async move {
+            let msg = next_message(&mut ws).await.unwrap();
+
+            ws.send(Frame::text(format!("{}|ok|test:a|J{{\"a\": \"b\"}}", msg.id)))
+                .await
+                .unwrap();
+
+            if done {
+                ws.send(Frame::text(format!("{}|done", msg.id))).await.unwrap();
+            }
+
+            while let Some(msg) = next_message(&mut ws).await {
+                if tx.send(msg).await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    fn key(key: &str) -> DbKey {
+        key.parse().unwrap()
+    }
+
+    fn cancel_message(id: usize) -> Message {
+        Message {
+            id,
+            cmd: "cancel".to_string(),
+            key: None,
+            payload: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_a_subscription_cancels_it() {
+        let (tx, mut rx) = channel(8);
+        let uri = serve(answer_and_forward(tx, true)).await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+        let mut sub = api.subscribe::<Test>(Query::new("test:")).await.unwrap();
+
+        assert!(matches!(sub.next().await, Some(RecordEvent::Initial(_, _))));
+        assert!(matches!(sub.next().await, Some(RecordEvent::SnapshotDone)));
+
+        drop(sub);
+
+        assert_eq!(rx.recv().await, Some(cancel_message(0)));
+    }
+
+    #[tokio::test]
+    async fn explicit_cancel() {
+        let (tx, mut rx) = channel(8);
+        let uri = serve(answer_and_forward(tx, false)).await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+        let mut query = api
+            .request(Request::Query(Query::new("test:")))
+            .await
+            .unwrap();
+
+        assert!(matches!(query.recv().await, Some(Response::Ok(_, _))));
+
+        query.cancel();
+
+        assert_eq!(rx.recv().await, Some(cancel_message(query.id())));
+
+        // canceling twice or dropping afterwards must not send another cancel.
+        query.cancel();
+        drop(query);
+
+        let next = api
+            .request(Request::Get(key("test:b")))
+            .await
+            .unwrap();
+
+        assert_eq!(rx.recv().await.map(|m| m.id), Some(next.id()));
+    }
+
+    #[tokio::test]
+    async fn invalid_queries_are_not_sent() {
+        let (tx, _rx) = channel(8);
+        let uri = serve(answer_and_forward(tx, false)).await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+
+        for query in [
+            Query::new("test"),
+            Query::new("test:").filter(Condition::Exists("a|b".to_string())),
+        ] {
+            assert!(matches!(
+                api.request(Request::Query(query.clone())).await,
+                Err(ClientError::Message(MessageError::InvalidQuery(_)))
+            ));
+            assert!(matches!(
+                api.subscribe::<Test>(query).await,
+                Err(ClientError::Message(MessageError::InvalidQuery(_)))
+            ));
+        }
+
+        // nothing has been sent so the server answers the next request.
+        let mut next = api.request(Request::Get(key("test:a"))).await.unwrap();
+
+        assert!(matches!(next.recv().await, Some(Response::Ok(_, _))));
+    }
+
+    #[tokio::test]
+    async fn finished_requests_are_not_canceled() {
+        let (tx, mut rx) = channel(8);
+        let uri = serve(answer_and_forward(tx, true)).await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+        let mut query = api
+            .request(Request::Query(Query::new("test:")))
+            .await
+            .unwrap();
+
+        assert!(matches!(query.recv().await, Some(Response::Ok(_, _))));
+        assert_eq!(query.recv().await, Some(Response::Done));
+
+        drop(query);
+
+        let next = api
+            .request(Request::Get(key("test:b")))
+            .await
+            .unwrap();
+
+        assert_eq!(rx.recv().await.map(|m| m.id), Some(next.id()));
+    }
+
+    #[tokio::test]
+    async fn text_and_binary_frames() {
+        let uri = serve(|mut ws| async move {
+            let frame = next_frame(&mut ws).await;
+            let msg = Message::try_from(&frame.as_payload()[..]).unwrap();
+            assert_eq!(msg.cmd, "qsub");
+
+            let id = msg.id;
+            let frames = vec![
+                Frame::text(format!("{}|ok|test:a|J{{\"a\": \"x|y\"}}", id)),
+                Frame::binary(Bytes::from(format!("{}|upd|test:a|J{{\"a\": \"z\"}}", id))),
+                Frame::binary(Bytes::from(
+                    [format!("{}|upd|test:a|J", id).as_bytes(), b"{\"a\": \"\xff\"}"].concat(),
+                )),
+                Frame::text(format!("{}|bogus|test:a", id)),
+                Frame::text("not-an-id|ok|test:a|J{}".to_string()),
+                Frame::text(format!("{}|done", id)),
+            ];
+
+            for frame in frames {
+                ws.send(frame).await.unwrap();
+            }
+
+            while ws.next().await.is_some() {}
+        })
+        .await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+        let mut rx = api
+            .request(Request::QuerySubscribe(Query::new("test:")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            rx.recv().await,
+            Some(Response::Ok(
+                "test:a".to_string(),
+                Payload::JSON("{\"a\": \"x|y\"}".to_string())
+            ))
+        );
+        assert_eq!(
+            rx.recv().await,
+            Some(Response::Update(
+                "test:a".to_string(),
+                Payload::JSON("{\"a\": \"z\"}".to_string())
+            ))
+        );
+        assert!(matches!(
+            rx.recv().await,
+            Some(Response::Malformed(MessageError::InvalidUtf8(_)))
+        ));
+        assert_eq!(
+            rx.recv().await,
+            Some(Response::Malformed(MessageError::UnknownCommand(
+                "bogus".to_string()
+            )))
+        );
+        assert_eq!(rx.recv().await, Some(Response::Done));
+    }
+
+    #[tokio::test]
+    async fn binary_payloads_are_sent_as_binary_frames() {
+        let (tx, mut rx) = channel(1);
+
+        let uri = serve(|mut ws| async move {
+            let frame = next_frame(&mut ws).await;
+            let _ = tx.send(frame).await;
+
+            while ws.next().await.is_some() {}
+        })
+        .await;
+
+        let value = Test {
+            a: "b".to_string(),
+        };
+
+        let api = connect(&uri).await.expect("failed to connect");
+        let _ = api
+            .request(Request::Insert(
+                key("test:a"),
+                Payload::encode(&value, Format::CBOR).unwrap(),
+            ))
+            .await
+            .unwrap();
+
+        let frame = rx.recv().await.unwrap();
+        assert!(frame.is_binary());
+
+        let msg = Message::try_from(&frame.as_payload()[..]).unwrap();
+        assert_eq!(msg.cmd, "insert");
+        assert_eq!(msg.key.as_deref(), Some("test:a"));
+        assert_eq!(msg.payload.unwrap().parse::<Test>().unwrap(), value);
+    }
+
+    /// Serves a small fixed database for the one-shot helpers.
+    async fn serve_database() -> String {
+        serve(|mut ws| async move {
+            while let Some(msg) = next_message(&mut ws).await {
+                let id = msg.id;
+
+                let frames = match (msg.cmd.as_str(), msg.key.as_deref()) {
+                    ("get", Some("test:a")) => vec![format!(
+                        "{}|ok|test:a|J{{\"a\": \"b\", \"_meta\": {{\"Created\": 1, \"Modified\": 2, \"Key\": \"test:a\"}}}}",
+                        id
+                    )],
+                    ("get", Some("test:invalid")) => vec![format!("{}|ok|test:invalid|J{{\"a\": 1}}", id)],
+                    ("get", Some("test:broken")) => vec![format!("{}|error|disk on fire", id)],
+                    ("get", Some("test:slow")) => vec![],
+                    ("get", _) => vec![format!("{}|error|{}", id, NOT_FOUND)],
+                    ("query", Some("query test:")) => vec![
+                        format!("{}|ok|test:a|J{{\"a\": \"b\"}}", id),
+                        format!("{}|warning|slow query", id),
+                        format!("{}|ok|test:b|J{{\"a\": \"c\"}}", id),
+                        format!("{}|done", id),
+                    ],
+                    _ => vec![],
+                };
+
+                for frame in frames {
+                    ws.send(Frame::text(frame)).await.unwrap();
+                }
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn get() {
+        let api = connect(&serve_database().await).await.unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let (record, value) = api.get::<Test>(&key("test:a"), timeout).await.unwrap();
+        assert_eq!(value.a, "b");
+        assert_eq!(
+            record,
+            Record {
+                created: 1,
+                deleted: 0,
+                expires: 0,
+                modified: 2,
+                key: key("test:a"),
+            }
+        );
+
+        assert!(matches!(
+            api.get::<Test>(&key("test:missing"), timeout).await,
+            Err(ClientError::NotFound)
+        ));
+        assert!(matches!(
+            api.get::<Test>(&key("test:broken"), timeout).await,
+            Err(ClientError::Server(msg)) if msg == "disk on fire"
+        ));
+        assert!(matches!(
+            api.get::<Test>(&key("test:invalid"), timeout).await,
+            Err(ClientError::Parse(key, _)) if key == "test:invalid"
+        ));
+        assert!(matches!(
+            api.get::<Test>(&key("test:slow"), Duration::from_millis(50)).await,
+            Err(ClientError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_all() {
+        let api = connect(&serve_database().await).await.unwrap();
+
+        let records = api
+            .query_all::<Test>(Query::new("test:"), Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let values: Vec<(String, String)> = records
+            .into_iter()
+            .map(|(record, value)| (record.key.to_string(), value.a))
+            .collect();
+
+        assert_eq!(
+            values,
+            vec![
+                ("test:a".to_string(), "b".to_string()),
+                ("test:b".to_string(), "c".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_loss_fails_pending_requests() {
+        let uri = serve(|mut ws| async move {
+            next_message(&mut ws).await.unwrap();
+            next_message(&mut ws).await.unwrap();
+
+            let _ = ws.close().await;
+        })
+        .await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+
+        let mut query = api
+            .request(Request::Query(Query::new("test:")))
+            .await
+            .unwrap();
+        let mut sub = api.subscribe::<Test>(Query::new("test:")).await.unwrap();
+
+        assert_eq!(query.recv().await, Some(Response::ConnectionLost));
+        assert_eq!(query.recv().await, None);
+
+        assert!(matches!(
+            sub.next().await,
+            Some(RecordEvent::Error(SubscriptionError::ConnectionLost))
+        ));
+        assert!(sub.next().await.is_none());
+
+        assert!(api.is_closed());
+        assert!(!api.is_connected());
+        assert!(matches!(
+            api.request(Request::Get(key("test:a"))).await,
+            Err(ClientError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_loss_does_not_wait_for_full_subscribers() {
+        let uri = serve(|mut ws| async move {
+            let msg = next_message(&mut ws).await.unwrap();
+            next_message(&mut ws).await.unwrap();
+
+            // fill the buffer of the first request.
+            ws.send(Frame::text(format!("{}|ok|test:a|J{{\"a\": \"b\"}}", msg.id)))
+                .await
+                .unwrap();
+
+            let _ = ws.close().await;
+        })
+        .await;
+
+        let api = connect(&uri).await.expect("failed to connect");
+
+        let mut full = api
+            .request_with_buffer_size(Request::Query(Query::new("test:")), 1)
+            .await
+            .unwrap();
+        let mut query = api
+            .request(Request::Query(Query::new("test:")))
+            .await
+            .unwrap();
+
+        assert_eq!(query.recv().await, Some(Response::ConnectionLost));
+
+        assert!(matches!(full.recv().await, Some(Response::Ok(_, _))));
+        assert_eq!(full.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn resilient_client_replays_subscriptions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}/api/database/v1", listener.local_addr().unwrap());
+        let (tx, mut rx) = channel(8);
+
+        tokio::spawn(async move {
+            for value in ["b", "c"] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = ServerBuilder::new().accept(stream).await.unwrap();
+
+                let msg = next_message(&mut ws).await.unwrap();
+
+                ws.send(Frame::text(format!("{}|ok|test:a|J{{\"a\": \"{}\"}}", msg.id, value)))
+                    .await
+                    .unwrap();
+                ws.send(Frame::text(format!("{}|done", msg.id))).await.unwrap();
+
+                tx.send(msg).await.unwrap();
+
+                // simulate a restart of the core.
+                let _ = ws.close().await;
+            }
+        });
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        let api = connect_resilient(&uri, policy, None, None, None).await.unwrap();
+        let mut sub = api.subscribe::<Test>(Query::new("test:")).await.unwrap();
+
+        let first = rx.recv().await.unwrap();
+
+        assert!(matches!(sub.next().await, Some(RecordEvent::Initial(_, Test { a })) if a == "b"));
+        assert!(matches!(sub.next().await, Some(RecordEvent::SnapshotDone)));
+        assert!(matches!(sub.next().await, Some(RecordEvent::Reconnected)));
+        assert!(matches!(sub.next().await, Some(RecordEvent::Initial(_, Test { a })) if a == "c"));
+        assert!(matches!(sub.next().await, Some(RecordEvent::SnapshotDone)));
+
+        // the same request must have been sent again.
+        assert_eq!(rx.recv().await, Some(first));
+        assert!(!api.is_closed());
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}/api/database/v1", listener.local_addr().unwrap());
+
+        // reject all upgrade requests and forward the request headers.
+        let (tx, mut rx) = channel::<String>(16);
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let _ = stream
+                    .write_all(b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n")
+                    .await;
+
+                let _ = tx.send(String::from_utf8(request).unwrap().to_lowercase()).await;
+            }
+        });
+
+        let credentials: Credentials = "secret".parse().unwrap();
+        assert!(matches!(
+            connect_with_credentials(&uri, Some(&credentials)).await,
+            Err(ClientError::AuthRequired)
+        ));
+        assert!(rx.recv().await.unwrap().contains("authorization: bearer secret\r\n"));
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let credentials: Credentials = "Portmaster-API-Session=token".parse().unwrap();
+
+        let api = connect_resilient(&uri, policy, Some(credentials), None, None).await.unwrap();
+        assert!(rx.recv().await.unwrap().contains("cookie: portmaster-api-session=token\r\n"));
+
+        while !api.is_auth_required() {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            api.request(Request::Get(key("test:a"))).await,
+            Err(ClientError::AuthRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}/api/database/v1", listener.local_addr().unwrap());
+
+        let (answered_tx, answered) = tokio::sync::oneshot::channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = ServerBuilder::new().accept(stream).await.unwrap();
+
+            // answer a few pings, then stop reading from the connection like a
+            // half-open TCP connection would. Pongs are flushed once the next frame
+            // is read so all but the last ping have been answered.
+            let mut pings = 0;
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_ping() {
+                    pings += 1;
+                }
+
+                if pings == 4 {
+                    break;
+                }
+            }
+
+            answered_tx.send(()).unwrap();
+
+            // keep the connection open without reading from it.
+            std::future::pending::<()>().await;
+        });
+
+        let keepalive = Keepalive {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(500),
+        };
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        let api = connect_resilient(&uri, policy, None, Some(keepalive), None)
+            .await
+            .unwrap();
+        let mut state = api.watch_state();
+
+        assert_eq!(*state.borrow(), ConnectionState::Connecting);
+        state.wait_for(|s| *s == ConnectionState::Connected).await.unwrap();
+
+        // the answered pings kept the connection alive.
+        answered.await.unwrap();
+        assert!(api.is_connected());
+
+        let disconnected = state
+            .wait_for(|s| *s != ConnectionState::Connected)
+            .await
+            .unwrap()
+            .clone();
+
+        assert_eq!(
+            disconnected,
+            ConnectionState::Disconnected {
+                reason: "keepalive timeout".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}/api/database/v1", listener.local_addr().unwrap());
+
+        // accept the TCP connection but never answer the websocket upgrade.
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+
+            std::future::pending::<()>().await;
+        });
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(60),
+            connect_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let api = connect_resilient(&uri, policy, None, None, None).await.unwrap();
+        let mut state = api.watch_state();
+
+        let disconnected = state
+            .wait_for(|s| *s != ConnectionState::Connecting)
+            .await
+            .unwrap()
+            .clone();
+
+        assert_eq!(
+            disconnected,
+            ConnectionState::Disconnected {
+                reason: ClientError::Timeout.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn reconnect_delay() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            ..Default::default()
+        };
+
+        for attempt in 0..100 {
+            let base = 2f64.powi(attempt as i32).min(10.0);
+            let delay = policy.delay(attempt).as_secs_f64();
+
+            assert!(delay >= base * 0.5 && delay <= base * 1.5, "attempt {}: {}", attempt, delay);
+        }
+
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            ..policy
+        };
+
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(4));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn requests_are_queued_while_connecting() {
+        // the listener does not accept connections until all requests are queued.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}/api/database/v1", listener.local_addr().unwrap());
+
+        let api = connect_resilient(&uri, ReconnectPolicy::default(), None, None, None)
+            .await
+            .unwrap();
+
+        // more requests than the dispatch queue can hold.
+        let mut receivers = Vec::new();
+        for _ in 0..100 {
+            let rx = tokio::time::timeout(
+                Duration::from_secs(1),
+                api.request(Request::Get(key("test:a"))),
+            )
+            .await
+            .expect("request blocked while connecting")
+            .unwrap();
+
+            receivers.push(rx);
+        }
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = ServerBuilder::new().accept(stream).await.unwrap();
+
+        for id in 0..100 {
+            assert_eq!(next_message(&mut ws).await.unwrap().id, id);
+        }
     }
 }
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+use url::Url;
+
+/// The base URL of a default Portmaster installation.
+pub const DEFAULT_BASE_URL: &str = "http://localhost:817/";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum EndpointError {
+    #[error("invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("unsupported URL scheme {0:?}, expected http or ws")]
+    Scheme(String),
+
+    #[error("TLS is not supported, use http or ws instead of {0}")]
+    Tls(String),
+}
+
+/// Endpoint is the base URL of the Portmaster API from which the websocket and
+/// HTTP endpoints are derived.
+///
+/// The base URL may use `ws` as well, which is treated the same as `http`. A path is
+/// kept as a prefix of all derived URLs which allows to reach Portmaster behind a
+/// reverse proxy. `https` and `wss` are rejected since neither the websocket nor the
+/// HTTP client is built with TLS support.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    base: Url,
+}
+
+impl Endpoint {
+    /// Returns the base URL of the Portmaster API.
+    #[cfg(test)]
+    pub fn base(&self) -> &Url {
+        &self.base
+    }
+
+    /// Returns the URL of the websocket database API.
+    pub fn websocket_url(&self) -> Url {
+        let mut url = self.join("api/database/v1");
+
+        // switching between "special" schemes is always allowed.
+        let _ = url.set_scheme("ws");
+
+        url
+    }
+
+    /// Returns the URL of an HTTP API endpoint. path is relative to `/api/v1/`.
+    pub fn http_url(&self, path: &str) -> Url {
+        self.join(&format!("api/v1/{}", path.trim_start_matches('/')))
+    }
+
+    fn join(&self, path: &str) -> Url {
+        // path is always relative and the base URL always ends in a slash so
+        // joining cannot fail.
+        self.base.join(path).unwrap()
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        DEFAULT_BASE_URL.parse().unwrap()
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut base = Url::parse(s)?;
+
+        match base.scheme() {
+            "http" | "ws" => {}
+            "https" | "wss" => return Err(EndpointError::Tls(base.scheme().to_string())),
+            other => return Err(EndpointError::Scheme(other.to_string())),
+        };
+        let _ = base.set_scheme("http");
+
+        base.set_query(None);
+        base.set_fragment(None);
+
+        if !base.path().ends_with('/') {
+            let path = format!("{}/", base.path());
+            base.set_path(&path);
+        }
+
+        Ok(Endpoint { base })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.base.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_urls() {
+        let endpoint = Endpoint::default();
+        assert_eq!(endpoint.base().as_str(), "http://localhost:817/");
+        assert_eq!(
+            endpoint.websocket_url().as_str(),
+            "ws://localhost:817/api/database/v1"
+        );
+        assert_eq!(
+            endpoint.http_url("ping").as_str(),
+            "http://localhost:817/api/v1/ping"
+        );
+
+        let endpoint: Endpoint = "ws://10.0.0.1:8817/portmaster?foo=bar".parse().unwrap();
+        assert_eq!(endpoint.base().as_str(), "http://10.0.0.1:8817/portmaster/");
+        assert_eq!(
+            endpoint.websocket_url().as_str(),
+            "ws://10.0.0.1:8817/portmaster/api/database/v1"
+        );
+        assert_eq!(
+            endpoint.http_url("/debug/info").as_str(),
+            "http://10.0.0.1:8817/portmaster/api/v1/debug/info"
+        );
+
+        assert_eq!(
+            "https://10.0.0.1:8817/".parse::<Endpoint>(),
+            Err(EndpointError::Tls("https".to_string()))
+        );
+        assert_eq!(
+            "wss://10.0.0.1:8817/".parse::<Endpoint>(),
+            Err(EndpointError::Tls("wss".to_string()))
+        );
+
+        assert!("127.0.0.1:817".parse::<Endpoint>().is_err());
+        assert_eq!(
+            "ftp://127.0.0.1".parse::<Endpoint>(),
+            Err(EndpointError::Scheme("ftp".to_string()))
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use reqwest::{Method, StatusCode};
+use std::time::Duration;
+use thiserror::Error;
+
+use super::auth::Credentials;
+use super::endpoint::Endpoint;
+
+/// The default timeout for HTTP API requests.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("the Portmaster API requires authentication, please configure an API key")]
+    AuthRequired,
+
+    #[error("server failed with {status}: {message}")]
+    Server { status: StatusCode, message: String },
+}
+
+/// HttpClient calls endpoints of the Portmaster HTTP API at `/api/v1/`.
+///
+/// It uses the same `Endpoint` and `Credentials` as the websocket client. Cloning
+/// the client is cheap and shares the underlying connection pool.
+///
+/// Note that the client does not support TLS, `Endpoint` only accepts plain `http`
+/// base URLs.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    endpoint: Endpoint,
+    credentials: Option<Credentials>,
+    client: reqwest::Client,
+}
+
+impl HttpClient {
+    /// Creates a new HTTP API client for endpoint.
+    pub fn new(endpoint: Endpoint, credentials: Option<Credentials>) -> Result<Self, HttpError> {
+        let client = reqwest::Client::builder().timeout(DEFAULT_TIMEOUT).build()?;
+
+        Ok(HttpClient {
+            endpoint,
+            credentials,
+            client,
+        })
+    }
+
+    /// Checks whether the Portmaster API is reachable.
+    #[cfg(test)]
+    pub async fn ping(&self) -> Result<(), HttpError> {
+        self.call(Method::GET, "ping").await.map(|_| ())
+    }
+
+    /// Shuts down the Portmaster core service.
+    pub async fn shutdown(&self) -> Result<(), HttpError> {
+        self.call(Method::POST, "core/shutdown").await.map(|_| ())
+    }
+
+    /// Restarts the Portmaster core service.
+    pub async fn restart(&self) -> Result<(), HttpError> {
+        self.call(Method::POST, "core/restart").await.map(|_| ())
+    }
+
+    /// Returns the debug information of the core formatted as markdown, ready to be
+    /// pasted into a support request.
+    pub async fn debug_info(&self) -> Result<String, HttpError> {
+        self.call(Method::GET, "debug/info?style=github").await
+    }
+
+    /// Calls an endpoint and returns the response body as text.
+    pub async fn call(&self, method: Method, path: &str) -> Result<String, HttpError> {
+        self.call_with_body(method, path, None).await
+    }
+
+    /// Like `call` but sends body encoded as JSON.
+    pub async fn call_with_body(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<String, HttpError> {
+        let text = self.send(method, path, body).await?.text().await?;
+
+        Ok(text.trim().to_string())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<reqwest::Response, HttpError> {
+        let mut req = self.client.request(method, self.endpoint.http_url(path));
+
+        if let Some(body) = body {
+            req = req.json(body);
+        }
+
+        if let Some(credentials) = &self.credentials {
+            req = req.header(credentials.header_name(), credentials.header_value());
+        }
+
+        let res = req.send().await?;
+        let status = res.status();
+
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(HttpError::AuthRequired);
+        }
+
+        if !status.is_success() {
+            let message = res.text().await.unwrap_or_default().trim().to_string();
+
+            return Err(HttpError::Server { status, message });
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::channel;
+
+    /// Serves the given HTTP responses, one per connection, and forwards the
+    /// lower-cased requests.
+    async fn serve(responses: Vec<&'static str>) -> (Endpoint, tokio::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let (tx, rx) = channel(16);
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+
+                let header_end = loop {
+                    if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                };
+
+                // read the body as well, the client might not accept the response otherwise.
+                let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .and_then(|len| len.trim().parse().ok())
+                    .unwrap_or(0);
+
+                while request.len() < header_end + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+                let _ = stream.shutdown().await;
+
+                tx.send(String::from_utf8(request).unwrap().to_lowercase())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (endpoint, rx)
+    }
+
+    #[tokio::test]
+    async fn calls() {
+        let (endpoint, mut rx) = serve(vec![
+            "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 20\r\n\r\nshutdown initiated\r\n",
+            "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 7\r\n\r\n# Info\n",
+            "HTTP/1.1 401 Unauthorized\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 500 Internal Server Error\r\nconnection: close\r\ncontent-length: 7\r\n\r\nfailed\n",
+            "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 2\r\n\r\nok",
+        ])
+        .await;
+
+        let credentials = "secret".parse().ok();
+        let client = HttpClient::new(endpoint, credentials).unwrap();
+
+        client.shutdown().await.unwrap();
+
+        let request = rx.recv().await.unwrap();
+        assert!(request.starts_with("post /api/v1/core/shutdown http/1.1\r\n"));
+        assert!(request.contains("authorization: bearer secret\r\n"));
+
+        assert_eq!(client.debug_info().await.unwrap(), "# Info");
+        assert!(rx
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("get /api/v1/debug/info?style=github http/1.1\r\n"));
+
+        assert!(matches!(client.restart().await, Err(HttpError::AuthRequired)));
+
+        match client.ping().await {
+            Err(HttpError::Server { status, message }) => {
+                assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+                assert_eq!(message, "failed");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // skip the restart and ping requests.
+        rx.recv().await.unwrap();
+        rx.recv().await.unwrap();
+
+        let body = serde_json::json!({"a": 1});
+        assert_eq!(
+            client
+                .call_with_body(Method::PUT, "updates/apply", Some(&body))
+                .await
+                .unwrap(),
+            "ok"
+        );
+
+        let request = rx.recv().await.unwrap();
+        assert!(request.starts_with("put /api/v1/updates/apply http/1.1\r\n"));
+        assert!(request.contains("content-type: application/json\r\n"));
+    }
+}
@@ -0,0 +1,181 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use super::message::MessageError;
+
+/// DbKey is a validated portbase database key in the form of `<database>:<path>`.
+///
+/// The database name must only contain alphanumeric characters, `-` and `_` while
+/// the path may contain anything but control characters and the PortAPI separator `|`.
+/// The path may be empty to address all records of a database.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DbKey {
+    database: String,
+    path: String,
+}
+
+impl DbKey {
+    /// Creates a new key for path in database.
+    pub fn new(database: impl Into<String>, path: impl Into<String>) -> Result<Self, MessageError> {
+        let key = DbKey {
+            database: database.into(),
+            path: path.into(),
+        };
+
+        let valid_db = !key.database.is_empty()
+            && key
+                .database
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        let valid_path = !key.path.chars().any(|c| c == '|' || c.is_control());
+
+        if valid_db && valid_path {
+            Ok(key)
+        } else {
+            Err(MessageError::InvalidKey(key.to_string()))
+        }
+    }
+
+    /// Returns the name of the database.
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    /// Returns the path of the key within the database.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns a new key for name below this key, separated by `/`.
+    pub fn child(&self, name: &str) -> Result<Self, MessageError> {
+        let path = if self.path.is_empty() || self.path.ends_with('/') {
+            format!("{}{}", self.path, name)
+        } else {
+            format!("{}/{}", self.path, name)
+        };
+
+        DbKey::new(self.database.clone(), path)
+    }
+
+    /// Reports whether the key is in the same database and it's path starts with
+    /// the path of prefix.
+    pub fn starts_with(&self, prefix: &DbKey) -> bool {
+        self.database == prefix.database && self.path.starts_with(&prefix.path)
+    }
+}
+
+impl fmt::Display for DbKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.database, self.path)
+    }
+}
+
+impl FromStr for DbKey {
+    type Err = MessageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((database, path)) => DbKey::new(database, path),
+            None => Err(MessageError::InvalidKey(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<&str> for DbKey {
+    type Error = MessageError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl TryFrom<String> for DbKey {
+    type Error = MessageError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Serialize for DbKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DbKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keys() {
+        let key: DbKey = "runtime:subsystems/core".parse().unwrap();
+        assert_eq!(key.database(), "runtime");
+        assert_eq!(key.path(), "subsystems/core");
+        assert_eq!(key.to_string(), "runtime:subsystems/core");
+
+        // only the first colon separates the database name.
+        let key: DbKey = "cache:intel/ip:1.1.1.1".parse().unwrap();
+        assert_eq!(key.path(), "intel/ip:1.1.1.1");
+
+        assert_eq!("config:".parse::<DbKey>().unwrap().path(), "");
+
+        for invalid in [
+            "",
+            "config",
+            ":spn/enable",
+            "con fig:spn/enable",
+            "config:spn|enable",
+            "config:spn\nenable",
+        ] {
+            assert_eq!(
+                invalid.parse::<DbKey>(),
+                Err(MessageError::InvalidKey(invalid.to_string())),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn child_and_prefix() {
+        let root: DbKey = "runtime:".parse().unwrap();
+        let subsystems = root.child("subsystems").unwrap();
+        let core = subsystems.child("core").unwrap();
+
+        assert_eq!(subsystems.to_string(), "runtime:subsystems");
+        assert_eq!(core.to_string(), "runtime:subsystems/core");
+        assert_eq!(
+            "runtime:subsystems/".parse::<DbKey>().unwrap().child("core"),
+            Ok(core.clone())
+        );
+        assert!(subsystems.child("a|b").is_err());
+
+        assert!(core.starts_with(&root));
+        assert!(core.starts_with(&subsystems));
+        assert!(!subsystems.starts_with(&core));
+        assert!(!core.starts_with(&"config:subsystems".parse().unwrap()));
+    }
+
+    #[test]
+    fn serde() {
+        let key: DbKey = "notifications:all/foo".parse().unwrap();
+
+        assert_eq!(serde_json::to_string(&key).unwrap(), "\"notifications:all/foo\"");
+        assert_eq!(
+            serde_json::from_str::<DbKey>("\"notifications:all/foo\"").unwrap(),
+            key
+        );
+        assert!(serde_json::from_str::<DbKey>("\"invalid\"").is_err());
+    }
+}
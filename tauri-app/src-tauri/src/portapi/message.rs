@@ -1,5 +1,8 @@
+use serde::{de::DeserializeOwned, Serialize};
 use thiserror::Error;
 
+use super::query::QueryError;
+
 /// MessageError describes any error that is encountered when parsing
 /// PortAPI messages or when converting between the Request/Response types.
 #[derive(Debug, Error)]
@@ -22,48 +25,155 @@ pub enum MessageError {
     #[error("unknown or unsupported command: {0}")]
     UnknownCommand(String),
 
+    #[error("invalid UTF-8 in message: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+
+    #[error("invalid query: {0}")]
+    InvalidQuery(#[from] QueryError),
+
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+
     #[error(transparent)]
     InvalidPayload(#[from] serde_json::Error),
 }
 
+/// serde_json::Error does not implement PartialEq so errors are considered equal
+/// if they are of the same variant and render the same message.
+impl PartialEq for MessageError {
+    fn eq(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+            && self.to_string() == other.to_string()
+    }
+}
+
 
 /// Payload defines the payload type and content of a PortAPI message.
 /// 
-/// For the time being, only JSON payloads (indicated by a prefixed 'J' of the payload content)
-/// is directly supported in `Payload::parse()`.
-/// 
-/// For other payload types (like CBOR, BSON, ...) it's the user responsibility to figure out
-/// appropriate decoding from the `Payload::UNKNOWN` variant.
+/// The payload format is indicated by a single prefix character of the payload content
+/// as defined by portbase's DSD (dynamic structured data) package:
+///
+///  - 'J' for JSON
+///  - 'C' for CBOR
+///  - 'M' for MsgPack
+///  - 'B' for BSON
+///  - 'X' for raw bytes
+///
+/// JSON, CBOR, MsgPack and BSON payloads can be decoded using `Payload::parse()` and
+/// created using `Payload::encode()`. Raw bytes are passed through as-is.
+/// Any other format ends up in the `Payload::UNKNOWN` variant and it's the user
+/// responsibility to figure out appropriate decoding.
+///
+/// Note that only JSON payloads can be transmitted as websocket text frames. Use the
+/// `Vec<u8>` conversion of `Message` for any other format.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug, Clone)]
 pub enum Payload {
     JSON(String),
+    CBOR(Vec<u8>),
+    MSGPACK(Vec<u8>),
+    BSON(Vec<u8>),
+    BYTES(Vec<u8>),
     UNKNOWN(String),
 }
 
+/// Format defines the serialization formats supported by `Payload::encode()`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Format {
+    JSON,
+    CBOR,
+    MSGPACK,
+    BSON,
+}
+
 /// ParseError is returned from `Payload::parse()`.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Error)]
 pub enum ParseError {
     #[error(transparent)]
     JSON(#[from] serde_json::Error),
 
+    #[error(transparent)]
+    CBOR(#[from] ciborium::de::Error<std::io::Error>),
+
+    #[error(transparent)]
+    MSGPACK(#[from] rmp_serde::decode::Error),
+
+    #[error(transparent)]
+    BSON(#[from] bson::de::Error),
+
     #[error("unknown error while parsing")]
     UNKNOWN
 }
 
+/// EncodeError is returned from `Payload::encode()`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error(transparent)]
+    JSON(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    CBOR(#[from] ciborium::ser::Error<std::io::Error>),
+
+    #[error(transparent)]
+    MSGPACK(#[from] rmp_serde::encode::Error),
+
+    #[error(transparent)]
+    BSON(#[from] bson::ser::Error),
+}
 
 impl Payload {
     /// Parse the payload into T.
     /// 
-    /// Only JSON parsing is supported for now. See [Payload] for more information.
-    pub fn parse<'a, T>(self: &'a Self) -> std::result::Result<T, ParseError> 
+    /// Raw bytes and unknown formats cannot be parsed. See [Payload] for more information.
+    pub fn parse<T>(&self) -> std::result::Result<T, ParseError> 
     where
-        T: serde::de::Deserialize<'a> {
+        T: DeserializeOwned {
 
         match self {
             Payload::JSON(blob) => Ok(serde_json::from_str::<T>(blob.as_str())?),
-            Payload::UNKNOWN(_) => Err(ParseError::UNKNOWN),
+            Payload::CBOR(blob) => Ok(ciborium::from_reader(blob.as_slice())?),
+            Payload::MSGPACK(blob) => Ok(rmp_serde::from_slice(blob)?),
+            Payload::BSON(blob) => Ok(bson::from_slice(blob)?),
+            Payload::BYTES(_) | Payload::UNKNOWN(_) => Err(ParseError::UNKNOWN),
+        }
+    }
+
+    /// Serializes value into a new payload using the requested format.
+    pub fn encode<T>(value: &T, format: Format) -> std::result::Result<Payload, EncodeError>
+    where
+        T: Serialize {
+
+        match format {
+            Format::JSON => Ok(Payload::JSON(serde_json::to_string(value)?)),
+            Format::CBOR => {
+                let mut blob = Vec::new();
+                ciborium::into_writer(value, &mut blob)?;
+
+                Ok(Payload::CBOR(blob))
+            },
+            Format::MSGPACK => Ok(Payload::MSGPACK(rmp_serde::to_vec_named(value)?)),
+            Format::BSON => Ok(Payload::BSON(bson::to_vec(value)?)),
         }
     }
+
+    /// Returns the format of the payload, if it's one supported by `Payload::parse()`.
+    pub fn format(&self) -> Option<Format> {
+        match self {
+            Payload::JSON(_) => Some(Format::JSON),
+            Payload::CBOR(_) => Some(Format::CBOR),
+            Payload::MSGPACK(_) => Some(Format::MSGPACK),
+            Payload::BSON(_) => Some(Format::BSON),
+            Payload::BYTES(_) | Payload::UNKNOWN(_) => None,
+        }
+    }
+
+    /// Reports whether the payload can be transmitted without loss in a text frame.
+    pub fn is_text(&self) -> bool {
+        matches!(self, Payload::JSON(_) | Payload::UNKNOWN(_))
+    }
 }
 
 /// Supports creating a Payload instance from a String.
@@ -78,6 +188,10 @@ impl std::convert::From<String> for Payload {
         match first {
             Some(c) => match c {
                 'J' => Payload::JSON(rest),
+                'C' => Payload::CBOR(rest.into_bytes()),
+                'M' => Payload::MSGPACK(rest.into_bytes()),
+                'B' => Payload::BSON(rest.into_bytes()),
+                'X' => Payload::BYTES(rest.into_bytes()),
                 _ => Payload::UNKNOWN(value),
             },
             None => Payload::UNKNOWN("".to_string())
@@ -85,13 +199,66 @@ impl std::convert::From<String> for Payload {
     }
 }
 
+/// Supports creating a Payload instance from it's binary wire-format.
+///
+/// JSON and unknown payloads must be valid UTF-8, otherwise `MessageError::InvalidUtf8`
+/// is returned.
+impl std::convert::TryFrom<&[u8]> for Payload {
+    type Error = MessageError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match value.split_first() {
+            Some((b'J', rest)) => Ok(Payload::JSON(std::str::from_utf8(rest)?.to_string())),
+            Some((b'C', rest)) => Ok(Payload::CBOR(rest.to_vec())),
+            Some((b'M', rest)) => Ok(Payload::MSGPACK(rest.to_vec())),
+            Some((b'B', rest)) => Ok(Payload::BSON(rest.to_vec())),
+            Some((b'X', rest)) => Ok(Payload::BYTES(rest.to_vec())),
+            _ => Ok(Payload::UNKNOWN(std::str::from_utf8(value)?.to_string())),
+        }
+    }
+}
+
+/// Marshals the payload into it's binary wire-format, including the format prefix.
+impl std::convert::From<Payload> for Vec<u8> {
+    fn from(value: Payload) -> Self {
+        let (prefix, mut blob) = match value {
+            Payload::JSON(payload) => (Some(b'J'), payload.into_bytes()),
+            Payload::CBOR(blob) => (Some(b'C'), blob),
+            Payload::MSGPACK(blob) => (Some(b'M'), blob),
+            Payload::BSON(blob) => (Some(b'B'), blob),
+            Payload::BYTES(blob) => (Some(b'X'), blob),
+            Payload::UNKNOWN(payload) => (None, payload.into_bytes()),
+        };
+
+        if let Some(prefix) = prefix {
+            blob.insert(0, prefix);
+        }
+
+        blob
+    }
+}
+
 /// Display implementation for Payload that just displays the raw payload.
+///
+/// Binary payloads that are not valid UTF-8 are displayed lossy.
 impl std::fmt::Display for Payload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Payload::JSON(payload) => {
                 write!(f, "J{}", payload)
             },
+            Payload::CBOR(blob) => {
+                write!(f, "C{}", String::from_utf8_lossy(blob))
+            },
+            Payload::MSGPACK(blob) => {
+                write!(f, "M{}", String::from_utf8_lossy(blob))
+            },
+            Payload::BSON(blob) => {
+                write!(f, "B{}", String::from_utf8_lossy(blob))
+            },
+            Payload::BYTES(blob) => {
+                write!(f, "X{}", String::from_utf8_lossy(blob))
+            },
             Payload::UNKNOWN(payload) => {
                 write!(f, "{}", payload)
             }
@@ -113,25 +280,115 @@ pub struct Message {
     pub payload: Option<Payload>,
 }
 
+/// MessageRef is a borrowed variant of `Message` that references the wire-format
+/// frame it has been parsed from instead of allocating owned strings.
+///
+/// It is meant for high-volume streams where most messages are inspected (e.g. by id
+/// or key) and only a few need to be converted into an owned `Message`.
+/// The payload is kept in it's raw wire-format (including the format prefix).
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    pub id: usize,
+    pub cmd: &'a str,
+    pub key: Option<&'a str>,
+    pub payload: Option<&'a [u8]>,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parses the wire-format representation of a PortAPI message without allocating.
+    ///
+    /// Only the first three separators are significant: the id, the command and the key
+    /// must not contain a pipe character while the payload is taken verbatim and may contain
+    /// any number of pipes.
+    pub fn parse(line: &'a str) -> Result<Self, MessageError> {
+        Self::parse_bytes(line.as_bytes())
+    }
+
+    /// Like `parse` but accepts a binary frame. The id, command and key must be
+    /// valid UTF-8 while the payload may contain arbitrary bytes.
+    pub fn parse_bytes(frame: &'a [u8]) -> Result<Self, MessageError> {
+        let mut parts = frame.splitn(4, |b| *b == b'|');
+
+        let id = match parts.next() {
+            Some(s) => std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .ok_or(MessageError::InvalidID)?,
+            None => return Err(MessageError::MissingID),
+        };
+
+        let cmd = std::str::from_utf8(parts.next().ok_or(MessageError::MissingCommand)?)?;
+
+        let key = match parts.next() {
+            Some(key) => Some(std::str::from_utf8(key)?),
+            None => None,
+        };
+
+        Ok(MessageRef {
+            id,
+            cmd,
+            key,
+            payload: parts.next(),
+        })
+    }
+
+    /// Returns the command id of a frame without validating the rest of it.
+    ///
+    /// This is useful to route errors for malformed frames to the affected subscriber.
+    pub fn peek_id(frame: &[u8]) -> Option<usize> {
+        let id = frame.split(|b| *b == b'|').next()?;
+
+        std::str::from_utf8(id).ok()?.parse().ok()
+    }
+
+    /// Converts the borrowed message into an owned `Message`.
+    ///
+    /// This fails only if the payload cannot be decoded. See `Payload` for more information.
+    pub fn into_owned(self) -> Result<Message, MessageError> {
+        let payload = match self.payload {
+            Some(p) => Some(Payload::try_from(p)?),
+            None => None,
+        };
+
+        Ok(Message {
+            id: self.id,
+            cmd: self.cmd.to_string(),
+            key: self.key.map(str::to_string),
+            payload,
+        })
+    }
+}
+
+impl<'a> std::convert::TryFrom<MessageRef<'a>> for Message {
+    type Error = MessageError;
+
+    fn try_from(value: MessageRef<'a>) -> Result<Self, Self::Error> {
+        value.into_owned()
+    }
+}
+
 /// Implementation to marshal a PortAPI message into it's wire-format representation
 /// (which is a string).
 /// 
-/// Note that this conversion does not check for invalid messages!
+/// Note that this conversion does not check for invalid messages! Parsing the result
+/// yields the same message again as long as the command and the key do not contain a
+/// pipe character and a payload is only set together with a key.
+///
+/// Binary payloads are converted lossy, use the `Vec<u8>` conversion for them instead.
 impl std::convert::From<Message> for String {
     fn from(value: Message) -> Self {
-        let mut result = "".to_owned();
+        let mut result = value.id.to_string();
 
-        result.push_str(value.id.to_string().as_str());
-        result.push_str("|");
+        result.push('|');
         result.push_str(&value.cmd);
 
         if let Some(key) = value.key {
-            result.push_str("|");
+            result.push('|');
             result.push_str(key.as_str());
         }
 
         if let Some(payload) = value.payload {
-            result.push_str("|");
+            result.push('|');
             result.push_str(payload.to_string().as_str())
         }
 
@@ -139,49 +396,56 @@ impl std::convert::From<Message> for String {
     }
 }
 
+/// Implementation to marshal a PortAPI message into it's binary wire-format representation.
+///
+/// The same restrictions as for the `String` conversion apply.
+impl std::convert::From<Message> for Vec<u8> {
+    fn from(value: Message) -> Self {
+        let mut result = value.id.to_string().into_bytes();
+
+        result.push(b'|');
+        result.extend_from_slice(value.cmd.as_bytes());
+
+        if let Some(key) = value.key {
+            result.push(b'|');
+            result.extend_from_slice(key.as_bytes());
+        }
+
+        if let Some(payload) = value.payload {
+            result.push(b'|');
+            result.append(&mut payload.into());
+        }
+
+        result
+    }
+}
+
 /// An implementation for `String::parse()` to convert a wire-format representation
 /// of a PortAPI message to a Message instance.
 /// 
+/// See `MessageRef::parse()` for details on how the line is split.
 /// Any errors returned from `String::parse()` will be of type `MessageError`
 impl std::str::FromStr for Message {
     type Err = MessageError;
 
     fn from_str(line: &str) -> Result<Self, Self::Err> {
-        let parts = line.split("|").collect::<Vec<&str>>();
-
-        let id = match parts.get(0) {
-            Some(s) => match (*s).parse::<usize>() {
-                Ok(id) => Ok(id),
-                Err(_) => Err(MessageError::InvalidID),
-            },
-            None => Err(MessageError::MissingID),
-        }?;
-
-        let cmd = match parts.get(1) {
-            Some(s) => Ok(*s),
-            None => Err(MessageError::MissingCommand),
-        }?
-        .to_string();
-
-        let key = parts.get(2)
-            .and_then(|key| Some(key.to_string()));
+        MessageRef::parse(line)?.into_owned()
+    }
+}
 
-        let payload : Option<Payload> = parts.get(3)
-            .and_then(|p| Some(p.to_string().into()));
+/// Converts a binary wire-format representation of a PortAPI message to a Message instance.
+impl std::convert::TryFrom<&[u8]> for Message {
+    type Error = MessageError;
 
-        return Ok(Message {
-            id,
-            cmd,
-            key,
-            payload: payload
-        });
+    fn try_from(frame: &[u8]) -> Result<Self, Self::Error> {
+        MessageRef::parse_bytes(frame)?.into_owned()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
     #[derive(Debug, PartialEq, Deserialize)]
     struct Test {
@@ -255,4 +519,197 @@ mod tests {
             panic!("unexpected error value: {}", m)
         }
     }
+
+    #[test]
+    fn parse_message_with_pipes_in_payload() {
+        let m = "3|upd|core:profiles/local/1|J{\"Cmdline\": \"ls | grep foo\", \"Match\": \"a|b\"}"
+            .parse::<Message>()
+            .expect("Expected message to parse");
+
+        assert_eq!(m, Message{
+            id: 3,
+            cmd: "upd".to_string(),
+            key: Some("core:profiles/local/1".to_string()),
+            payload: Some(Payload::JSON("{\"Cmdline\": \"ls | grep foo\", \"Match\": \"a|b\"}".to_string())),
+        });
+
+        let m = "4|ok|some:key||".parse::<Message>()
+            .expect("Expected message to parse");
+
+        assert_eq!(m.payload, Some(Payload::UNKNOWN("|".to_string())));
+    }
+
+    #[test]
+    fn parse_message_ref() {
+        let line = "10|insert|some:key|J{\"url\": \"https://example.com/?a|b\"}";
+        let m = MessageRef::parse(line)
+            .expect("Expected message to parse");
+
+        assert_eq!(m, MessageRef{
+            id: 10,
+            cmd: "insert",
+            key: Some("some:key"),
+            payload: Some(b"J{\"url\": \"https://example.com/?a|b\"}".as_slice()),
+        });
+
+        assert_eq!(m.into_owned().unwrap(), line.parse::<Message>().unwrap());
+
+        assert_eq!(MessageRef::peek_id(b"12|\xff|key"), Some(12));
+        assert_eq!(MessageRef::peek_id(b"x12|done"), None);
+
+        let m = MessageRef::parse("abc|done")
+            .expect_err("Expected parsing to fail");
+
+        if let MessageError::InvalidID = m {} else {
+            panic!("unexpected error value: {}", m)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Nested {
+        #[serde(rename = "Value")]
+        value: Option<bool>,
+
+        #[serde(rename = "List")]
+        list: Vec<String>,
+    }
+
+    #[test]
+    fn payload_encode_and_parse() {
+        let value = Nested {
+            value: Some(true),
+            list: vec!["a|b".to_string(), "c".to_string()],
+        };
+
+        for format in [Format::JSON, Format::CBOR, Format::MSGPACK, Format::BSON] {
+            let p = Payload::encode(&value, format)
+                .expect("Expected payload encoding to work");
+
+            assert_eq!(p.format(), Some(format));
+
+            let blob: Vec<u8> = p.into();
+            let p = Payload::try_from(blob.as_slice())
+                .expect("Expected payload decoding to work");
+
+            let t: Nested = p.parse()
+                .expect("Expected payload parsing to work");
+
+            assert_eq!(t, value);
+        }
+
+        let p = Payload::BYTES(vec![0, 159, 146, 150]);
+        assert_eq!(p.format(), None);
+        p.parse::<Nested>().expect_err("Expected raw bytes parsing to fail");
+    }
+
+    #[test]
+    fn payload_from_bytes() {
+        let p = Payload::try_from(b"X\x00\xff|".as_slice())
+            .expect("Expected payload decoding to work");
+        assert_eq!(p, Payload::BYTES(vec![0, 255, b'|']));
+
+        let p = Payload::try_from(b"Cabc".as_slice())
+            .expect("Expected payload decoding to work");
+        assert_eq!(p, Payload::CBOR(b"abc".to_vec()));
+
+        let err = Payload::try_from(b"J{\xff}".as_slice())
+            .expect_err("Expected payload decoding to fail");
+
+        if let MessageError::InvalidUtf8(_) = err {} else {
+            panic!("unexpected error value: {}", err)
+        }
+    }
+
+    #[test]
+    fn message_with_binary_payload() {
+        let mut frame = b"7|ok|core:some/key|M".to_vec();
+        frame.extend_from_slice(&rmp_serde::to_vec_named(&Nested { value: None, list: vec![] }).unwrap());
+
+        let m = Message::try_from(frame.as_slice())
+            .expect("Expected message to parse");
+
+        assert_eq!(m.key.as_deref(), Some("core:some/key"));
+
+        let t: Nested = m.payload.as_ref().unwrap().parse()
+            .expect("Expected payload parsing to work");
+        assert_eq!(t, Nested { value: None, list: vec![] });
+
+        let encoded: Vec<u8> = m.into();
+        assert_eq!(encoded, frame);
+
+        let err = Message::try_from(b"7|ok|core:\xffkey|J{}".as_slice())
+            .expect_err("Expected message parsing to fail");
+
+        if let MessageError::InvalidUtf8(_) = err {} else {
+            panic!("unexpected error value: {}", err)
+        }
+    }
+
+    mod proptests {
+        use super::super::*;
+        use proptest::prelude::*;
+
+        fn message() -> impl Strategy<Value = Message> {
+            (
+                any::<usize>(),
+                "[a-z]{1,8}",
+                prop::option::of(("[^|]*", prop::option::of(any::<String>()))),
+            )
+                .prop_map(|(id, cmd, key)| {
+                    let (key, payload) = match key {
+                        Some((key, payload)) => (Some(key), payload.map(Payload::from)),
+                        None => (None, None),
+                    };
+
+                    Message { id, cmd, key, payload }
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn message_round_trip(msg in message()) {
+                let blob: String = msg.clone().into();
+                let parsed = blob.parse::<Message>().expect("Expected message to parse");
+
+                prop_assert_eq!(parsed, msg);
+            }
+
+            #[test]
+            fn message_ref_round_trip(msg in message()) {
+                let blob: String = msg.clone().into();
+                let parsed = MessageRef::parse(&blob).expect("Expected message to parse");
+
+                prop_assert_eq!(parsed.id, msg.id);
+                prop_assert_eq!(parsed.cmd, msg.cmd.as_str());
+                prop_assert_eq!(parsed.key, msg.key.as_deref());
+                prop_assert_eq!(parsed.payload.map(<[u8]>::to_vec), msg.payload.map(Vec::from));
+            }
+
+            #[test]
+            fn payload_round_trip(raw in any::<String>()) {
+                prop_assert_eq!(Payload::from(raw.clone()).to_string(), raw);
+            }
+
+            #[test]
+            fn message_bytes_round_trip(msg in message(), blob in any::<Vec<u8>>()) {
+                let msg = Message {
+                    payload: msg.key.as_ref().map(|_| Payload::BYTES(blob)),
+                    ..msg
+                };
+
+                let frame: Vec<u8> = msg.clone().into();
+                let parsed = Message::try_from(frame.as_slice()).expect("Expected message to parse");
+
+                prop_assert_eq!(parsed, msg);
+            }
+
+            #[test]
+            fn parse_never_panics(line in any::<String>()) {
+                let owned = line.parse::<Message>().ok();
+                let borrowed = MessageRef::parse(&line).ok().and_then(|m| m.into_owned().ok());
+
+                prop_assert_eq!(owned, borrowed);
+            }
+        }
+    }
 }
@@ -0,0 +1,694 @@
+//! An in-process mock of the Portmaster database API for tests.
+//!
+//! `MockServer` accepts websocket connections on a random local port and speaks
+//! the PortAPI protocol backed by an in-memory key/value store. Tests can modify
+//! the store using `MockServer::set` and `MockServer::delete`, which notifies all
+//! matching subscriptions just like portbase would.
+//!
+//! The server evaluates the key prefix and `where` conditions of queries against
+//! the JSON representation of each record. Like portbase, field keys may select
+//! array elements using `#(Key=="value")` or `#(Key!="value")`. `orderby` and the
+//! `matches` operator are not supported, records are always returned in key order.
+
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_websockets::{Message as Frame, ServerBuilder, WebSocketStream};
+
+use super::message::*;
+use super::query::*;
+
+/// The error portbase returns if a record does not exist.
+const NOT_FOUND: &str = "database entry not found";
+
+/// Frames queued for a single connection.
+enum Outgoing {
+    Frame(String),
+    Close,
+}
+
+/// An active `sub` or `qsub` of a connection.
+struct Sub {
+    conn: usize,
+    id: usize,
+    query: Query,
+}
+
+#[derive(Default)]
+struct State {
+    records: BTreeMap<String, Payload>,
+    subs: Vec<Sub>,
+    connections: HashMap<usize, UnboundedSender<Outgoing>>,
+    received: Vec<Message>,
+    next_conn: usize,
+}
+
+impl State {
+    fn send(&self, conn: usize, id: usize, cmd: &str, key: Option<&str>, payload: Option<&Payload>) {
+        let msg = Message {
+            id,
+            cmd: cmd.to_string(),
+            key: key.map(|k| k.to_string()),
+            payload: payload.cloned(),
+        };
+
+        if let Some(tx) = self.connections.get(&conn) {
+            let _ = tx.send(Outgoing::Frame(msg.into()));
+        }
+    }
+
+    /// Sends all records matching query as `ok` followed by `done`.
+    fn send_snapshot(&self, conn: usize, id: usize, query: &Query) {
+        let matching = self
+            .records
+            .iter()
+            .filter(|(key, payload)| matches(query, key, payload))
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX));
+
+        for (key, payload) in matching {
+            self.send(conn, id, "ok", Some(key), Some(payload));
+        }
+
+        self.send(conn, id, "done", None, None);
+    }
+
+    /// Notifies all subscriptions that match key about a change.
+    fn notify(&self, cmd: &str, key: &str, payload: Option<&Payload>) {
+        for sub in &self.subs {
+            // deleted records are matched by their last value.
+            let matched = match payload.or_else(|| self.records.get(key)) {
+                Some(payload) => matches(&sub.query, key, payload),
+                None => key.starts_with(&sub.query.prefix),
+            };
+
+            if matched {
+                self.send(sub.conn, sub.id, cmd, Some(key), payload);
+            }
+        }
+    }
+
+    fn set(&mut self, key: &str, payload: Payload) {
+        let cmd = if self.records.contains_key(key) {
+            "upd"
+        } else {
+            "new"
+        };
+
+        self.records.insert(key.to_string(), payload.clone());
+        self.notify(cmd, key, Some(&payload));
+    }
+
+    fn delete(&mut self, key: &str) -> bool {
+        if !self.records.contains_key(key) {
+            return false;
+        }
+
+        self.notify("del", key, None);
+        self.records.remove(key);
+
+        true
+    }
+
+    /// Handles a single message received on conn.
+    fn handle(&mut self, conn: usize, msg: Message) {
+        self.received.push(msg.clone());
+
+        let id = msg.id;
+        let key = msg.key.unwrap_or_default();
+
+        let query = || key.parse::<Query>();
+
+        match (msg.cmd.as_str(), msg.payload) {
+            ("get", _) => match self.records.get(&key) {
+                Some(payload) => self.send(conn, id, "ok", Some(&key), Some(payload)),
+                None => self.send(conn, id, "error", Some(NOT_FOUND), None),
+            },
+            ("query", _) | ("sub", _) | ("qsub", _) => {
+                let query = match query() {
+                    Ok(query) => query,
+                    Err(err) => {
+                        self.send(conn, id, "error", Some(&err.to_string()), None);
+                        return;
+                    }
+                };
+
+                if msg.cmd != "sub" {
+                    self.send_snapshot(conn, id, &query);
+                }
+
+                if msg.cmd != "query" {
+                    self.subs.push(Sub { conn, id, query });
+                }
+            }
+            ("create", Some(payload)) => {
+                if self.records.contains_key(&key) {
+                    self.send(conn, id, "error", Some("database entry already exists"), None);
+                } else {
+                    self.set(&key, payload);
+                    self.send(conn, id, "success", None, None);
+                }
+            }
+            ("update", Some(payload)) => {
+                if self.records.contains_key(&key) {
+                    self.write(&key, payload);
+                    self.send(conn, id, "success", None, None);
+                } else {
+                    self.send(conn, id, "error", Some(NOT_FOUND), None);
+                }
+            }
+            ("insert", Some(payload)) => match self.merge(&key, &payload) {
+                Some(merged) => {
+                    self.write(&key, merged);
+                    self.send(conn, id, "success", None, None);
+                }
+                None => self.send(conn, id, "error", Some(NOT_FOUND), None),
+            },
+            ("delete", _) => {
+                if self.delete(&key) {
+                    self.send(conn, id, "success", None, None);
+                } else {
+                    self.send(conn, id, "error", Some(NOT_FOUND), None);
+                }
+            }
+            ("cancel", _) => {
+                self.subs.retain(|sub| sub.conn != conn || sub.id != id);
+            }
+            (cmd, _) => {
+                let err = format!("unknown or unsupported command: {}", cmd);
+                self.send(conn, id, "error", Some(&err), None);
+            }
+        }
+    }
+
+    /// Replaces the record stored at key. Like portbase, notifications that exist
+    /// already only accept a new selected action and state, all other changes are
+    /// dropped.
+    fn write(&mut self, key: &str, payload: Payload) {
+        let existing = self.records.get(key).and_then(to_json);
+
+        let payload = match (existing, to_json(&payload)) {
+            (Some(Value::Object(mut existing)), Some(Value::Object(fields)))
+                if key.starts_with("notifications:") =>
+            {
+                for name in ["SelectedActionID", "State"] {
+                    if let Some(value) = fields.get(name) {
+                        existing.insert(name.to_string(), value.clone());
+                    }
+                }
+
+                Payload::JSON(Value::Object(existing).to_string())
+            }
+            _ => payload,
+        };
+
+        self.set(key, payload);
+    }
+
+    /// Merges the fields of payload into the record stored at key like portbase's
+    /// `insert` does. Returns None if the record does not exist.
+    fn merge(&self, key: &str, payload: &Payload) -> Option<Payload> {
+        let mut record = to_json(self.records.get(key)?)?;
+
+        if let (Value::Object(record), Some(Value::Object(fields))) = (&mut record, to_json(payload)) {
+            record.extend(fields);
+        }
+
+        Some(Payload::JSON(record.to_string()))
+    }
+}
+
+/// MockServer is a local websocket server speaking the PortAPI protocol. The server
+/// is shut down when the last reference is dropped.
+#[derive(Clone)]
+pub struct MockServer {
+    uri: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    /// Starts a new mock server on a random local port.
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}/api/database/v1", listener.local_addr().unwrap());
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let weak = Arc::downgrade(&state);
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = match weak.upgrade() {
+                    Some(state) => state,
+                    None => return,
+                };
+
+                if let Ok(ws) = ServerBuilder::new().accept(stream).await {
+                    tokio::spawn(serve(ws, state));
+                }
+            }
+        });
+
+        MockServer { uri, state }
+    }
+
+    /// Returns the URI to connect to.
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Creates or updates the record at key and notifies all matching subscriptions
+    /// with `new` or `upd`.
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) {
+        let payload = Payload::encode(value, Format::JSON).unwrap();
+
+        self.state.lock().unwrap().set(key, payload);
+    }
+
+    /// Deletes the record at key and notifies all matching subscriptions with `del`.
+    pub fn delete(&self, key: &str) {
+        self.state.lock().unwrap().delete(key);
+    }
+
+    /// Sends a raw response to all connections. This can be used to inject
+    /// errors, warnings or malformed frames.
+    pub fn inject(&self, frame: &str) {
+        let state = self.state.lock().unwrap();
+
+        for tx in state.connections.values() {
+            let _ = tx.send(Outgoing::Frame(frame.to_string()));
+        }
+    }
+
+    /// Closes all connections like a restart of the Portmaster core would. All
+    /// subscriptions are dropped while the records are kept.
+    pub fn disconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        for (_, tx) in state.connections.drain() {
+            let _ = tx.send(Outgoing::Close);
+        }
+
+        state.subs.clear();
+    }
+
+    /// Returns all messages received from clients so far.
+    pub fn received(&self) -> Vec<Message> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Returns the number of active subscriptions.
+    pub fn subscriptions(&self) -> usize {
+        self.state.lock().unwrap().subs.len()
+    }
+}
+
+/// Handles a single websocket connection until it is closed.
+async fn serve(mut ws: WebSocketStream<TcpStream>, state: Arc<Mutex<State>>) {
+    let (tx, mut rx) = unbounded_channel();
+
+    let conn = {
+        let mut state = state.lock().unwrap();
+
+        state.next_conn += 1;
+
+        let conn = state.next_conn;
+        state.connections.insert(conn, tx);
+
+        conn
+    };
+
+    loop {
+        tokio::select! {
+            frame = ws.next() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    _ => break,
+                };
+
+                if !frame.is_text() && !frame.is_binary() {
+                    continue;
+                }
+
+                if let Ok(msg) = Message::try_from(&frame.as_payload()[..]) {
+                    state.lock().unwrap().handle(conn, msg);
+                }
+            }
+
+            out = rx.recv() => {
+                match out {
+                    Some(Outgoing::Frame(frame)) => {
+                        if ws.send(Frame::text(frame)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Outgoing::Close) | None => {
+                        let _ = ws.close().await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    state.connections.remove(&conn);
+    state.subs.retain(|sub| sub.conn != conn);
+}
+
+fn to_json(payload: &Payload) -> Option<Value> {
+    payload.parse::<Value>().ok()
+}
+
+/// Reports whether the record at key matches query.
+fn matches(query: &Query, key: &str, payload: &Payload) -> bool {
+    if !key.starts_with(&query.prefix) {
+        return false;
+    }
+
+    match &query.condition {
+        Some(condition) => to_json(payload).is_some_and(|value| eval(condition, &value)),
+        None => true,
+    }
+}
+
+/// Evaluates condition against the JSON representation of a record.
+fn eval(condition: &Condition, record: &Value) -> bool {
+    match condition {
+        Condition::Compare { key, op, value } => {
+            let field = match lookup(record, key) {
+                Some(field) => field,
+                None => return false,
+            };
+
+            let number = || Some((field.as_f64()?, value.parse::<f64>().ok()?));
+            let text = as_text(field);
+
+            match op {
+                Operator::Equals => text == *value,
+                Operator::GreaterThan | Operator::FloatGreaterThan => {
+                    number().is_some_and(|(a, b)| a > b)
+                }
+                Operator::GreaterThanOrEqual | Operator::FloatGreaterThanOrEqual => {
+                    number().is_some_and(|(a, b)| a >= b)
+                }
+                Operator::LessThan | Operator::FloatLessThan => {
+                    number().is_some_and(|(a, b)| a < b)
+                }
+                Operator::LessThanOrEqual | Operator::FloatLessThanOrEqual => {
+                    number().is_some_and(|(a, b)| a <= b)
+                }
+                Operator::FloatEquals => number().is_some_and(|(a, b)| a == b),
+                Operator::SameAs => text.to_lowercase() == value.to_lowercase(),
+                Operator::Contains => text.contains(value.as_str()),
+                Operator::StartsWith => text.starts_with(value.as_str()),
+                Operator::EndsWith => text.ends_with(value.as_str()),
+                Operator::Matches => false,
+                Operator::Is => field.as_bool().is_some_and(|a| value.parse() == Ok(a)),
+            }
+        }
+        Condition::In { key, values } => {
+            lookup(record, key).is_some_and(|field| values.contains(&as_text(field)))
+        }
+        Condition::Exists(key) => lookup(record, key).is_some(),
+        Condition::And(conditions) => conditions.iter().all(|c| eval(c, record)),
+        Condition::Or(conditions) => conditions.iter().any(|c| eval(c, record)),
+        Condition::Not(condition) => !eval(condition, record),
+    }
+}
+
+/// Looks up a field using a dot separated path.
+fn lookup<'a>(record: &'a Value, path: &str) -> Option<&'a Value> {
+    split_path(path)
+        .into_iter()
+        .try_fold(record, |value, name| {
+            match name.strip_prefix("#(").and_then(|f| f.strip_suffix(')')) {
+                Some(filter) => select(value, filter),
+                None => value.get(name),
+            }
+        })
+}
+
+/// Splits path at dots that are not part of an array query.
+fn split_path(path: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (idx, c) in path.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' if depth > 0 => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            '.' if depth == 0 => {
+                parts.push(&path[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&path[start..]);
+    parts
+}
+
+/// Returns the first element of array that matches filter, e.g. `Value=="foo"`. The
+/// value is JSON encoded.
+fn select<'a>(array: &'a Value, filter: &str) -> Option<&'a Value> {
+    let (key, rest) = filter.split_at(filter.find(|c| c == '=' || c == '!')?);
+
+    let (equal, value) = match rest.strip_prefix("==") {
+        Some(value) => (true, value),
+        None => (false, rest.strip_prefix("!=")?),
+    };
+
+    let value: Value = serde_json::from_str(value).ok()?;
+
+    array
+        .as_array()?
+        .iter()
+        .find(|item| (item.get(key) == Some(&value)) == equal)
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portapi::client::*;
+    use crate::portapi::key::DbKey;
+    use crate::portapi::subscription::*;
+    use crate::portapi::types::*;
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Test {
+        a: String,
+        n: i64,
+    }
+
+    fn test(a: &str, n: i64) -> Test {
+        Test {
+            a: a.to_string(),
+            n,
+        }
+    }
+
+    fn key(key: &str) -> DbKey {
+        key.parse().unwrap()
+    }
+
+    fn json(value: &Test) -> Payload {
+        Payload::encode(value, Format::JSON).unwrap()
+    }
+
+    #[tokio::test]
+    async fn crud() {
+        let server = MockServer::start().await;
+        let api = connect(server.uri()).await.unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let mut res = api
+            .request(Request::Create(key("test:a"), json(&test("a", 1))))
+            .await
+            .unwrap();
+        assert_eq!(res.recv().await, Some(Response::Success));
+
+        let mut res = api
+            .request(Request::Create(key("test:a"), json(&test("a", 1))))
+            .await
+            .unwrap();
+        assert!(matches!(res.recv().await, Some(Response::Error(_))));
+
+        let mut res = api
+            .request(Request::Update(key("test:a"), json(&test("b", 2))))
+            .await
+            .unwrap();
+        assert_eq!(res.recv().await, Some(Response::Success));
+
+        let mut res = api
+            .request(Request::Insert(
+                key("test:a"),
+                Payload::JSON("{\"n\": 3}".to_string()),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.recv().await, Some(Response::Success));
+
+        let (_, value) = api.get::<Test>(&key("test:a"), timeout).await.unwrap();
+        assert_eq!(value, test("b", 3));
+
+        let mut res = api.request(Request::Delete(key("test:a"))).await.unwrap();
+        assert_eq!(res.recv().await, Some(Response::Success));
+
+        assert!(matches!(
+            api.get::<Test>(&key("test:a"), timeout).await,
+            Err(ClientError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_conditions() {
+        let server = MockServer::start().await;
+        server.set("test:a", &test("foo", 1));
+        server.set("test:b", &test("bar", 2));
+        server.set("test:c", &test("foobar", 3));
+        server.set("other:a", &test("foo", 4));
+
+        let api = connect(server.uri()).await.unwrap();
+
+        let keys = |query: Query| {
+            let api = api.clone();
+            async move {
+                api.query_all::<Value>(query, Duration::from_secs(5))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(record, _)| record.key.to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(keys(Query::new("test:")).await, ["test:a", "test:b", "test:c"]);
+        assert_eq!(
+            keys(Query::new("test:").filter(field("a").starts_with("foo").and(field("n").gt(1)))).await,
+            ["test:c"]
+        );
+        assert_eq!(
+            keys(Query::new("test:").filter(!field("a").one_of(["foo", "bar"]))).await,
+            ["test:c"]
+        );
+        assert_eq!(keys(Query::new("test:").offset(1).limit(1)).await, ["test:b"]);
+
+        server.set("list:a", &serde_json::json!({"L": [{"K": "x.y"}, {"K": "a\"b"}]}));
+        server.set("list:b", &serde_json::json!({"L": [{"K": "x.y"}]}));
+
+        assert_eq!(
+            keys(Query::new("list:").filter(field("L.#(K==\"a\\\"b\").K").eq("a\"b"))).await,
+            ["list:a"]
+        );
+        assert_eq!(
+            keys(Query::new("list:").filter(field("L.#(K!=\"x.y\")").exists())).await,
+            ["list:a"]
+        );
+        assert_eq!(
+            keys(Query::new("list:").filter(field("L.#(K==\"x.y\")").exists())).await,
+            ["list:a", "list:b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriptions() {
+        let server = MockServer::start().await;
+        server.set("test:a", &test("a", 1));
+
+        let api = connect(server.uri()).await.unwrap();
+
+        let mut qsub = api.subscribe::<Test>(Query::new("test:")).await.unwrap();
+        let mut sub = api
+            .request(Request::Subscribe(Query::new("test:").filter(field("n").gt(1))))
+            .await
+            .unwrap();
+
+        assert!(matches!(qsub.next().await, Some(RecordEvent::Initial(_, v)) if v == test("a", 1)));
+        assert!(matches!(qsub.next().await, Some(RecordEvent::SnapshotDone)));
+
+        // make sure both subscriptions are registered before changing records.
+        while server.subscriptions() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        server.set("test:a", &test("a", 2));
+        server.set("test:b", &test("b", 1));
+        server.delete("test:a");
+
+        let qsub_id = server
+            .received()
+            .iter()
+            .find(|msg| msg.cmd == "qsub")
+            .map(|msg| msg.id)
+            .unwrap();
+        server.inject(&format!("{}|warning|slow", qsub_id));
+
+        assert!(matches!(qsub.next().await, Some(RecordEvent::Updated(_, v)) if v == test("a", 2)));
+        assert!(matches!(qsub.next().await, Some(RecordEvent::Created(_, v)) if v == test("b", 1)));
+        assert!(matches!(qsub.next().await, Some(RecordEvent::Deleted(k)) if k == key("test:a")));
+        assert!(matches!(qsub.next().await, Some(RecordEvent::Warning(w)) if w == "slow"));
+
+        // test:b does not match the where condition of sub.
+        assert!(matches!(sub.recv().await, Some(Response::Update(k, _)) if k == "test:a"));
+        assert!(matches!(sub.recv().await, Some(Response::Delete(k)) if k == "test:a"));
+
+        drop(qsub);
+        drop(sub);
+
+        while server.subscriptions() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let cancels = server
+            .received()
+            .into_iter()
+            .filter(|msg| msg.cmd == "cancel")
+            .count();
+        assert_eq!(cancels, 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let server = MockServer::start().await;
+        let api = connect(server.uri()).await.unwrap();
+
+        server.disconnect();
+
+        let mut res = api
+            .request(Request::Get(key("test:a")))
+            .await
+            .unwrap_or_else(|_| panic!("client closed too early"));
+
+        // the request is either failed or rejected once the connection is gone.
+        let _ = res.recv().await;
+
+        while !api.is_closed() {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            api.request(Request::Get(key("test:a"))).await,
+            Err(ClientError::Closed)
+        ));
+    }
+}
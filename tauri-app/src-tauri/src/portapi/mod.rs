@@ -1,4 +1,13 @@
+pub mod auth;
 pub mod client;
+pub mod endpoint;
+pub mod http;
+pub mod key;
 pub mod message;
+#[cfg(test)]
+pub mod mock;
+pub mod query;
+pub mod recording;
+pub mod subscription;
 pub mod types;
 pub mod models;
\ No newline at end of file
@@ -1,5 +1,16 @@
 use serde::*;
-use super::super::message::Payload;
+#[cfg(test)]
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+use super::super::client::{ClientError, PortAPI};
+use super::super::key::DbKey;
+#[cfg(test)]
+use super::super::message::ParseError;
+use super::super::message::{EncodeError, Format, Payload};
+use super::super::types::Request;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct BooleanValue {
@@ -8,11 +19,438 @@ pub struct BooleanValue {
 }
 
 impl TryInto<Payload> for BooleanValue {
-    type Error = serde_json::Error;
+    type Error = EncodeError;
 
     fn try_into(self) -> Result<Payload, Self::Error> {
-        let str = serde_json::to_string(&self)?;
+        Payload::encode(&self, Format::JSON)
+    }
+}
+
+/// The value of a configuration option, used to update an option using
+/// `Request::Update`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct OptionValue {
+    #[serde(rename = "Value")]
+    pub value: serde_json::Value,
+}
+
+impl TryInto<Payload> for OptionValue {
+    type Error = EncodeError;
+
+    fn try_into(self) -> Result<Payload, Self::Error> {
+        Payload::encode(&self, Format::JSON)
+    }
+}
+
+/// A configuration option as exported by portbase in the `config:` database.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ConfigOption {
+    #[serde(rename = "Key")]
+    pub key: String,
+
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    #[serde(rename = "Description", default)]
+    pub description: String,
+
+    #[serde(rename = "OptType")]
+    pub opt_type: OptType,
+
+    #[serde(rename = "DefaultValue", default)]
+    pub default_value: serde_json::Value,
+
+    /// The value configured by the user, null if the default value is used.
+    #[serde(rename = "Value", default)]
+    pub value: serde_json::Value,
+
+    #[serde(rename = "ExpertiseLevel", default)]
+    pub expertise_level: ExpertiseLevel,
+
+    #[serde(rename = "ReleaseLevel", default)]
+    pub release_level: ReleaseLevel,
+
+    #[serde(rename = "Annotations", default)]
+    pub annotations: HashMap<String, serde_json::Value>,
+
+    #[serde(rename = "ValidationRegex", default)]
+    pub validation_regex: String,
+}
+
+impl ConfigOption {
+    /// Returns the value that is currently in effect, either the value configured
+    /// by the user or the default value.
+    #[cfg(test)]
+    pub fn effective_value(&self) -> &serde_json::Value {
+        if self.value.is_null() {
+            &self.default_value
+        } else {
+            &self.value
+        }
+    }
+
+    /// Validates value against the type and the validation regex of the option
+    /// the same way portbase does before accepting a new value.
+    pub fn validate(&self, value: &serde_json::Value) -> Result<(), ConfigError> {
+        let valid_type = match self.opt_type {
+            OptType::String => value.is_string(),
+            OptType::StringArray => value
+                .as_array()
+                .map(|values| values.iter().all(|v| v.is_string()))
+                .unwrap_or(false),
+            OptType::Int => value.is_i64(),
+            OptType::Bool => value.is_boolean(),
+            OptType::Unknown(_) => false,
+        };
+
+        if !valid_type {
+            return Err(ConfigError::InvalidType {
+                key: self.key.clone(),
+                expected: self.opt_type,
+            });
+        }
+
+        if self.validation_regex.is_empty() {
+            return Ok(());
+        }
+
+        let regex = regex::Regex::new(&self.validation_regex).map_err(|err| {
+            ConfigError::InvalidRegex {
+                key: self.key.clone(),
+                message: err.to_string(),
+            }
+        })?;
+
+        // portbase applies the regex to each entry of string arrays.
+        let strings: Vec<&str> = match value {
+            serde_json::Value::String(s) => vec![s.as_str()],
+            serde_json::Value::Array(values) => values.iter().filter_map(|v| v.as_str()).collect(),
+            _ => vec![],
+        };
+
+        for s in strings {
+            if !regex.is_match(s) {
+                return Err(ConfigError::InvalidValue {
+                    key: self.key.clone(),
+                    value: s.to_string(),
+                    regex: self.validation_regex.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ConfigError {
+    #[error("{key} expects a value of type {expected}")]
+    InvalidType { key: String, expected: OptType },
+
+    #[error("{key}: {value:?} does not match {regex}")]
+    InvalidValue {
+        key: String,
+        value: String,
+        regex: String,
+    },
+
+    #[error("{key} has an invalid validation regex: {message}")]
+    InvalidRegex { key: String, message: String },
+
+    #[error("failed to encode value: {0}")]
+    Encode(String),
+}
+
+/// The type of a configuration option.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(from = "u8", into = "u8")]
+pub enum OptType {
+    String,
+    StringArray,
+    Int,
+    Bool,
+    Unknown(u8),
+}
+
+impl From<u8> for OptType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => OptType::String,
+            2 => OptType::StringArray,
+            3 => OptType::Int,
+            4 => OptType::Bool,
+            other => OptType::Unknown(other),
+        }
+    }
+}
+
+impl From<OptType> for u8 {
+    fn from(value: OptType) -> Self {
+        match value {
+            OptType::String => 1,
+            OptType::StringArray => 2,
+            OptType::Int => 3,
+            OptType::Bool => 4,
+            OptType::Unknown(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for OptType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptType::String => f.write_str("string"),
+            OptType::StringArray => f.write_str("[]string"),
+            OptType::Int => f.write_str("int"),
+            OptType::Bool => f.write_str("bool"),
+            OptType::Unknown(other) => write!(f, "unknown({})", other),
+        }
+    }
+}
+
+/// The expertise level a user needs to see a configuration option.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(from = "u8", into = "u8")]
+pub enum ExpertiseLevel {
+    #[default]
+    User,
+    Expert,
+    Developer,
+}
 
-        Ok(Payload::JSON(str))
+impl From<u8> for ExpertiseLevel {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ExpertiseLevel::User,
+            1 => ExpertiseLevel::Expert,
+            _ => ExpertiseLevel::Developer,
+        }
     }
-}
\ No newline at end of file
+}
+
+impl From<ExpertiseLevel> for u8 {
+    fn from(value: ExpertiseLevel) -> Self {
+        value as u8
+    }
+}
+
+/// The release level of the feature a configuration option belongs to.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(from = "u8", into = "u8")]
+pub enum ReleaseLevel {
+    #[default]
+    Stable,
+    Beta,
+    Experimental,
+}
+
+impl From<u8> for ReleaseLevel {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ReleaseLevel::Stable,
+            1 => ReleaseLevel::Beta,
+            _ => ReleaseLevel::Experimental,
+        }
+    }
+}
+
+impl From<ReleaseLevel> for u8 {
+    fn from(value: ReleaseLevel) -> Self {
+        value as u8
+    }
+}
+
+/// ConfigApi adds helpers to read and change configuration options to `PortAPI`.
+pub trait ConfigApi {
+    /// `get_config_option` fetches the configuration option with the given key, e.g.
+    /// `spn/enable`, from the `config:` database.
+    async fn get_config_option(
+        &self,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<ConfigOption, ClientError>;
+
+    /// `get_config` returns the value of the configuration option with the given key
+    /// decoded into T. If the user did not configure a value, the default value is
+    /// returned.
+    #[cfg(test)]
+    async fn get_config<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<T, ClientError>;
+
+    /// `set_config` sets the configuration option with the given key to value.
+    ///
+    /// The option is fetched first so value can be validated against the option type and
+    /// validation regex. Returns `ClientError::Config` if the value is not valid.
+    async fn set_config<T: Serialize>(
+        &self,
+        key: &str,
+        value: T,
+        timeout: Duration,
+    ) -> Result<(), ClientError>;
+
+    /// `reset_config` resets the configuration option with the given key to its default
+    /// value.
+    #[cfg(test)]
+    async fn reset_config(&self, key: &str, timeout: Duration) -> Result<(), ClientError>;
+}
+
+impl ConfigApi for PortAPI {
+    async fn get_config_option(
+        &self,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<ConfigOption, ClientError> {
+        let (_, option) = self.get(&config_key(key)?, timeout).await?;
+
+        Ok(option)
+    }
+
+    #[cfg(test)]
+    async fn get_config<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        timeout: Duration,
+    ) -> Result<T, ClientError> {
+        let option = self.get_config_option(key, timeout).await?;
+
+        serde_json::from_value(option.effective_value().clone())
+            .map_err(|err| ClientError::Parse(format!("config:{}", key), ParseError::JSON(err)))
+    }
+
+    async fn set_config<T: Serialize>(
+        &self,
+        key: &str,
+        value: T,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        let value = serde_json::to_value(value)
+            .map_err(|err| ConfigError::Encode(err.to_string()))?;
+
+        let option = self.get_config_option(key, timeout).await?;
+        option.validate(&value)?;
+
+        let payload: Payload = OptionValue { value }
+            .try_into()
+            .map_err(|err: EncodeError| ConfigError::Encode(err.to_string()))?;
+
+        self.expect_success(Request::Update(config_key(key)?, payload), timeout)
+            .await
+    }
+
+    #[cfg(test)]
+    async fn reset_config(&self, key: &str, timeout: Duration) -> Result<(), ClientError> {
+        // portbase resets options when their record is deleted.
+        self.expect_success(Request::Delete(config_key(key)?), timeout)
+            .await
+    }
+}
+
+/// Returns the database key of the configuration option key.
+fn config_key(key: &str) -> Result<DbKey, ClientError> {
+    Ok(format!("config:{}", key).parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn option(opt_type: u8, regex: &str) -> ConfigOption {
+        serde_json::from_value(json!({
+            "Key": "test/option",
+            "Name": "Test",
+            "OptType": opt_type,
+            "DefaultValue": null,
+            "ExpertiseLevel": 1,
+            "ReleaseLevel": 2,
+            "ValidationRegex": regex,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_values() {
+        let opt = option(4, "");
+        assert_eq!(opt.opt_type, OptType::Bool);
+        assert_eq!(opt.expertise_level, ExpertiseLevel::Expert);
+        assert_eq!(opt.release_level, ReleaseLevel::Experimental);
+        assert!(opt.validate(&json!(true)).is_ok());
+        assert_eq!(
+            opt.validate(&json!("true")),
+            Err(ConfigError::InvalidType {
+                key: "test/option".to_string(),
+                expected: OptType::Bool
+            })
+        );
+
+        let opt = option(3, "");
+        assert!(opt.validate(&json!(10)).is_ok());
+        assert!(opt.validate(&json!(1.5)).is_err());
+
+        let opt = option(2, "^[a-z]+$");
+        assert!(opt.validate(&json!(["a", "b"])).is_ok());
+        assert!(opt.validate(&json!(["a", 1])).is_err());
+        assert!(matches!(
+            opt.validate(&json!(["a", "B"])),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+
+        let opt = option(9, "");
+        assert_eq!(opt.opt_type, OptType::Unknown(9));
+        assert!(opt.validate(&json!("x")).is_err());
+    }
+
+    #[tokio::test]
+    async fn config_api() {
+        let server = crate::portapi::mock::MockServer::start().await;
+        let api = crate::portapi::client::connect(server.uri()).await.unwrap();
+        let timeout = Duration::from_secs(5);
+
+        server.set(
+            "config:filter/lists",
+            &serde_json::json!({
+                "Key": "filter/lists",
+                "Name": "Filter Lists",
+                "OptType": 2,
+                "DefaultValue": ["TRAC"],
+                "Value": null,
+                "ValidationRegex": "^[A-Z]+$",
+            }),
+        );
+
+        let lists: Vec<String> = api.get_config("filter/lists", timeout).await.unwrap();
+        assert_eq!(lists, vec!["TRAC".to_string()]);
+
+        assert!(matches!(
+            api.set_config("filter/lists", true, timeout).await,
+            Err(ClientError::Config(ConfigError::InvalidType { .. }))
+        ));
+        assert!(matches!(
+            api.set_config("filter/lists", ["trac"], timeout).await,
+            Err(ClientError::Config(ConfigError::InvalidValue { .. }))
+        ));
+
+        api.set_config("filter/lists", ["TRAC", "MAL"], timeout)
+            .await
+            .unwrap();
+
+        let update = server.received().pop().unwrap();
+        assert_eq!(update.cmd, "update");
+        assert_eq!(update.key.as_deref(), Some("config:filter/lists"));
+        assert_eq!(
+            update.payload.unwrap().parse::<OptionValue>().unwrap().value,
+            serde_json::json!(["TRAC", "MAL"])
+        );
+
+        api.reset_config("filter/lists", timeout).await.unwrap();
+        assert_eq!(server.received().pop().unwrap().cmd, "delete");
+
+        assert!(matches!(
+            api.get_config::<bool>("spn/enable", timeout).await,
+            Err(ClientError::NotFound)
+        ));
+    }
+}
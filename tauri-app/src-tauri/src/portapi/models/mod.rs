@@ -1,4 +1,18 @@
 pub mod config;
+pub mod network;
+pub mod profile;
 pub mod spn;
 pub mod notification;
-pub mod subsystem;
\ No newline at end of file
+pub mod subsystem;
+
+use serde::{Deserialize, Deserializer};
+
+/// Deserializes null, which Go uses for nil pointers, slices and maps, as the default
+/// value.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
@@ -0,0 +1,538 @@
+use serde::*;
+#[cfg(test)]
+use std::net::IpAddr;
+
+#[cfg(test)]
+use super::super::client::{ClientError, PortAPI};
+#[cfg(test)]
+use super::super::query::{field, Condition, Query};
+#[cfg(test)]
+use super::super::subscription::Subscription;
+use super::null_as_default;
+
+/// The key prefix of all processes and connections in the `network:` database.
+#[cfg(test)]
+pub const NETWORK_PREFIX: &str = "network:tree/";
+
+/// A network connection or DNS request as exported by the `network:` database.
+/// Connections are stored as `network:tree/<pid>/<scope>/<id>`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Connection {
+    #[serde(rename = "ID")]
+    pub id: String,
+
+    #[serde(rename = "Type")]
+    pub conn_type: ConnectionType,
+
+    #[serde(rename = "External", default)]
+    pub external: bool,
+
+    #[serde(rename = "IPVersion", default)]
+    pub ip_version: u8,
+
+    #[serde(rename = "Inbound", default)]
+    pub inbound: bool,
+
+    #[serde(rename = "IPProtocol", default)]
+    pub ip_protocol: u8,
+
+    #[serde(rename = "LocalIP", default)]
+    pub local_ip: String,
+
+    #[serde(rename = "LocalPort", default)]
+    pub local_port: u16,
+
+    #[serde(rename = "Entity", default, deserialize_with = "null_as_default")]
+    pub entity: Entity,
+
+    #[serde(rename = "Verdict", default, deserialize_with = "null_as_default")]
+    pub verdict: Verdicts,
+
+    #[serde(rename = "Reason", default, deserialize_with = "null_as_default")]
+    pub reason: Reason,
+
+    #[serde(rename = "Started", default)]
+    pub started: i64,
+
+    /// The unix timestamp when the connection ended, 0 while it's still active.
+    #[serde(rename = "Ended", default)]
+    pub ended: i64,
+
+    #[serde(rename = "VerdictPermanent", default)]
+    pub verdict_permanent: bool,
+
+    #[serde(rename = "Tunneled", default)]
+    pub tunneled: bool,
+
+    #[serde(rename = "Encrypted", default)]
+    pub encrypted: bool,
+
+    #[serde(rename = "Internal", default)]
+    pub internal: bool,
+
+    #[serde(rename = "ProcessContext", default, deserialize_with = "null_as_default")]
+    pub process_context: ProcessContext,
+}
+
+#[cfg(test)]
+impl Connection {
+    /// Reports whether the connection is still active.
+    pub fn is_active(&self) -> bool {
+        self.ended == 0
+    }
+
+    /// Reports whether the connection has been blocked or dropped.
+    pub fn is_blocked(&self) -> bool {
+        matches!(self.verdict.active, Verdict::Block | Verdict::Drop)
+    }
+
+    /// Returns the domain of the remote entity or its IP address if the domain
+    /// is unknown.
+    pub fn remote(&self) -> &str {
+        if self.entity.domain.is_empty() {
+            &self.entity.ip
+        } else {
+            &self.entity.domain
+        }
+    }
+}
+
+/// The type of a `Connection`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(from = "u8", into = "u8")]
+pub enum ConnectionType {
+    IP,
+    DNSRequest,
+    Unknown(u8),
+}
+
+impl From<u8> for ConnectionType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ConnectionType::IP,
+            2 => ConnectionType::DNSRequest,
+            other => ConnectionType::Unknown(other),
+        }
+    }
+}
+
+impl From<ConnectionType> for u8 {
+    fn from(value: ConnectionType) -> Self {
+        match value {
+            ConnectionType::IP => 1,
+            ConnectionType::DNSRequest => 2,
+            ConnectionType::Unknown(other) => other,
+        }
+    }
+}
+
+/// The verdicts of a connection.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Verdicts {
+    /// The worst verdict the connection ever had.
+    #[serde(rename = "Worst", default)]
+    pub worst: Verdict,
+
+    /// The verdict currently in effect.
+    #[serde(rename = "Active", default)]
+    pub active: Verdict,
+
+    /// The verdict applied by the firewall, before any tunneling.
+    #[serde(rename = "Firewall", default)]
+    pub firewall: Verdict,
+}
+
+/// The decision the firewall made for a connection.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(from = "u8", into = "u8")]
+pub enum Verdict {
+    #[default]
+    Undecided,
+    Undeterminable,
+    Accept,
+    Block,
+    Drop,
+    RerouteToNameserver,
+    RerouteToTunnel,
+    Failed,
+    Unknown(u8),
+}
+
+impl From<u8> for Verdict {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Verdict::Undecided,
+            1 => Verdict::Undeterminable,
+            2 => Verdict::Accept,
+            3 => Verdict::Block,
+            4 => Verdict::Drop,
+            5 => Verdict::RerouteToNameserver,
+            6 => Verdict::RerouteToTunnel,
+            7 => Verdict::Failed,
+            other => Verdict::Unknown(other),
+        }
+    }
+}
+
+impl From<Verdict> for u8 {
+    fn from(value: Verdict) -> Self {
+        match value {
+            Verdict::Undecided => 0,
+            Verdict::Undeterminable => 1,
+            Verdict::Accept => 2,
+            Verdict::Block => 3,
+            Verdict::Drop => 4,
+            Verdict::RerouteToNameserver => 5,
+            Verdict::RerouteToTunnel => 6,
+            Verdict::Failed => 7,
+            Verdict::Unknown(other) => other,
+        }
+    }
+}
+
+/// The reason for the verdict of a connection.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Reason {
+    #[serde(rename = "Msg", default)]
+    pub msg: String,
+
+    /// The configuration option that caused the verdict, if any.
+    #[serde(rename = "OptionKey", default)]
+    pub option_key: String,
+
+    /// The profile the option has been configured in, if any.
+    #[serde(rename = "Profile", default)]
+    pub profile: String,
+}
+
+/// The remote side of a connection.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Entity {
+    #[serde(rename = "IPScope", default)]
+    pub ip_scope: i8,
+
+    #[serde(rename = "Protocol", default)]
+    pub protocol: u8,
+
+    #[serde(rename = "Port", default)]
+    pub port: u16,
+
+    #[serde(rename = "Domain", default)]
+    pub domain: String,
+
+    #[serde(rename = "CNAME", default, deserialize_with = "null_as_default")]
+    pub cname: Vec<String>,
+
+    #[serde(rename = "IP", default)]
+    pub ip: String,
+
+    #[serde(rename = "Country", default)]
+    pub country: String,
+
+    #[serde(rename = "ASN", default)]
+    pub asn: u32,
+
+    #[serde(rename = "ASOrg", default)]
+    pub as_org: String,
+
+    #[serde(rename = "BlockedByLists", default, deserialize_with = "null_as_default")]
+    pub blocked_by_lists: Vec<String>,
+}
+
+#[cfg(test)]
+impl Entity {
+    /// Returns the parsed IP address of the entity, None for DNS requests.
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        self.ip.parse().ok()
+    }
+}
+
+/// The process a connection belongs to.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ProcessContext {
+    #[serde(rename = "BinaryPath", default)]
+    pub binary_path: String,
+
+    #[serde(rename = "ProcessName", default)]
+    pub process_name: String,
+
+    #[serde(rename = "ProfileName", default)]
+    pub profile_name: String,
+
+    #[serde(rename = "PID", default)]
+    pub pid: i64,
+
+    #[serde(rename = "CreatedAt", default)]
+    pub created_at: i64,
+
+    /// The ID of the app profile, see `source`.
+    #[serde(rename = "Profile", default)]
+    pub profile: String,
+
+    /// The source of the app profile, e.g. `local`.
+    #[serde(rename = "Source", default)]
+    pub source: String,
+}
+
+/// A process as exported by the `network:` database. Processes are stored as
+/// `network:tree/<pid>` and own the connections stored below their key.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct Process {
+    #[serde(rename = "Pid")]
+    pub pid: i64,
+
+    #[serde(rename = "CreatedAt", default)]
+    pub created_at: i64,
+
+    #[serde(rename = "ParentPid", default)]
+    pub parent_pid: i64,
+
+    #[serde(rename = "Name", default)]
+    pub name: String,
+
+    #[serde(rename = "Path", default)]
+    pub path: String,
+
+    #[serde(rename = "ExecName", default)]
+    pub exec_name: String,
+
+    #[serde(rename = "Cwd", default)]
+    pub cwd: String,
+
+    #[serde(rename = "CmdLine", default)]
+    pub cmd_line: String,
+
+    #[serde(rename = "UserID", default)]
+    pub user_id: i64,
+
+    #[serde(rename = "UserName", default)]
+    pub user_name: String,
+
+    /// The key of the local profile the process is attributed to, if any.
+    #[serde(rename = "LocalProfileKey", default)]
+    pub local_profile_key: String,
+
+    #[serde(rename = "Tags", default, deserialize_with = "null_as_default")]
+    pub tags: Vec<ProcessTag>,
+
+    #[serde(rename = "FirstSeen", default)]
+    pub first_seen: i64,
+
+    #[serde(rename = "LastSeen", default)]
+    pub last_seen: i64,
+
+    /// Set if the process information could not be fully retrieved.
+    #[serde(rename = "Error", default)]
+    pub error: String,
+}
+
+/// A tag attached to a process, e.g. the name of a flatpak or snap package.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ProcessTag {
+    #[serde(rename = "Key", default)]
+    pub key: String,
+
+    #[serde(rename = "Value", default)]
+    pub value: String,
+}
+
+/// Returns a query for all connections in the `network:` database that match
+/// condition. Process records that share the key space are skipped.
+#[cfg(test)]
+pub fn connections_query(condition: Option<Condition>) -> Query {
+    let is_connection = field("Type").exists();
+
+    Query::new(NETWORK_PREFIX).filter(match condition {
+        Some(condition) => is_connection.and(condition),
+        None => is_connection,
+    })
+}
+
+/// Returns a query for all processes in the `network:` database that match
+/// condition. Connection records that share the key space are skipped.
+#[cfg(test)]
+pub fn processes_query(condition: Option<Condition>) -> Query {
+    let is_process = !field("Type").exists();
+
+    Query::new(NETWORK_PREFIX).filter(match condition {
+        Some(condition) => is_process.and(condition),
+        None => is_process,
+    })
+}
+
+/// NetworkApi adds subscriptions to the processes and connections in the `network:`
+/// database to `PortAPI`. See `PortAPI::subscribe` for the events yielded by the streams.
+#[cfg(test)]
+pub trait NetworkApi {
+    /// `subscribe_connections` subscribes to all network connections and DNS requests
+    /// that match condition.
+    async fn subscribe_connections(
+        &self,
+        condition: Option<Condition>,
+    ) -> Result<Subscription<Connection>, ClientError>;
+
+    /// `subscribe_processes` subscribes to all processes that match condition.
+    async fn subscribe_processes(
+        &self,
+        condition: Option<Condition>,
+    ) -> Result<Subscription<Process>, ClientError>;
+}
+
+#[cfg(test)]
+impl NetworkApi for PortAPI {
+    async fn subscribe_connections(
+        &self,
+        condition: Option<Condition>,
+    ) -> Result<Subscription<Connection>, ClientError> {
+        self.subscribe(connections_query(condition)).await
+    }
+
+    async fn subscribe_processes(
+        &self,
+        condition: Option<Condition>,
+    ) -> Result<Subscription<Process>, ClientError> {
+        self.subscribe(processes_query(condition)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portapi::subscription::RecordEvent;
+    use futures_util::StreamExt;
+    use serde_json::json;
+
+    #[test]
+    fn parse_connection() {
+        let conn: Connection = serde_json::from_value(json!({
+            "ID": "192.168.1.2-443",
+            "Type": 1,
+            "External": true,
+            "IPVersion": 4,
+            "IPProtocol": 6,
+            "LocalIP": "192.168.1.10",
+            "LocalPort": 50123,
+            "Entity": {
+                "IPScope": 4,
+                "Protocol": 6,
+                "Port": 443,
+                "Domain": "example.com.",
+                "IP": "93.184.216.34",
+                "Country": "US",
+                "ASN": 15133,
+                "ASOrg": "Edgecast",
+                "CNAME": null,
+            },
+            "Verdict": {"Worst": 3, "Active": 3, "Firewall": 3},
+            "Reason": {"Msg": "blocked by filter list", "OptionKey": "filter/lists"},
+            "Started": 1700000000,
+            "Ended": 0,
+            "Encrypted": true,
+            "ProcessContext": {"ProcessName": "firefox", "PID": 42, "Source": "local"},
+            "Unknown": 1,
+        }))
+        .unwrap();
+
+        assert_eq!(conn.conn_type, ConnectionType::IP);
+        assert!(conn.is_active());
+        assert!(conn.is_blocked());
+        assert_eq!(conn.remote(), "example.com.");
+        assert_eq!(conn.entity.ip_addr(), Some("93.184.216.34".parse().unwrap()));
+        assert_eq!(conn.entity.asn, 15133);
+        assert!(conn.entity.cname.is_empty());
+        assert_eq!(conn.process_context.process_name, "firefox");
+        assert_eq!(conn.reason.option_key, "filter/lists");
+
+        let dns: Connection = serde_json::from_value(json!({
+            "ID": "example.com.-A",
+            "Type": 2,
+            "Verdict": {"Active": 9},
+            "Ended": 1700000001,
+        }))
+        .unwrap();
+
+        assert_eq!(dns.conn_type, ConnectionType::DNSRequest);
+        assert_eq!(dns.verdict.active, Verdict::Unknown(9));
+        assert!(!dns.is_active());
+        assert_eq!(dns.entity.ip_addr(), None);
+    }
+
+    #[test]
+    fn parse_process() {
+        let process: Process = serde_json::from_value(json!({
+            "Pid": 42,
+            "CreatedAt": 1700000000,
+            "ParentPid": 1,
+            "Name": "firefox",
+            "Path": "/usr/lib/firefox/firefox",
+            "ExecName": "firefox",
+            "CmdLine": "/usr/lib/firefox/firefox --new-window",
+            "UserID": 1000,
+            "UserName": "user",
+            "LocalProfileKey": "local/firefox",
+            "Tags": null,
+            "Env": {"HOME": "/home/user"},
+        }))
+        .unwrap();
+
+        assert_eq!(process.pid, 42);
+        assert_eq!(process.name, "firefox");
+        assert_eq!(process.local_profile_key, "local/firefox");
+        assert!(process.tags.is_empty());
+        assert!(process.error.is_empty());
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(
+            connections_query(None).to_string(),
+            "query network:tree/ where Type exists"
+        );
+        assert_eq!(
+            processes_query(Some(field("Name").eq("firefox"))).to_string(),
+            "query network:tree/ where not Type exists and Name == firefox"
+        );
+    }
+
+    #[tokio::test]
+    async fn network_api() {
+        let server = crate::portapi::mock::MockServer::start().await;
+        let api = crate::portapi::client::connect(server.uri()).await.unwrap();
+
+        server.set("network:tree/42", &serde_json::json!({"Pid": 42, "Name": "firefox"}));
+        server.set(
+            "network:tree/42/global/1",
+            &serde_json::json!({"ID": "1", "Type": 1, "Verdict": {"Active": 2}}),
+        );
+
+        let mut conns = api
+            .subscribe_connections(Some(field("Verdict.Active").eq(3)))
+            .await
+            .unwrap();
+        assert!(matches!(conns.next().await, Some(RecordEvent::SnapshotDone)));
+
+        server.set(
+            "network:tree/42/global/2",
+            &serde_json::json!({"ID": "2", "Type": 1, "Verdict": {"Active": 3}}),
+        );
+
+        match conns.next().await {
+            Some(RecordEvent::Created(_, conn)) => {
+                assert_eq!(conn.id, "2");
+                assert!(conn.is_blocked());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        let mut processes = api.subscribe_processes(None).await.unwrap();
+        match processes.next().await {
+            Some(RecordEvent::Initial(record, process)) => {
+                assert_eq!(record.key.to_string(), "network:tree/42");
+                assert_eq!(process.pid, 42);
+                assert_eq!(process.name, "firefox");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(processes.next().await, Some(RecordEvent::SnapshotDone)));
+    }
+}
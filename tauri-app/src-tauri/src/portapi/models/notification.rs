@@ -1,6 +1,7 @@
 use serde::*;
+use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Notification {
     #[serde(rename = "EventID")]
     pub event_id: String,
@@ -26,7 +27,7 @@ pub struct Notification {
     pub expires: u64,
 
     #[serde(rename = "State")]
-    pub state: String,
+    pub state: State,
 
     #[serde(rename = "AvailableActions")]
     pub actions: Vec<Action>,
@@ -38,33 +39,331 @@ pub struct Notification {
     pub show_on_system: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(from = "RawAction", into = "RawAction")]
 pub struct Action {
-    #[serde(rename = "ID")]
     pub id: String,
 
-    #[serde(rename = "Text")]
     pub text: String,
 
-    #[serde(rename = "Type")]
-    pub action_type: String,
+    pub action_type: ActionType,
+}
+
+/// The type of a notification action together with its payload.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ActionType {
+    /// The action only reports the selected action ID back to the core.
+    None,
+
+    /// Opens the URL in the default browser.
+    OpenURL(String),
+
+    /// Opens a page of the user interface, e.g. `monitor`.
+    OpenPage(OpenPagePayload),
+
+    OpenSetting(OpenSettingPayload),
+
+    /// Opens the app profile with the given `<source>/<id>`.
+    OpenProfile(String),
+
+    /// Injects an event into the user interface.
+    InjectEvent(InjectEventPayload),
+
+    Webhook(WebhookPayload),
+
+    /// Opens the network monitor with the given search query.
+    Netquery(String),
+
+    /// An action type this version does not know about or whose payload could not
+    /// be decoded.
+    Unknown {
+        action_type: String,
+        payload: serde_json::Value,
+    },
+}
+
+impl ActionType {
+    /// Returns the action type name as used by portbase.
+    pub fn name(&self) -> &str {
+        match self {
+            ActionType::None => "",
+            ActionType::OpenURL(_) => "open-url",
+            ActionType::OpenPage(_) => "open-page",
+            ActionType::OpenSetting(_) => "open-setting",
+            ActionType::OpenProfile(_) => "open-profile",
+            ActionType::InjectEvent(_) => "inject-event",
+            ActionType::Webhook(_) => "call-webhook",
+            ActionType::Netquery(_) => "netquery",
+            ActionType::Unknown { action_type, .. } => action_type,
+        }
+    }
+}
+
+/// The page to open. portbase sends either the plain page ID or an object with
+/// the page ID and query parameters.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(from = "RawOpenPagePayload")]
+pub struct OpenPagePayload {
+    pub id: String,
+
+    pub query: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawOpenPagePayload {
+    ID(String),
+    Page {
+        id: String,
+        #[serde(default)]
+        query: HashMap<String, String>,
+    },
+}
+
+impl From<RawOpenPagePayload> for OpenPagePayload {
+    fn from(raw: RawOpenPagePayload) -> Self {
+        match raw {
+            RawOpenPagePayload::ID(id) => OpenPagePayload {
+                id,
+                query: HashMap::new(),
+            },
+            RawOpenPagePayload::Page { id, query } => OpenPagePayload { id, query },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct OpenSettingPayload {
+    #[serde(rename = "Key")]
+    pub key: String,
+
+    /// The profile to open the setting in, empty for the global settings.
+    #[serde(rename = "Profile", default)]
+    pub profile: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct InjectEventPayload {
+    #[serde(rename = "Event")]
+    pub event: String,
+
+    #[serde(rename = "Data", default)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WebhookPayload {
+    /// The HTTP method, defaults to PUT if there is a payload and POST otherwise.
+    #[serde(rename = "Method", default)]
+    pub method: String,
 
-    #[serde(rename = "Payload")]
+    /// The URL to call, relative to `/api/v1/` of the Portmaster API.
+    #[serde(rename = "URL")]
+    pub url: String,
+
+    #[serde(rename = "Payload", default)]
     pub payload: serde_json::Value,
+
+    /// Either `ignore` or `display`.
+    #[serde(rename = "ResultAction", default)]
+    pub result_action: String,
+}
+
+/// The wire representation of an `Action`.
+#[derive(Serialize, Deserialize)]
+struct RawAction {
+    #[serde(rename = "ID")]
+    id: String,
+
+    #[serde(rename = "Text")]
+    text: String,
+
+    #[serde(rename = "Type", default)]
+    action_type: String,
+
+    #[serde(rename = "Payload", default)]
+    payload: serde_json::Value,
+}
+
+impl From<RawAction> for Action {
+    fn from(raw: RawAction) -> Self {
+        fn decode<T: de::DeserializeOwned>(payload: &serde_json::Value) -> Option<T> {
+            serde_json::from_value(payload.clone()).ok()
+        }
+
+        let action_type = match raw.action_type.as_str() {
+            "" => Some(ActionType::None),
+            "open-url" => decode(&raw.payload).map(ActionType::OpenURL),
+            "open-page" => decode(&raw.payload).map(ActionType::OpenPage),
+            "open-setting" => decode(&raw.payload).map(ActionType::OpenSetting),
+            "open-profile" => decode(&raw.payload).map(ActionType::OpenProfile),
+            "inject-event" => decode(&raw.payload).map(ActionType::InjectEvent),
+            "call-webhook" => decode(&raw.payload).map(ActionType::Webhook),
+            "netquery" => decode(&raw.payload).map(ActionType::Netquery),
+            _ => None,
+        };
+
+        Action {
+            id: raw.id,
+            text: raw.text,
+            action_type: action_type.unwrap_or(ActionType::Unknown {
+                action_type: raw.action_type,
+                payload: raw.payload,
+            }),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub struct NotificationType(i32);
+impl From<Action> for RawAction {
+    fn from(action: Action) -> Self {
+        let action_type = action.action_type.name().to_string();
 
-#[allow(dead_code)]
-pub const INFO: NotificationType = NotificationType(0);
+        let payload = match action.action_type {
+            ActionType::None => serde_json::Value::Null,
+            ActionType::OpenURL(v) | ActionType::OpenProfile(v) | ActionType::Netquery(v) => {
+                serde_json::Value::String(v)
+            }
+            ActionType::OpenPage(v) => serde_json::to_value(v).unwrap_or_default(),
+            ActionType::OpenSetting(v) => serde_json::to_value(v).unwrap_or_default(),
+            ActionType::InjectEvent(v) => serde_json::to_value(v).unwrap_or_default(),
+            ActionType::Webhook(v) => serde_json::to_value(v).unwrap_or_default(),
+            ActionType::Unknown { payload, .. } => payload,
+        };
 
-#[allow(dead_code)]
-pub const WARN: NotificationType = NotificationType(1);
+        RawAction {
+            id: action.id,
+            text: action.text,
+            action_type,
+            payload,
+        }
+    }
+}
 
-#[allow(dead_code)]
-pub const PROMPT: NotificationType = NotificationType(2);
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(from = "u8", into = "u8")]
+pub enum NotificationType {
+    Info,
+    Warning,
+    Prompt,
+    Error,
+    Unknown(u8),
+}
 
-#[allow(dead_code)]
-pub const ERROR: NotificationType = NotificationType(3);
+impl From<u8> for NotificationType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => NotificationType::Info,
+            1 => NotificationType::Warning,
+            2 => NotificationType::Prompt,
+            3 => NotificationType::Error,
+            other => NotificationType::Unknown(other),
+        }
+    }
+}
 
+impl From<NotificationType> for u8 {
+    fn from(value: NotificationType) -> Self {
+        match value {
+            NotificationType::Info => 0,
+            NotificationType::Warning => 1,
+            NotificationType::Prompt => 2,
+            NotificationType::Error => 3,
+            NotificationType::Unknown(other) => other,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(from = "String", into = "String")]
+pub enum State {
+    Active,
+    Responded,
+    Executed,
+    Unknown(String),
+}
+
+impl From<String> for State {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "active" => State::Active,
+            "responded" => State::Responded,
+            "executed" => State::Executed,
+            _ => State::Unknown(value),
+        }
+    }
+}
+
+impl From<State> for String {
+    fn from(value: State) -> Self {
+        match value {
+            State::Active => "active".to_string(),
+            State::Responded => "responded".to_string(),
+            State::Executed => "executed".to_string(),
+            State::Unknown(other) => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_notification() {
+        let n: Notification = serde_json::from_value(json!({
+            "EventID": "updates:restart",
+            "GUID": "5c7e1ab2-8e1f-4f3c-9d7b-0b7f4f0b1a10",
+            "Type": 2,
+            "Message": "Restart to apply the update",
+            "Title": "Update available",
+            "Category": "Updates",
+            "EventData": null,
+            "Expires": 0,
+            "State": "active",
+            "AvailableActions": [
+                {"ID": "ack", "Text": "OK", "Type": ""},
+                {"ID": "docs", "Text": "Docs", "Type": "open-url", "Payload": "https://docs.safing.io"},
+                {"ID": "settings", "Text": "Settings", "Type": "open-setting", "Payload": {"Key": "core/automaticUpdates"}},
+                {"ID": "restart", "Text": "Restart", "Type": "call-webhook",
+                    "Payload": {"Method": "POST", "URL": "updates/apply", "ResultAction": "display"}},
+                {"ID": "broken", "Text": "Broken", "Type": "open-page", "Payload": 1},
+                {"ID": "monitor", "Text": "Monitor", "Type": "open-page", "Payload": {"id": "monitor", "query": {"q": "blocked"}}},
+                {"ID": "spn", "Text": "SPN", "Type": "open-page", "Payload": "spn"},
+                {"ID": "new", "Text": "New", "Type": "open-portal", "Payload": "x"},
+            ],
+            "SelectedActionID": "",
+            "ShowOnSystem": true,
+        }))
+        .unwrap();
+
+        assert_eq!(n.notification_type, NotificationType::Prompt);
+        assert_eq!(n.state, State::Active);
+
+        let types: Vec<&ActionType> = n.actions.iter().map(|a| &a.action_type).collect();
+        assert_eq!(types[0], &ActionType::None);
+        assert_eq!(types[1], &ActionType::OpenURL("https://docs.safing.io".to_string()));
+        assert!(matches!(types[2], ActionType::OpenSetting(p) if p.key == "core/automaticUpdates" && p.profile.is_empty()));
+        assert!(matches!(types[3], ActionType::Webhook(p) if p.url == "updates/apply" && p.result_action == "display"));
+        assert!(matches!(types[4], ActionType::Unknown { action_type, .. } if action_type == "open-page"));
+        assert!(matches!(types[5], ActionType::OpenPage(p) if p.id == "monitor" && p.query["q"] == "blocked"));
+        assert!(matches!(types[6], ActionType::OpenPage(p) if p.id == "spn" && p.query.is_empty()));
+        assert!(matches!(types[7], ActionType::Unknown { payload, .. } if payload == &json!("x")));
+
+        let encoded = serde_json::to_value(&n.actions[1]).unwrap();
+        assert_eq!(
+            encoded,
+            json!({"ID": "docs", "Text": "Docs", "Type": "open-url", "Payload": "https://docs.safing.io"})
+        );
+
+        let n: Notification = serde_json::from_value(json!({
+            "EventID": "x", "GUID": "", "Type": 7, "Message": "", "Title": "", "Category": "",
+            "EventData": null, "Expires": 0, "State": "dismissed", "AvailableActions": [],
+            "SelectedActionID": "", "ShowOnSystem": false,
+        }))
+        .unwrap();
+
+        assert_eq!(n.notification_type, NotificationType::Unknown(7));
+        assert_eq!(n.state, State::Unknown("dismissed".to_string()));
+    }
+}
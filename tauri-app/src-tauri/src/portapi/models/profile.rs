@@ -0,0 +1,636 @@
+use serde::*;
+use std::fmt;
+use std::str::FromStr;
+#[cfg(test)]
+use std::time::Duration;
+use thiserror::Error;
+
+#[cfg(test)]
+use super::super::client::{ClientError, PortAPI};
+#[cfg(test)]
+use super::super::key::DbKey;
+#[cfg(test)]
+use super::super::message::{Format, Payload};
+#[cfg(test)]
+use super::super::query::{field, Query};
+#[cfg(test)]
+use super::super::types::Request;
+use super::null_as_default;
+
+/// The key prefix of all app profiles in the `core:` database.
+#[cfg(test)]
+pub const PROFILES_PREFIX: &str = "core:profiles/";
+
+/// The configuration option keys used by the native profile helpers.
+#[cfg(test)]
+pub const ENDPOINTS_OPTION: &str = "filter/endpoints";
+#[cfg(test)]
+pub const DEFAULT_ACTION_OPTION: &str = "filter/defaultAction";
+
+/// An app profile as stored at `core:profiles/<source>/<id>`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Profile {
+    #[serde(rename = "ID")]
+    pub id: String,
+
+    #[serde(rename = "Source")]
+    pub source: String,
+
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    #[serde(rename = "Description", default)]
+    pub description: String,
+
+    #[serde(rename = "Homepage", default)]
+    pub homepage: String,
+
+    #[serde(rename = "Icons", default, deserialize_with = "null_as_default")]
+    pub icons: Vec<Icon>,
+
+    /// The path of the executable that is used to present the app in the UI.
+    #[serde(rename = "PresentationPath", default)]
+    pub presentation_path: String,
+
+    #[serde(rename = "UsePresentationPath", default)]
+    pub use_presentation_path: bool,
+
+    #[serde(rename = "Fingerprints", default, deserialize_with = "null_as_default")]
+    pub fingerprints: Vec<Fingerprint>,
+
+    /// The configuration overrides of the profile. Option keys are split at `/` into
+    /// nested objects, e.g. `filter/endpoints` is stored as `{"filter": {"endpoints": ...}}`.
+    #[serde(rename = "Config", default, deserialize_with = "null_as_default")]
+    pub config: serde_json::Map<String, serde_json::Value>,
+
+    #[serde(rename = "Internal", default)]
+    pub internal: bool,
+
+    #[serde(rename = "Created", default)]
+    pub created: i64,
+
+    #[serde(rename = "LastEdited", default)]
+    pub last_edited: i64,
+}
+
+#[cfg(test)]
+impl Profile {
+    /// Returns the database key of the profile.
+    pub fn key(&self) -> String {
+        profile_key(&self.source, &self.id)
+    }
+
+    /// Returns the value of the configuration option key if the profile overrides it.
+    pub fn config_value(&self, key: &str) -> Option<&serde_json::Value> {
+        let mut parts = key.split('/');
+        let mut value = self.config.get(parts.next()?)?;
+
+        for part in parts {
+            value = value.as_object()?.get(part)?;
+        }
+
+        Some(value)
+    }
+
+    /// Sets the value of the configuration option key. A null value removes the
+    /// override, including any objects that are left empty.
+    pub fn set_config_value(&mut self, key: &str, value: serde_json::Value) {
+        let parts: Vec<&str> = key.split('/').collect();
+
+        set_nested(&mut self.config, &parts, value);
+    }
+
+    /// Returns the endpoint rules configured in the profile. Entries that cannot be
+    /// parsed are skipped.
+    pub fn endpoint_rules(&self) -> Vec<EndpointRule> {
+        self.raw_endpoint_rules()
+            .iter()
+            .filter_map(parse_rule)
+            .collect()
+    }
+
+    /// Adds rule as the first endpoint rule so it takes precedence over all existing
+    /// rules. Existing rules for the same entity are removed, all other entries are
+    /// kept as they are.
+    pub fn add_endpoint_rule(&mut self, rule: &EndpointRule) {
+        let mut rules = self.raw_endpoint_rules();
+        rules.retain(|r| parse_rule(r).map_or(true, |r| r.entity != rule.entity));
+        rules.insert(0, serde_json::Value::String(rule.to_string()));
+
+        self.set_raw_endpoint_rules(rules);
+    }
+
+    /// Removes rule from the endpoint rules. Removing the last rule removes the
+    /// override.
+    pub fn remove_endpoint_rule(&mut self, rule: &EndpointRule) {
+        let mut rules = self.raw_endpoint_rules();
+        rules.retain(|r| parse_rule(r).as_ref() != Some(rule));
+
+        self.set_raw_endpoint_rules(rules);
+    }
+
+    fn raw_endpoint_rules(&self) -> Vec<serde_json::Value> {
+        self.config_value(ENDPOINTS_OPTION)
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_raw_endpoint_rules(&mut self, rules: Vec<serde_json::Value>) {
+        let value = if rules.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::Value::Array(rules)
+        };
+
+        self.set_config_value(ENDPOINTS_OPTION, value);
+    }
+
+    /// Returns the default action of the profile, None if the global setting is used.
+    pub fn default_action(&self) -> Option<DefaultAction> {
+        serde_json::from_value(self.config_value(DEFAULT_ACTION_OPTION)?.clone()).ok()
+    }
+
+    /// Reports whether the profile applies to the executable at path.
+    pub fn matches_path(&self, path: &str) -> bool {
+        let mut fingerprints = self
+            .fingerprints
+            .iter()
+            .filter(|fp| fp.fingerprint_type == "path")
+            .peekable();
+
+        // profiles created before fingerprints existed only have a presentation path.
+        if fingerprints.peek().is_none() {
+            return !self.presentation_path.is_empty() && self.presentation_path == path;
+        }
+
+        fingerprints.any(|fp| fp.matches(path))
+    }
+}
+
+#[cfg(test)]
+fn parse_rule(value: &serde_json::Value) -> Option<EndpointRule> {
+    value.as_str()?.parse().ok()
+}
+
+/// Sets the value at path in config and prunes objects that are left empty.
+#[cfg(test)]
+fn set_nested(
+    config: &mut serde_json::Map<String, serde_json::Value>,
+    path: &[&str],
+    value: serde_json::Value,
+) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    if rest.is_empty() {
+        if value.is_null() {
+            config.remove(*first);
+        } else {
+            config.insert(first.to_string(), value);
+        }
+
+        return;
+    }
+
+    if value.is_null() && !config.get(*first).is_some_and(|v| v.is_object()) {
+        return;
+    }
+
+    let entry = config
+        .entry(first.to_string())
+        .or_insert_with(|| serde_json::Value::Object(Default::default()));
+
+    if !entry.is_object() {
+        *entry = serde_json::Value::Object(Default::default());
+    }
+
+    let nested = entry.as_object_mut().unwrap();
+    set_nested(nested, rest, value);
+
+    if nested.is_empty() {
+        config.remove(*first);
+    }
+}
+
+/// Returns the database key of the profile with id from source.
+#[cfg(test)]
+pub fn profile_key(source: &str, id: &str) -> String {
+    format!("{}{}/{}", PROFILES_PREFIX, source, id)
+}
+
+/// Returns a query for the app profiles that may apply to the executable at path.
+///
+/// Portbase can only compare whole values, so profiles with a prefix or regex
+/// fingerprint are always included. Use `Profile::matches_path` to check the results.
+#[cfg(test)]
+pub fn profiles_by_path_query(path: &str) -> Query {
+    let value = serde_json::Value::from(path).to_string();
+
+    Query::new(PROFILES_PREFIX).filter(
+        field("PresentationPath")
+            .eq(path)
+            .or(field(format!("Fingerprints.#(Value=={})", value)).exists())
+            .or(field("Fingerprints.#(Operation!=\"equals\")").exists()),
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Icon {
+    /// Either `path`, `database` or `api`.
+    #[serde(rename = "Type")]
+    pub icon_type: String,
+
+    #[serde(rename = "Value")]
+    pub value: String,
+}
+
+/// A fingerprint identifies the processes a profile applies to.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Fingerprint {
+    /// Either `tag`, `env`, `path` or `cmdline`.
+    #[serde(rename = "Type")]
+    pub fingerprint_type: String,
+
+    #[serde(rename = "Key", default)]
+    pub key: String,
+
+    /// Either `equals`, `prefix` or `regex`.
+    #[serde(rename = "Operation")]
+    pub operation: String,
+
+    #[serde(rename = "Value")]
+    pub value: String,
+}
+
+#[cfg(test)]
+impl Fingerprint {
+    /// Reports whether value matches the fingerprint.
+    pub fn matches(&self, value: &str) -> bool {
+        match self.operation.as_str() {
+            "equals" => self.value == value,
+            "prefix" => value.starts_with(&self.value),
+            "regex" => regex::Regex::new(&self.value)
+                .map(|re| re.is_match(value))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+/// The action taken for connections that do not match any rule.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultAction {
+    Permit,
+    Block,
+    Ask,
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("invalid endpoint rule {0:?}, expected \"+ <entity>\" or \"- <entity>\"")]
+pub struct EndpointRuleError(String);
+
+/// An endpoint rule like `+ example.com` or `- *`. Rules are evaluated in order and
+/// the first matching rule decides whether a connection is permitted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EndpointRule {
+    pub permit: bool,
+
+    /// The entity the rule matches, e.g. a domain, IP, network, country or `*`.
+    pub entity: String,
+}
+
+impl EndpointRule {
+    pub fn permit(entity: impl Into<String>) -> Self {
+        EndpointRule {
+            permit: true,
+            entity: entity.into(),
+        }
+    }
+
+    pub fn block(entity: impl Into<String>) -> Self {
+        EndpointRule {
+            permit: false,
+            entity: entity.into(),
+        }
+    }
+}
+
+impl FromStr for EndpointRule {
+    type Err = EndpointRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || EndpointRuleError(s.to_string());
+
+        let (action, entity) = s.trim().split_once(' ').ok_or_else(err)?;
+        let entity = entity.trim();
+
+        if entity.is_empty() {
+            return Err(err());
+        }
+
+        match action {
+            "+" => Ok(EndpointRule::permit(entity)),
+            "-" => Ok(EndpointRule::block(entity)),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for EndpointRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.permit { '+' } else { '-' };
+
+        write!(f, "{} {}", action, self.entity)
+    }
+}
+
+/// ProfileApi adds helpers to look up app profiles and change their rules to `PortAPI`.
+#[cfg(test)]
+pub trait ProfileApi {
+    /// `get_profile` fetches the app profile with id from source.
+    async fn get_profile(
+        &self,
+        source: &str,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<Profile, ClientError>;
+
+    /// `get_profile_by_path` returns the app profile that applies to the executable at
+    /// path. Returns `ClientError::NotFound` if there is no such profile.
+    async fn get_profile_by_path(&self, path: &str, timeout: Duration) -> Result<Profile, ClientError>;
+
+    /// `add_endpoint_rule` adds rule as the first endpoint rule of profile so it takes
+    /// precedence over all existing rules. Existing rules for the same entity are removed,
+    /// other entries, including ones that cannot be parsed, are kept.
+    async fn add_endpoint_rule(
+        &self,
+        profile: &Profile,
+        rule: EndpointRule,
+        timeout: Duration,
+    ) -> Result<(), ClientError>;
+
+    /// `remove_endpoint_rule` removes rule from the endpoint rules of profile.
+    async fn remove_endpoint_rule(
+        &self,
+        profile: &Profile,
+        rule: &EndpointRule,
+        timeout: Duration,
+    ) -> Result<(), ClientError>;
+
+    /// `set_default_action` changes the default action of profile. Pass None to use
+    /// the global default action.
+    async fn set_default_action(
+        &self,
+        profile: &Profile,
+        action: Option<DefaultAction>,
+        timeout: Duration,
+    ) -> Result<(), ClientError>;
+}
+
+#[cfg(test)]
+impl ProfileApi for PortAPI {
+    async fn get_profile(
+        &self,
+        source: &str,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<Profile, ClientError> {
+        let (_, profile) = self
+            .get(&profile_key(source, id).parse()?, timeout)
+            .await?;
+
+        Ok(profile)
+    }
+
+    async fn get_profile_by_path(&self, path: &str, timeout: Duration) -> Result<Profile, ClientError> {
+        let profiles = self
+            .query_all::<Profile>(profiles_by_path_query(path), timeout)
+            .await?;
+
+        profiles
+            .into_iter()
+            .map(|(_, profile)| profile)
+            .find(|profile| profile.matches_path(path))
+            .ok_or(ClientError::NotFound)
+    }
+
+    async fn add_endpoint_rule(
+        &self,
+        profile: &Profile,
+        rule: EndpointRule,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        update_profile_config(self, profile, timeout, |profile| {
+            profile.add_endpoint_rule(&rule);
+        })
+        .await
+    }
+
+    async fn remove_endpoint_rule(
+        &self,
+        profile: &Profile,
+        rule: &EndpointRule,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        update_profile_config(self, profile, timeout, |profile| {
+            profile.remove_endpoint_rule(rule);
+        })
+        .await
+    }
+
+    async fn set_default_action(
+        &self,
+        profile: &Profile,
+        action: Option<DefaultAction>,
+        timeout: Duration,
+    ) -> Result<(), ClientError> {
+        update_profile_config(self, profile, timeout, |profile| {
+            profile.set_config_value(DEFAULT_ACTION_OPTION, serde_json::json!(action));
+        })
+        .await
+    }
+}
+
+/// Fetches the latest version of profile, applies f and saves the configuration of
+/// the profile. Only `Config` is sent so changes to other fields are not overwritten.
+#[cfg(test)]
+async fn update_profile_config<F: FnOnce(&mut Profile)>(
+    api: &PortAPI,
+    profile: &Profile,
+    timeout: Duration,
+    f: F,
+) -> Result<(), ClientError> {
+    let key: DbKey = profile.key().parse()?;
+    let (_, mut profile) = api.get::<Profile>(&key, timeout).await?;
+
+    f(&mut profile);
+
+    let payload = Payload::encode(&serde_json::json!({ "Config": profile.config }), Format::JSON)?;
+
+    api.expect_success(Request::Insert(key, payload), timeout)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn profile_config() {
+        let mut profile: Profile = serde_json::from_value(json!({
+            "ID": "abc",
+            "Source": "local",
+            "Name": "Firefox",
+            "Icons": null,
+            "PresentationPath": "/usr/lib/firefox/firefox",
+            "Fingerprints": [
+                {"Type": "path", "Operation": "prefix", "Value": "/usr/lib/firefox/"},
+                {"Type": "env", "Key": "SNAP", "Operation": "equals", "Value": "firefox"},
+            ],
+            "Config": {"filter": {"endpoints": ["+ example.com", "invalid", "- *"]}},
+        }))
+        .unwrap();
+
+        assert_eq!(profile.key(), "core:profiles/local/abc");
+        assert!(profile.matches_path("/usr/lib/firefox/firefox-bin"));
+        assert!(!profile.matches_path("/usr/bin/firefox"));
+
+        assert_eq!(
+            profile.endpoint_rules(),
+            vec![EndpointRule::permit("example.com"), EndpointRule::block("*")]
+        );
+        assert_eq!(profile.default_action(), None);
+
+        profile.set_config_value(DEFAULT_ACTION_OPTION, json!("block"));
+        assert_eq!(profile.default_action(), Some(DefaultAction::Block));
+        assert_eq!(
+            profile.config_value("filter/endpoints"),
+            Some(&json!(["+ example.com", "invalid", "- *"]))
+        );
+
+        profile.set_config_value(DEFAULT_ACTION_OPTION, serde_json::Value::Null);
+        assert_eq!(profile.default_action(), None);
+
+        profile.add_endpoint_rule(&EndpointRule::block("example.com"));
+        assert_eq!(
+            profile.config_value(ENDPOINTS_OPTION),
+            Some(&json!(["- example.com", "invalid", "- *"]))
+        );
+
+        profile.remove_endpoint_rule(&EndpointRule::block("*"));
+        profile.remove_endpoint_rule(&EndpointRule::block("example.com"));
+        assert_eq!(profile.config_value(ENDPOINTS_OPTION), Some(&json!(["invalid"])));
+
+        assert_eq!("- 10.0.0.0/8".parse(), Ok(EndpointRule::block("10.0.0.0/8")));
+        assert_eq!(EndpointRule::permit("*").to_string(), "+ *");
+        assert!("* example.com".parse::<EndpointRule>().is_err());
+        assert!("+".parse::<EndpointRule>().is_err());
+    }
+
+    #[test]
+    fn path_query() {
+        assert_eq!(
+            profiles_by_path_query("/usr/bin/firefox").to_string(),
+            r#"query core:profiles/ where PresentationPath == /usr/bin/firefox or "Fingerprints.#(Value==\"/usr/bin/firefox\")" exists or "Fingerprints.#(Operation!=\"equals\")" exists"#
+        );
+    }
+
+    #[tokio::test]
+    async fn profile_api() {
+        let server = crate::portapi::mock::MockServer::start().await;
+        let api = crate::portapi::client::connect(server.uri()).await.unwrap();
+        let timeout = Duration::from_secs(5);
+
+        server.set(
+            "core:profiles/local/firefox",
+            &serde_json::json!({
+                "ID": "firefox",
+                "Source": "local",
+                "Name": "Firefox",
+                "Fingerprints": [{"Type": "path", "Operation": "equals", "Value": "/usr/bin/firefox"}],
+                "Config": {"filter": {"endpoints": ["- example.com", "invalid"]}},
+            }),
+        );
+
+        let profile = api
+            .get_profile_by_path("/usr/bin/firefox", timeout)
+            .await
+            .unwrap();
+        assert_eq!(profile.name, "Firefox");
+        assert!(matches!(
+            api.get_profile_by_path("/usr/bin/curl", timeout).await,
+            Err(ClientError::NotFound)
+        ));
+
+        server.set(
+            "core:profiles/local/legacy",
+            &serde_json::json!({
+                "ID": "legacy",
+                "Source": "local",
+                "Name": "Legacy",
+                "PresentationPath": "/opt/legacy/app",
+            }),
+        );
+        server.set(
+            "core:profiles/local/tools",
+            &serde_json::json!({
+                "ID": "tools",
+                "Source": "local",
+                "Name": "Tools",
+                "Fingerprints": [{"Type": "path", "Operation": "prefix", "Value": "/opt/tools/"}],
+            }),
+        );
+
+        // only profiles that may match are transmitted.
+        let candidates: Vec<String> = api
+            .query_all::<Profile>(profiles_by_path_query("/usr/bin/firefox"), timeout)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, profile)| profile.id)
+            .collect();
+        assert_eq!(candidates, ["firefox", "tools"]);
+
+        let legacy = api.get_profile_by_path("/opt/legacy/app", timeout).await.unwrap();
+        assert_eq!(legacy.name, "Legacy");
+        let tools = api.get_profile_by_path("/opt/tools/bin/jq", timeout).await.unwrap();
+        assert_eq!(tools.name, "Tools");
+
+        api.add_endpoint_rule(&profile, EndpointRule::permit("example.com"), timeout)
+            .await
+            .unwrap();
+        api.add_endpoint_rule(&profile, EndpointRule::block("*"), timeout)
+            .await
+            .unwrap();
+        api.set_default_action(&profile, Some(DefaultAction::Block), timeout)
+            .await
+            .unwrap();
+
+        let profile = api.get_profile("local", "firefox", timeout).await.unwrap();
+        assert_eq!(
+            profile.endpoint_rules(),
+            vec![EndpointRule::block("*"), EndpointRule::permit("example.com")]
+        );
+        assert_eq!(profile.default_action(), Some(DefaultAction::Block));
+
+        api.remove_endpoint_rule(&profile, &EndpointRule::block("*"), timeout)
+            .await
+            .unwrap();
+        api.remove_endpoint_rule(&profile, &EndpointRule::permit("example.com"), timeout)
+            .await
+            .unwrap();
+        api.set_default_action(&profile, None, timeout).await.unwrap();
+
+        let profile = api.get_profile("local", "firefox", timeout).await.unwrap();
+        assert_eq!(
+            profile.config_value("filter/endpoints"),
+            Some(&serde_json::json!(["invalid"]))
+        );
+        assert_eq!(profile.default_action(), None);
+    }
+}
@@ -1,8 +1,123 @@
+use chrono::{DateTime, FixedOffset, Local, TimeZone};
 use serde::*;
+use std::fmt::Display;
 
-
+/// The SPN runtime status published at `runtime:spn/status`.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct SPNStatus {
+    /// One of `failed`, `disabled`, `connecting` or `connected`.
     #[serde(rename = "Status")]
     pub status: String,
-}
\ No newline at end of file
+
+    #[serde(rename = "HomeHubID", default)]
+    pub home_hub_id: String,
+
+    #[serde(rename = "HomeHubName", default)]
+    pub home_hub_name: String,
+
+    #[serde(rename = "ConnectedIP", default)]
+    pub connected_ip: String,
+
+    #[serde(rename = "ConnectedTransport", default)]
+    pub connected_transport: String,
+
+    #[serde(rename = "ConnectedCountry", default)]
+    pub connected_country: Option<CountryInfo>,
+
+    /// The autonomous system number of the home hub IP.
+    #[serde(rename = "ConnectedASN", default)]
+    pub connected_asn: Option<u32>,
+
+    /// The organization operating the autonomous system of the home hub IP.
+    #[serde(rename = "ConnectedASOrg", default)]
+    pub connected_as_org: String,
+
+    #[serde(rename = "ConnectedSince", default)]
+    pub connected_since: Option<DateTime<FixedOffset>>,
+}
+
+/// The country of the home hub.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CountryInfo {
+    #[serde(rename = "Code")]
+    pub code: String,
+
+    #[serde(rename = "Name", default)]
+    pub name: String,
+}
+
+impl SPNStatus {
+    /// Returns a short, human readable description of the status for the tray menu,
+    /// e.g. "SPN: connected via DE hub since 10:42".
+    pub fn describe(&self) -> String {
+        self.describe_in(&Local)
+    }
+
+    /// Like `describe` but formats the connection time in the timezone tz.
+    pub fn describe_in<Tz>(&self, tz: &Tz) -> String
+    where
+        Tz: TimeZone,
+        Tz::Offset: Display,
+    {
+        match self.status.as_str() {
+            "connected" => {
+                let mut line = "SPN: connected".to_string();
+
+                let hub = match &self.connected_country {
+                    Some(country) if !country.code.is_empty() => format!("{} hub", country.code),
+                    _ => self.home_hub_name.clone(),
+                };
+
+                if !hub.is_empty() {
+                    line.push_str(&format!(" via {}", hub));
+                }
+
+                if let Some(since) = self.connected_since {
+                    line.push_str(&format!(" since {}", since.with_timezone(tz).format("%H:%M")));
+                }
+
+                line
+            }
+            "" => "SPN: unknown".to_string(),
+            status => format!("SPN: {}", status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_status() {
+        let payload = r#"{"_meta":{"Created":1700470933,"Modified":1700471004,"Expires":0,"Deleted":0},
+            "Status":"connected","HomeHubID":"Zwu5LkWMFRNvURHHQALczfbhFpaHeiTcyFWDZcUPJjPi5y",
+            "HomeHubName":"pandora","ConnectedIP":"203.0.113.7","ConnectedTransport":"tcp:17",
+            "ConnectedCountry":{"Code":"DE","Name":"Germany","Center":{"Latitude":51.5,"Longitude":10.5,"AccuracyRadius":1000},
+            "Continent":{"Code":"EU","Region":"EU-C","Name":"Europe"}},
+            "ConnectedASN":24940,"ConnectedASOrg":"Hetzner Online GmbH",
+            "ConnectedSince":"2023-11-20T10:42:13.492231826+01:00"}"#;
+
+        let status: SPNStatus = serde_json::from_str(payload).unwrap();
+        assert_eq!(status.home_hub_name, "pandora");
+        assert_eq!(status.connected_ip, "203.0.113.7");
+        assert_eq!(status.connected_country.as_ref().unwrap().name, "Germany");
+        assert_eq!(status.connected_asn, Some(24940));
+        assert_eq!(status.connected_as_org, "Hetzner Online GmbH");
+
+        let tz = FixedOffset::east_opt(3600).unwrap();
+        assert_eq!(status.describe_in(&tz), "SPN: connected via DE hub since 10:42");
+
+        let payload = r#"{"Status":"failed","HomeHubID":"","HomeHubName":"","ConnectedIP":"",
+            "ConnectedTransport":"","ConnectedCountry":null,"ConnectedSince":null}"#;
+
+        let status: SPNStatus = serde_json::from_str(payload).unwrap();
+        assert_eq!(status.connected_since, None);
+        assert_eq!(status.connected_asn, None);
+        assert_eq!(status.connected_as_org, "");
+        assert_eq!(status.describe_in(&tz), "SPN: failed");
+
+        let status: SPNStatus = serde_json::from_str(r#"{"Status":"connecting"}"#).unwrap();
+        assert_eq!(status.describe_in(&tz), "SPN: connecting");
+    }
+}
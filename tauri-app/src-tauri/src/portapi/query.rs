@@ -0,0 +1,955 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::FromStr;
+use std::vec::IntoIter;
+use thiserror::Error;
+
+use super::key::DbKey;
+
+/// QueryError is returned when parsing a query string fails.
+#[derive(Debug, Error, PartialEq)]
+pub enum QueryError {
+    #[error("query must start with \"query\"")]
+    MissingQueryKeyword,
+
+    #[error("missing key prefix")]
+    MissingPrefix,
+
+    #[error("unexpected end of query")]
+    UnexpectedEnd,
+
+    #[error("unexpected token: {0}")]
+    UnexpectedToken(String),
+
+    #[error("unknown operator: {0}")]
+    UnknownOperator(String),
+
+    #[error("unknown clause: {0}")]
+    UnknownClause(String),
+
+    #[error("duplicate {0} clause")]
+    DuplicateClause(String),
+
+    #[error("invalid number: {0}")]
+    InvalidNumber(String),
+
+    #[error("\"and\" and \"or\" must not be mixed without parentheses")]
+    MixedAndOr,
+
+    #[error("unterminated quote")]
+    UnterminatedQuote,
+
+    #[error("missing closing parenthesis")]
+    MissingParenthesis,
+
+    #[error("invalid key prefix: {0}")]
+    InvalidPrefix(String),
+
+    #[error("invalid character {0:?} in query")]
+    InvalidCharacter(char),
+}
+
+/// Operator is a comparison operator supported in `where` conditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Equals,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    FloatEquals,
+    FloatGreaterThan,
+    FloatGreaterThanOrEqual,
+    FloatLessThan,
+    FloatLessThanOrEqual,
+    /// Compares strings case-insensitively.
+    SameAs,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Matches,
+    /// Compares booleans, e.g. `Internal is true`.
+    Is,
+}
+
+impl Operator {
+    /// Returns the name of the operator as used in query strings.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operator::Equals => "==",
+            Operator::GreaterThan => ">",
+            Operator::GreaterThanOrEqual => ">=",
+            Operator::LessThan => "<",
+            Operator::LessThanOrEqual => "<=",
+            Operator::FloatEquals => "f==",
+            Operator::FloatGreaterThan => "f>",
+            Operator::FloatGreaterThanOrEqual => "f>=",
+            Operator::FloatLessThan => "f<",
+            Operator::FloatLessThanOrEqual => "f<=",
+            Operator::SameAs => "sameas",
+            Operator::Contains => "contains",
+            Operator::StartsWith => "startswith",
+            Operator::EndsWith => "endswith",
+            Operator::Matches => "matches",
+            Operator::Is => "is",
+        }
+    }
+
+    /// Returns the operator for name, including the short aliases supported by portbase.
+    pub fn from_name(name: &str) -> Option<Operator> {
+        match name {
+            "==" => Some(Operator::Equals),
+            ">" => Some(Operator::GreaterThan),
+            ">=" => Some(Operator::GreaterThanOrEqual),
+            "<" => Some(Operator::LessThan),
+            "<=" => Some(Operator::LessThanOrEqual),
+            "f==" => Some(Operator::FloatEquals),
+            "f>" => Some(Operator::FloatGreaterThan),
+            "f>=" => Some(Operator::FloatGreaterThanOrEqual),
+            "f<" => Some(Operator::FloatLessThan),
+            "f<=" => Some(Operator::FloatLessThanOrEqual),
+            "sameas" | "s==" => Some(Operator::SameAs),
+            "contains" | "co" => Some(Operator::Contains),
+            "startswith" | "sw" => Some(Operator::StartsWith),
+            "endswith" | "ew" => Some(Operator::EndsWith),
+            "matches" | "re" => Some(Operator::Matches),
+            "is" => Some(Operator::Is),
+            _ => None,
+        }
+    }
+}
+
+/// Condition is a node of the `where` clause of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// Compares the value of a field using an operator.
+    Compare {
+        key: String,
+        op: Operator,
+        value: String,
+    },
+
+    /// Matches if the value of a field is one of values. Values must not contain commas
+    /// as they are transmitted as a single, comma separated list.
+    In { key: String, values: Vec<String> },
+
+    /// Matches if the field exists.
+    Exists(String),
+
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    /// Combines the condition with other using `and`.
+    pub fn and(self, other: Condition) -> Condition {
+        match self {
+            Condition::And(mut conditions) => {
+                conditions.push(other);
+                Condition::And(conditions)
+            }
+            cond => Condition::And(vec![cond, other]),
+        }
+    }
+
+    /// Combines the condition with other using `or`.
+    pub fn or(self, other: Condition) -> Condition {
+        match self {
+            Condition::Or(mut conditions) => {
+                conditions.push(other);
+                Condition::Or(conditions)
+            }
+            cond => Condition::Or(vec![cond, other]),
+        }
+    }
+
+    fn is_compound(&self) -> bool {
+        matches!(self, Condition::And(_) | Condition::Or(_))
+    }
+
+    /// Writes the condition and wraps compound conditions in parentheses.
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_compound() {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl std::ops::Not for Condition {
+    type Output = Condition;
+
+    fn not(self) -> Condition {
+        Condition::Not(Box::new(self))
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Compare { key, op, value } => {
+                write!(f, "{} {} {}", escape(key), op.name(), escape(value))
+            }
+            Condition::In { key, values } => {
+                write!(f, "{} in {}", escape(key), escape(&values.join(",")))
+            }
+            Condition::Exists(key) => write!(f, "{} exists", escape(key)),
+            Condition::And(conditions) | Condition::Or(conditions) => {
+                let sep = if matches!(self, Condition::And(_)) {
+                    " and "
+                } else {
+                    " or "
+                };
+
+                for (idx, cond) in conditions.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(sep)?;
+                    }
+
+                    cond.fmt_nested(f)?;
+                }
+
+                Ok(())
+            }
+            Condition::Not(cond) => {
+                f.write_str("not ")?;
+                cond.fmt_nested(f)
+            }
+        }
+    }
+}
+
+/// Field starts a condition on the field key of a record. Use `field` to create one.
+#[cfg(test)]
+pub struct Field(String);
+
+/// Returns a `Field` to build a condition for key.
+///
+/// ```ignore
+/// let cond = field("Name").eq("foo").and(field("FailureStatus").gt(2));
+/// ```
+#[cfg(test)]
+pub fn field(key: impl Into<String>) -> Field {
+    Field(key.into())
+}
+
+#[cfg(test)]
+impl Field {
+    fn compare(self, op: Operator, value: impl ToString) -> Condition {
+        Condition::Compare {
+            key: self.0,
+            op,
+            value: value.to_string(),
+        }
+    }
+
+    pub fn eq(self, value: impl ToString) -> Condition {
+        self.compare(Operator::Equals, value)
+    }
+
+    pub fn gt(self, value: impl ToString) -> Condition {
+        self.compare(Operator::GreaterThan, value)
+    }
+
+    pub fn ge(self, value: impl ToString) -> Condition {
+        self.compare(Operator::GreaterThanOrEqual, value)
+    }
+
+    pub fn lt(self, value: impl ToString) -> Condition {
+        self.compare(Operator::LessThan, value)
+    }
+
+    pub fn le(self, value: impl ToString) -> Condition {
+        self.compare(Operator::LessThanOrEqual, value)
+    }
+
+    /// Compares the field case-insensitively.
+    pub fn same_as(self, value: impl ToString) -> Condition {
+        self.compare(Operator::SameAs, value)
+    }
+
+    pub fn is(self, value: bool) -> Condition {
+        self.compare(Operator::Is, value)
+    }
+
+    pub fn contains(self, value: impl ToString) -> Condition {
+        self.compare(Operator::Contains, value)
+    }
+
+    pub fn starts_with(self, value: impl ToString) -> Condition {
+        self.compare(Operator::StartsWith, value)
+    }
+
+    pub fn ends_with(self, value: impl ToString) -> Condition {
+        self.compare(Operator::EndsWith, value)
+    }
+
+    /// Matches the field against a regular expression.
+    pub fn matches(self, regex: impl ToString) -> Condition {
+        self.compare(Operator::Matches, regex)
+    }
+
+    /// Matches if the field equals one of values (`in`).
+    pub fn one_of<I, V>(self, values: I) -> Condition
+    where
+        I: IntoIterator<Item = V>,
+        V: ToString,
+    {
+        Condition::In {
+            key: self.0,
+            values: values.into_iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    pub fn exists(self) -> Condition {
+        Condition::Exists(self.0)
+    }
+}
+
+/// Query is a portbase database query as used by the `query` and `qsub` commands.
+///
+/// Queries can be built using the builder methods:
+///
+/// ```ignore
+/// let query = Query::new("runtime:subsystems/")
+///     .filter(field("FailureStatus").gt(0))
+///     .order_by("Name")
+///     .limit(10);
+///
+/// assert_eq!(query.to_string(), "query runtime:subsystems/ where FailureStatus > 0 orderby Name limit 10");
+/// ```
+///
+/// or parsed from a query string using `str::parse`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    /// The key prefix (including the database name) records must match.
+    pub prefix: String,
+    pub condition: Option<Condition>,
+    pub order_by: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl Query {
+    /// Creates a new query for all records with the key prefix.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Query {
+            prefix: prefix.into(),
+            condition: None,
+            order_by: None,
+            limit: None,
+            offset: None,
+        }
+    }
+
+    /// Sets the `where` clause of the query.
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn order_by(mut self, field: impl Into<String>) -> Self {
+        self.order_by = Some(field.into());
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Checks that the query can be sent to portbase. The prefix must be a valid
+    /// `DbKey` and the rendered query must not contain the PortAPI separator `|`
+    /// or control characters, which cannot be escaped.
+    pub fn validate(&self) -> Result<(), QueryError> {
+        if self.prefix.parse::<DbKey>().is_err() {
+            return Err(QueryError::InvalidPrefix(self.prefix.clone()));
+        }
+
+        match self
+            .to_string()
+            .chars()
+            .find(|&c| c == '|' || c.is_control())
+        {
+            Some(c) => Err(QueryError::InvalidCharacter(c)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "query {}", escape(&self.prefix))?;
+
+        if let Some(condition) = &self.condition {
+            write!(f, " where {}", condition)?;
+        }
+
+        if let Some(order_by) = &self.order_by {
+            write!(f, " orderby {}", escape(order_by))?;
+        }
+
+        if let Some(limit) = self.limit {
+            write!(f, " limit {}", limit)?;
+        }
+
+        if let Some(offset) = self.offset {
+            write!(f, " offset {}", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = Parser {
+            tokens: tokenize(s)?.into_iter().peekable(),
+        }
+        .parse_query()?;
+
+        query.validate()?;
+
+        Ok(query)
+    }
+}
+
+/// Words that have a special meaning and must be quoted if used as a key or value.
+const KEYWORDS: &[&str] = &[
+    "query", "where", "orderby", "limit", "offset", "and", "or", "not",
+];
+
+/// Quotes s if it cannot be represented as a single, plain word.
+fn escape(s: &str) -> String {
+    let plain = !s.is_empty()
+        && !KEYWORDS.contains(&s)
+        && !s
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '(' | ')' | '"' | '\\'));
+
+    if plain {
+        return s.to_string();
+    }
+
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word { text: String, quoted: bool },
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word { text, quoted: false } if text == keyword)
+    }
+}
+
+/// Splits a query into words, quoted strings and parentheses. A backslash escapes
+/// the next character.
+fn tokenize(s: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.push(chars.next().ok_or(QueryError::UnterminatedQuote)?),
+                        Some(c) => text.push(c),
+                        None => return Err(QueryError::UnterminatedQuote),
+                    }
+                }
+
+                tokens.push(Token::Word { text, quoted: true });
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"') {
+                        break;
+                    }
+
+                    chars.next();
+                    if c == '\\' {
+                        text.push(chars.next().ok_or(QueryError::UnexpectedEnd)?);
+                    } else {
+                        text.push(c);
+                    }
+                }
+
+                tokens.push(Token::Word {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+}
+
+impl Parser {
+    fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.tokens.next()
+    }
+
+    /// Returns the text of the next word.
+    fn word(&mut self) -> Result<String, QueryError> {
+        match self.next() {
+            Some(Token::Word { text, .. }) => Ok(text),
+            Some(Token::Open) => Err(QueryError::UnexpectedToken("(".to_string())),
+            Some(Token::Close) => Err(QueryError::UnexpectedToken(")".to_string())),
+            None => Err(QueryError::UnexpectedEnd),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, QueryError> {
+        let word = self.word()?;
+
+        word.parse().map_err(|_| QueryError::InvalidNumber(word))
+    }
+
+    fn parse_query(mut self) -> Result<Query, QueryError> {
+        if !self.peek().is_some_and(|t| t.is_keyword("query")) {
+            return Err(QueryError::MissingQueryKeyword);
+        }
+        self.next();
+
+        let prefix = match self.next() {
+            Some(Token::Word { text, .. }) => text,
+            _ => return Err(QueryError::MissingPrefix),
+        };
+
+        let mut query = Query::new(prefix);
+
+        while let Some(token) = self.next() {
+            let clause = match token {
+                Token::Word {
+                    text,
+                    quoted: false,
+                } => text,
+                Token::Word { text, .. } => return Err(QueryError::UnknownClause(text)),
+                Token::Open => return Err(QueryError::UnexpectedToken("(".to_string())),
+                Token::Close => return Err(QueryError::UnexpectedToken(")".to_string())),
+            };
+
+            let duplicate = match clause.as_str() {
+                "where" => query.condition.replace(self.parse_expr()?).is_some(),
+                "orderby" => query.order_by.replace(self.word()?).is_some(),
+                "limit" => query.limit.replace(self.number()?).is_some(),
+                "offset" => query.offset.replace(self.number()?).is_some(),
+                _ => return Err(QueryError::UnknownClause(clause)),
+            };
+
+            if duplicate {
+                return Err(QueryError::DuplicateClause(clause));
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// Parses a list of conditions joined by either `and` or `or`.
+    fn parse_expr(&mut self) -> Result<Condition, QueryError> {
+        let mut conditions = vec![self.parse_unary()?];
+        let mut and: Option<bool> = None;
+
+        loop {
+            let is_and = match self.peek() {
+                Some(t) if t.is_keyword("and") => true,
+                Some(t) if t.is_keyword("or") => false,
+                _ => break,
+            };
+
+            if and.is_some_and(|and| and != is_and) {
+                return Err(QueryError::MixedAndOr);
+            }
+
+            and = Some(is_and);
+            self.next();
+
+            conditions.push(self.parse_unary()?);
+        }
+
+        Ok(match and {
+            None => conditions.remove(0),
+            Some(true) => Condition::And(conditions),
+            Some(false) => Condition::Or(conditions),
+        })
+    }
+
+    /// Parses a single condition, a negated condition or a parenthesized expression.
+    fn parse_unary(&mut self) -> Result<Condition, QueryError> {
+        match self.peek() {
+            Some(Token::Open) => {
+                self.next();
+
+                let cond = self.parse_expr()?;
+
+                match self.next() {
+                    Some(Token::Close) => Ok(cond),
+                    _ => Err(QueryError::MissingParenthesis),
+                }
+            }
+            Some(t) if t.is_keyword("not") => {
+                self.next();
+
+                Ok(!self.parse_unary()?)
+            }
+            _ => {
+                let key = self.word()?;
+                let op = self.word()?;
+
+                match op.as_str() {
+                    "exists" | "ex" => Ok(Condition::Exists(key)),
+                    "in" => {
+                        let values = self.word()?;
+                        let values = if values.is_empty() {
+                            Vec::new()
+                        } else {
+                            values.split(',').map(|v| v.to_string()).collect()
+                        };
+
+                        Ok(Condition::In { key, values })
+                    }
+                    name => {
+                        let op = Operator::from_name(name)
+                            .ok_or_else(|| QueryError::UnknownOperator(op.clone()))?;
+
+                        Ok(Condition::Compare {
+                            key,
+                            op,
+                            value: self.word()?,
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_query() {
+        let query = Query::new("runtime:subsystems/")
+            .filter(
+                field("FailureStatus")
+                    .gt(0)
+                    .and(!field("Name").matches("^spn"))
+                    .and(field("ID").one_of(["core", "filter"]).or(field("Modules").exists())),
+            )
+            .order_by("Name")
+            .limit(10)
+            .offset(5);
+
+        assert_eq!(
+            query.to_string(),
+            "query runtime:subsystems/ where FailureStatus > 0 and not Name matches ^spn and (ID in core,filter or Modules exists) orderby Name limit 10 offset 5"
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let query = Query::new("core:my prefix").filter(
+            field("Name")
+                .eq("a \"quoted\" (value)")
+                .and(field("or").contains(""))
+                .and(field("Path").starts_with("C:\\Windows")),
+        );
+
+        assert_eq!(
+            query.to_string(),
+            r#"query "core:my prefix" where Name == "a \"quoted\" (value)" and "or" contains "" and Path startswith "C:\\Windows""#
+        );
+        assert_eq!(query.to_string().parse::<Query>(), Ok(query));
+    }
+
+    #[test]
+    fn parse_query() {
+        let query: Query = "query  notifications:   where (Type == 2 or Type co 3)and not State ex orderby Created limit 5"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            Query::new("notifications:")
+                .filter(
+                    field("Type")
+                        .eq(2)
+                        .or(field("Type").contains(3))
+                        .and(!field("State").exists())
+                )
+                .order_by("Created")
+                .limit(5)
+        );
+
+        assert_eq!("query config:".parse(), Ok(Query::new("config:")));
+
+        let query: Query = "query network: where A >= 1 and B < 2 and C <= 3 and D endswith x"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            Query::new("network:").filter(
+                field("A")
+                    .ge(1)
+                    .and(field("B").lt(2))
+                    .and(field("C").le(3))
+                    .and(field("D").ends_with("x"))
+            )
+        );
+    }
+
+    #[test]
+    fn operators() {
+        let cases = [
+            ("f==", Operator::FloatEquals, "f=="),
+            ("f>", Operator::FloatGreaterThan, "f>"),
+            ("f>=", Operator::FloatGreaterThanOrEqual, "f>="),
+            ("f<", Operator::FloatLessThan, "f<"),
+            ("f<=", Operator::FloatLessThanOrEqual, "f<="),
+            ("sameas", Operator::SameAs, "sameas"),
+            ("s==", Operator::SameAs, "sameas"),
+            ("is", Operator::Is, "is"),
+        ];
+
+        for (name, op, canonical) in cases {
+            let query: Query = format!("query network: where Value {} 1.5", name)
+                .parse()
+                .unwrap();
+
+            assert_eq!(
+                query.condition,
+                Some(Condition::Compare {
+                    key: "Value".to_string(),
+                    op,
+                    value: "1.5".to_string(),
+                }),
+                "{}",
+                name
+            );
+            assert_eq!(
+                query.to_string(),
+                format!("query network: where Value {} 1.5", canonical)
+            );
+        }
+
+        assert_eq!(
+            field("Internal").is(true).and(field("Scope").same_as("Global")).to_string(),
+            "Internal is true and Scope sameas Global"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("", QueryError::MissingQueryKeyword),
+            ("qsub config:", QueryError::MissingQueryKeyword),
+            ("query", QueryError::MissingPrefix),
+            ("query config: where", QueryError::UnexpectedEnd),
+            ("query config: where a ==", QueryError::UnexpectedEnd),
+            ("query config: where a ~ b", QueryError::UnknownOperator("~".to_string())),
+            ("query config: where a == b and c == d or e == f", QueryError::MixedAndOr),
+            ("query config: where (a == b", QueryError::MissingParenthesis),
+            ("query config: where a == b)", QueryError::UnexpectedToken(")".to_string())),
+            ("query config: where a == \"b", QueryError::UnterminatedQuote),
+            ("query config: limit x", QueryError::InvalidNumber("x".to_string())),
+            ("query config: limit 1 limit 2", QueryError::DuplicateClause("limit".to_string())),
+            ("query config: sortby Name", QueryError::UnknownClause("sortby".to_string())),
+            ("query config", QueryError::InvalidPrefix("config".to_string())),
+            ("query :spn/", QueryError::InvalidPrefix(":spn/".to_string())),
+            ("query config: where a == b|c", QueryError::InvalidCharacter('|')),
+        ];
+
+        for (query, err) in cases {
+            assert_eq!(query.parse::<Query>(), Err(err), "{}", query);
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(Query::new("runtime:subsystems/").validate(), Ok(()));
+        assert_eq!(Query::new("config:").validate(), Ok(()));
+
+        for prefix in ["", "config", ":spn/", "con fig:", "config:spn|enable", "config:\n"] {
+            assert_eq!(
+                Query::new(prefix).validate(),
+                Err(QueryError::InvalidPrefix(prefix.to_string())),
+                "{:?}",
+                prefix
+            );
+        }
+
+        let cases = [
+            (field("Name").eq("a|b"), '|'),
+            (field("Na|me").exists(), '|'),
+            (field("Name").one_of(["a", "b|c"]), '|'),
+            (field("Name").contains("a\nb"), '\n'),
+            (!field("Name").eq("\u{7f}"), '\u{7f}'),
+        ];
+
+        for (cond, c) in cases {
+            let query = Query::new("core:").filter(cond);
+
+            assert_eq!(query.validate(), Err(QueryError::InvalidCharacter(c)), "{}", query);
+        }
+
+        assert_eq!(
+            Query::new("core:").order_by("a|b").validate(),
+            Err(QueryError::InvalidCharacter('|'))
+        );
+    }
+
+    mod proptests {
+        use super::super::*;
+        use proptest::prelude::*;
+
+        fn operator() -> impl Strategy<Value = Operator> {
+            prop_oneof![
+                Just(Operator::Equals),
+                Just(Operator::GreaterThan),
+                Just(Operator::GreaterThanOrEqual),
+                Just(Operator::LessThan),
+                Just(Operator::LessThanOrEqual),
+                Just(Operator::FloatEquals),
+                Just(Operator::FloatGreaterThan),
+                Just(Operator::FloatGreaterThanOrEqual),
+                Just(Operator::FloatLessThan),
+                Just(Operator::FloatLessThanOrEqual),
+                Just(Operator::SameAs),
+                Just(Operator::Contains),
+                Just(Operator::StartsWith),
+                Just(Operator::EndsWith),
+                Just(Operator::Matches),
+                Just(Operator::Is),
+            ]
+        }
+
+        /// Text that can be sent to portbase, i.e. without `|` and control characters.
+        fn wire_safe() -> BoxedStrategy<String> {
+            "[^|\\p{Cc}]*".boxed()
+        }
+
+        fn condition(text: BoxedStrategy<String>) -> impl Strategy<Value = Condition> {
+            let value = text.clone().prop_filter("must not be empty or contain commas", |v| {
+                !v.is_empty() && !v.contains(',')
+            });
+
+            let leaf = prop_oneof![
+                (text.clone(), operator(), text.clone())
+                    .prop_map(|(key, op, value)| Condition::Compare { key, op, value }),
+                (text.clone(), prop::collection::vec(value, 1..4))
+                    .prop_map(|(key, values)| Condition::In { key, values }),
+                text.prop_map(Condition::Exists),
+            ];
+
+            leaf.prop_recursive(4, 32, 4, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 2..4).prop_map(Condition::And),
+                    prop::collection::vec(inner.clone(), 2..4).prop_map(Condition::Or),
+                    inner.prop_map(|c| !c),
+                ]
+            })
+        }
+
+        fn query(
+            prefix: BoxedStrategy<String>,
+            text: BoxedStrategy<String>,
+        ) -> impl Strategy<Value = Query> {
+            (
+                prefix,
+                prop::option::of(condition(text.clone())),
+                prop::option::of(text),
+                prop::option::of(any::<usize>()),
+                prop::option::of(any::<usize>()),
+            )
+                .prop_map(|(prefix, condition, order_by, limit, offset)| Query {
+                    prefix,
+                    condition,
+                    order_by,
+                    limit,
+                    offset,
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn query_round_trip(
+                query in query("[a-z0-9_-]{1,10}:[^|\\p{Cc}]*".boxed(), wire_safe()),
+            ) {
+                prop_assert_eq!(query.validate(), Ok(()));
+
+                let parsed = query.to_string().parse::<Query>();
+
+                prop_assert_eq!(parsed, Ok(query));
+            }
+
+            #[test]
+            fn valid_queries_are_wire_safe(
+                query in query("[a-z]{1,5}:.*".boxed(), any::<String>().boxed()),
+            ) {
+                if query.validate().is_ok() {
+                    let rendered = query.to_string();
+
+                    prop_assert!(!rendered.chars().any(|c| c == '|' || c.is_control()));
+                    prop_assert_eq!(rendered.parse::<Query>(), Ok(query));
+                }
+            }
+
+            #[test]
+            fn parse_never_panics(s in any::<String>()) {
+                let _ = s.parse::<Query>();
+            }
+        }
+    }
+}
@@ -0,0 +1,570 @@
+//! Recording and replay of PortAPI sessions.
+//!
+//! A `Recorder` passed to `connect_resilient` writes every frame sent and received,
+//! as well as connection changes, as one JSON object per line:
+//!
+//! ```text
+//! {"time":1700000000000,"type":"connected"}
+//! {"time":1700000000001,"type":"sent","id":0,"frame":{"text":"0|qsub|query runtime:"}}
+//! {"time":1700000000002,"type":"received","id":0,"frame":{"text":"0|ok|runtime:foo|J{}"}}
+//! {"time":1700000000003,"type":"disconnected","reason":"websocket connection lost"}
+//! ```
+//!
+//! `ReplayServer` serves such a recording from a local websocket server so the
+//! behavior of the tray and notification code can be reproduced.
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_websockets::{Message as Frame, ServerBuilder, WebSocketStream};
+
+use super::message::MessageRef;
+
+/// The content of a recorded websocket frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameData {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl FrameData {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            FrameData::Text(text) => text.as_bytes(),
+            FrameData::Binary(blob) => blob,
+        }
+    }
+
+    /// Returns the command id of the frame, if any.
+    pub fn id(&self) -> Option<usize> {
+        MessageRef::peek_id(self.as_bytes())
+    }
+
+    /// Returns everything after the command id.
+    fn rest(&self) -> Option<&[u8]> {
+        let bytes = self.as_bytes();
+        let pos = bytes.iter().position(|b| *b == b'|')?;
+
+        Some(&bytes[pos + 1..])
+    }
+
+    fn to_frame(&self) -> Frame {
+        match self {
+            FrameData::Text(text) => Frame::text(text.clone()),
+            FrameData::Binary(blob) => Frame::binary(Bytes::from(blob.clone())),
+        }
+    }
+
+    /// Returns the frame with the command id replaced by id.
+    fn with_id(&self, id: usize) -> Frame {
+        let rest = self.rest().unwrap_or_default();
+
+        match self {
+            FrameData::Text(_) => {
+                Frame::text(format!("{}|{}", id, String::from_utf8_lossy(rest)))
+            }
+            FrameData::Binary(_) => {
+                let mut blob = format!("{}|", id).into_bytes();
+                blob.extend_from_slice(rest);
+
+                Frame::binary(Bytes::from(blob))
+            }
+        }
+    }
+}
+
+impl From<&Frame> for FrameData {
+    fn from(frame: &Frame) -> Self {
+        match frame.as_text() {
+            Some(text) => FrameData::Text(text.to_string()),
+            None => FrameData::Binary(frame.as_payload().to_vec()),
+        }
+    }
+}
+
+/// Event is a single recorded event of a PortAPI session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    /// The client connected to the server.
+    Connected,
+
+    /// The connection to the server has been lost or could not be established.
+    Disconnected { reason: String },
+
+    /// The client sent a frame.
+    Sent { id: Option<usize>, frame: FrameData },
+
+    /// The client received a frame.
+    Received { id: Option<usize>, frame: FrameData },
+}
+
+/// Entry is a single line of a recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Milliseconds since the UNIX epoch.
+    pub time: u64,
+
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Recorder writes the events of a PortAPI session as JSONL. Events are written by a
+/// background thread so recording never blocks the client. Lines are flushed as soon
+/// as no more events are queued so the recording survives a crash of the application.
+pub struct Recorder {
+    events: Option<mpsc::Sender<Entry>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Creates a recorder that writes to out.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        let (events, rx) = mpsc::channel();
+        let writer = thread::spawn(move || write_entries(BufWriter::new(out), rx));
+
+        Recorder {
+            events: Some(events),
+            writer: Some(writer),
+        }
+    }
+
+    /// Creates a recorder that writes to a new file at path. An existing file
+    /// is truncated.
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Recorder::new(std::fs::File::create(path)?))
+    }
+
+    /// Records event with the current time.
+    pub fn record(&self, event: Event) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        if let Some(events) = &self.events {
+            let _ = events.send(Entry { time, event });
+        }
+    }
+}
+
+impl Drop for Recorder {
+    /// Waits until all recorded events have been written.
+    fn drop(&mut self) {
+        drop(self.events.take());
+
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes the entries received from rx to out until the recorder is dropped.
+fn write_entries<W: Write>(mut out: BufWriter<W>, rx: mpsc::Receiver<Entry>) {
+    while let Ok(mut entry) = rx.recv() {
+        loop {
+            if let Err(err) = write_entry(&mut out, &entry) {
+                error!("failed to write recorded event: {}", err);
+            }
+
+            match rx.try_recv() {
+                Ok(next) => entry = next,
+                Err(_) => break,
+            }
+        }
+
+        if let Err(err) = out.flush() {
+            error!("failed to write recorded event: {}", err);
+        }
+    }
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *out, entry)?;
+
+    out.write_all(b"\n")
+}
+
+/// Reads a recording written by `Recorder`.
+pub fn read_recording(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let file = BufReader::new(std::fs::File::open(path)?);
+    let mut entries = Vec::new();
+
+    for (idx, line) in file.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = serde_json::from_str(&line).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("line {}: {}", idx + 1, err),
+            )
+        })?;
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// ReplayServer serves a recorded PortAPI session from a local websocket server.
+///
+/// The recording is split into one part per connection at each `Event::Disconnected`.
+/// Connection attempts for parts that never connected are refused, all others are
+/// served by sending the received frames of that part in order. Before a response
+/// is sent, the server waits for the client to send the request it belongs to and
+/// replaces the recorded command id with the one used by the client. Requests are
+/// matched by their content so the client does not need to use the same ids.
+///
+/// Frames are sent as fast as possible, the recorded timestamps are ignored. The
+/// connection is closed at the end of each part that ends with a disconnect while
+/// the last part stays connected. Once all parts have been served, any further
+/// connection attempts are refused.
+pub struct ReplayServer {
+    addr: SocketAddr,
+}
+
+impl ReplayServer {
+    /// Starts serving entries on a random local port.
+    pub fn start(entries: Vec<Entry>) -> std::io::Result<ReplayServer> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+
+        let addr = listener.local_addr()?;
+
+        let parts = split_connections(&entries);
+        let requests = Arc::new(
+            entries
+                .iter()
+                .filter_map(|entry| match &entry.event {
+                    Event::Sent { id, .. } => *id,
+                    _ => None,
+                })
+                .collect::<HashSet<usize>>(),
+        );
+
+        tauri::async_runtime::spawn(async move {
+            let listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("failed to start replay server: {}", err);
+                    return;
+                }
+            };
+
+            let mut parts = parts.into_iter();
+
+            while let Ok((stream, _)) = listener.accept().await {
+                let part = match parts.next() {
+                    Some(Some(part)) => part,
+                    // refuse the connection.
+                    _ => continue,
+                };
+
+                let requests = requests.clone();
+                tauri::async_runtime::spawn(async move {
+                    match ServerBuilder::new().accept(stream).await {
+                        Ok(ws) => replay(ws, part, &requests).await,
+                        Err(err) => warn!("replay: failed to accept websocket: {}", err),
+                    }
+                });
+            }
+        });
+
+        Ok(ReplayServer { addr })
+    }
+
+    /// Returns the base URL of the replay server to be used as the API endpoint.
+    pub fn base_url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+}
+
+/// Splits entries into the parts served to each connection. Parts that never
+/// connected are None.
+fn split_connections(entries: &[Entry]) -> Vec<Option<Vec<Event>>> {
+    let mut parts = Vec::new();
+    let mut current: Vec<Event> = Vec::new();
+
+    for entry in entries {
+        let done = matches!(entry.event, Event::Disconnected { .. });
+
+        current.push(entry.event.clone());
+
+        if done {
+            parts.push(std::mem::take(&mut current));
+        }
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+        .into_iter()
+        .map(|part| {
+            part.iter()
+                .any(|event| *event == Event::Connected)
+                .then_some(part)
+        })
+        .collect()
+}
+
+/// Serves a single part of a recording on ws. requests holds the command ids of
+/// all recorded requests.
+async fn replay(mut ws: WebSocketStream<TcpStream>, part: Vec<Event>, requests: &HashSet<usize>) {
+    // requests the client did not send yet, keyed by their content.
+    let mut pending: Vec<(Vec<u8>, usize)> = part
+        .iter()
+        .filter_map(|event| match event {
+            Event::Sent {
+                id: Some(id),
+                frame,
+            } => Some((frame.rest()?.to_vec(), *id)),
+            _ => None,
+        })
+        .filter(|(rest, _)| rest.as_slice() != b"cancel")
+        .collect();
+
+    // maps recorded command ids to the ones used by the client.
+    let mut ids: HashMap<usize, usize> = HashMap::new();
+
+    for event in part {
+        let (id, frame) = match event {
+            Event::Received { id, frame } => (id, frame),
+            Event::Disconnected { .. } => {
+                debug!("replay: closing connection");
+
+                let _ = ws.close().await;
+                return;
+            }
+            _ => continue,
+        };
+
+        let live_id = match id {
+            // responses to requests must wait for the client to send the request.
+            Some(id) if requests.contains(&id) => loop {
+                if let Some(live_id) = ids.get(&id) {
+                    break *live_id;
+                }
+
+                if !read_request(&mut ws, &mut pending, &mut ids).await {
+                    return;
+                }
+            },
+            Some(id) => id,
+            None => {
+                let _ = ws.send(frame.to_frame()).await;
+                continue;
+            }
+        };
+
+        if ws.send(frame.with_id(live_id)).await.is_err() {
+            return;
+        }
+    }
+
+    // keep the connection open and answer pings until the client disconnects.
+    while read_request(&mut ws, &mut pending, &mut ids).await {}
+}
+
+/// Reads the next frame from the client and matches it against the pending requests.
+/// Returns false if the connection has been closed.
+async fn read_request(
+    ws: &mut WebSocketStream<TcpStream>,
+    pending: &mut Vec<(Vec<u8>, usize)>,
+    ids: &mut HashMap<usize, usize>,
+) -> bool {
+    let frame = match ws.next().await {
+        Some(Ok(frame)) => frame,
+        _ => return false,
+    };
+
+    if !frame.is_text() && !frame.is_binary() {
+        return true;
+    }
+
+    let data = FrameData::from(&frame);
+
+    let (live_id, rest) = match (data.id(), data.rest()) {
+        (Some(id), Some(rest)) => (id, rest),
+        _ => return true,
+    };
+
+    match pending.iter().position(|(recorded, _)| recorded.as_slice() == rest) {
+        Some(idx) => {
+            let (_, recorded_id) = pending.remove(idx);
+            ids.insert(recorded_id, live_id);
+        }
+        None => debug!("replay: ignoring unrecorded request {}", String::from_utf8_lossy(rest)),
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portapi::client::*;
+    use crate::portapi::key::DbKey;
+    use crate::portapi::mock::MockServer;
+    use crate::portapi::query::Query;
+    use crate::portapi::subscription::RecordEvent;
+    use futures_util::StreamExt;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Test {
+        a: i64,
+    }
+
+    fn entry(event: Event) -> Entry {
+        Entry { time: 0, event }
+    }
+
+    fn text(frame: &str) -> FrameData {
+        FrameData::Text(frame.to_string())
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let server = MockServer::start().await;
+        server.set("test:a", &Test { a: 1 });
+
+        let path = std::env::temp_dir().join(format!("portapi-record-{}.jsonl", std::process::id()));
+        let key: DbKey = "test:a".parse().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let recorder = Recorder::create(&path).unwrap();
+        let api = connect_resilient(
+            server.uri(),
+            ReconnectPolicy::default(),
+            None,
+            None,
+            Some(recorder),
+        )
+        .await
+        .unwrap();
+
+        let (_, value) = api.get::<Test>(&key, timeout).await.unwrap();
+        assert_eq!(value, Test { a: 1 });
+
+        // waits for the recorder to write all events.
+        api.set_recorder(None);
+
+        let entries = read_recording(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let events: Vec<Event> = entries.iter().map(|e| e.event.clone()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Connected,
+                Event::Sent {
+                    id: Some(0),
+                    frame: text("0|get|test:a"),
+                },
+                Event::Received {
+                    id: Some(0),
+                    frame: text("0|ok|test:a|J{\"a\":1}"),
+                },
+            ]
+        );
+
+        // the recording can be replayed without the original server.
+        drop(server);
+
+        let replay = ReplayServer::start(entries).unwrap();
+        let uri = format!("{}api/database/v1", replay.base_url().replace("http", "ws"));
+
+        let api = connect_resilient(&uri, ReconnectPolicy::default(), None, None, None)
+            .await
+            .unwrap();
+
+        let (_, value) = api.get::<Test>(&key, timeout).await.unwrap();
+        assert_eq!(value, Test { a: 1 });
+    }
+
+    #[tokio::test]
+    async fn replay_session() {
+        // the recorded ids don't match the ones used by the client during replay.
+        let entries = vec![
+            entry(Event::Connected),
+            entry(Event::Sent {
+                id: Some(7),
+                frame: text("7|qsub|query test:"),
+            }),
+            entry(Event::Received {
+                id: Some(7),
+                frame: text("7|ok|test:a|J{\"a\": 1}"),
+            }),
+            entry(Event::Received {
+                id: Some(7),
+                frame: text("7|done"),
+            }),
+            entry(Event::Received {
+                id: Some(7),
+                frame: text("7|upd|test:a|J{\"a\": 2}"),
+            }),
+            entry(Event::Disconnected {
+                reason: "websocket connection lost".to_string(),
+            }),
+            entry(Event::Disconnected {
+                reason: "connection refused".to_string(),
+            }),
+            entry(Event::Connected),
+            entry(Event::Sent {
+                id: Some(7),
+                frame: text("7|qsub|query test:"),
+            }),
+            entry(Event::Received {
+                id: Some(7),
+                frame: text("7|ok|test:a|J{\"a\": 3}"),
+            }),
+            entry(Event::Received {
+                id: Some(7),
+                frame: text("7|done"),
+            }),
+        ];
+
+        let server = ReplayServer::start(entries).unwrap();
+        let uri = format!("{}api/database/v1", server.base_url().replace("http", "ws"));
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        let api = connect_resilient(&uri, policy, None, None, None).await.unwrap();
+        let mut sub = api.subscribe::<Test>(Query::new("test:")).await.unwrap();
+
+        let mut values = Vec::new();
+        while values.len() < 6 {
+            match sub.next().await.unwrap() {
+                RecordEvent::Initial(_, v) => values.push(format!("initial {}", v.a)),
+                RecordEvent::Updated(_, v) => values.push(format!("updated {}", v.a)),
+                RecordEvent::SnapshotDone => values.push("done".to_string()),
+                RecordEvent::Reconnected => values.push("reconnected".to_string()),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+
+        assert_eq!(
+            values,
+            ["initial 1", "done", "updated 2", "reconnected", "initial 3", "done"]
+        );
+    }
+}
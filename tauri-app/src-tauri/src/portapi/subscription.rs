@@ -0,0 +1,213 @@
+use futures_util::{ready, Stream};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::sync::mpsc::Receiver;
+
+use super::client::ResponseReceiver;
+use super::key::DbKey;
+use super::message::*;
+use super::types::*;
+
+/// SubscriptionError is emitted as `RecordEvent::Error` if the server reported an
+/// error, a record could not be decoded or the connection has been lost.
+#[derive(Debug, Error)]
+pub enum SubscriptionError {
+    #[error("server error: {0}")]
+    Server(String),
+
+    #[error("failed to parse record {0}: {1}")]
+    Parse(String, ParseError),
+
+    #[error(transparent)]
+    Malformed(MessageError),
+
+    #[error("connection to the server lost")]
+    ConnectionLost,
+}
+
+/// RecordEvent is a strongly typed event emitted by a `Subscription`. Records are
+/// accompanied by their metadata decoded from the `_meta` block of the payload.
+#[derive(Debug)]
+pub enum RecordEvent<T> {
+    /// A record that already existed when the subscription was created.
+    Initial(Record, T),
+
+    /// A record that has been created after the subscription was created.
+    Created(Record, T),
+
+    /// An existing record has been updated.
+    Updated(Record, T),
+
+    /// A record has been deleted.
+    Deleted(DbKey),
+
+    /// All records that existed when the subscription was created have been
+    /// sent as `RecordEvent::Initial`.
+    SnapshotDone,
+
+    /// The server sent a warning. The subscription is still active.
+    Warning(String),
+
+    /// The server reported an error or a record could not be decoded.
+    Error(SubscriptionError),
+
+    /// The client reconnected to the server and re-created the subscription. All
+    /// records that currently exist are sent again as `RecordEvent::Initial` followed
+    /// by `RecordEvent::SnapshotDone`. Records deleted in the meantime are not reported.
+    Reconnected,
+}
+
+impl<T: DeserializeOwned> RecordEvent<T> {
+    /// Converts a raw response into a record event. Returns None for responses
+    /// that carry no information for subscribers (i.e. `Response::Success`).
+    fn from_response(response: Response) -> Option<Self> {
+        let parse = |key: String, payload: Payload, make: fn(Record, T) -> Self| {
+            let record = match key.parse() {
+                Ok(key) => Record::from_payload(key, &payload),
+                Err(err) => return RecordEvent::Error(SubscriptionError::Malformed(err)),
+            };
+
+            match payload.parse::<T>() {
+                Ok(value) => make(record, value),
+                Err(err) => RecordEvent::Error(SubscriptionError::Parse(key, err)),
+            }
+        };
+
+        match response {
+            Response::Ok(key, payload) => Some(parse(key, payload, RecordEvent::Initial)),
+            Response::New(key, payload) => Some(parse(key, payload, RecordEvent::Created)),
+            Response::Update(key, payload) => Some(parse(key, payload, RecordEvent::Updated)),
+            Response::Delete(key) => Some(match key.parse() {
+                Ok(key) => RecordEvent::Deleted(key),
+                Err(err) => RecordEvent::Error(SubscriptionError::Malformed(err)),
+            }),
+            Response::Done => Some(RecordEvent::SnapshotDone),
+            Response::Warning(msg) => Some(RecordEvent::Warning(msg)),
+            Response::Error(msg) => Some(RecordEvent::Error(SubscriptionError::Server(msg))),
+            Response::Malformed(err) => Some(RecordEvent::Error(SubscriptionError::Malformed(err))),
+            Response::Reconnected => Some(RecordEvent::Reconnected),
+            Response::ConnectionLost => Some(RecordEvent::Error(SubscriptionError::ConnectionLost)),
+            Response::Success => None,
+        }
+    }
+}
+
+/// Subscription is a `futures::Stream` of typed record events for a query
+/// subscription. Use `PortAPI::subscribe` to create a new subscription.
+///
+/// The stream ends when the connection to the server is lost. Dropping the subscription
+/// cancels it on the server.
+pub struct Subscription<T> {
+    rx: ResponseReceiver,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> From<ResponseReceiver> for Subscription<T> {
+    fn from(rx: ResponseReceiver) -> Self {
+        Subscription {
+            rx,
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<T> From<Receiver<Response>> for Subscription<T> {
+    fn from(rx: Receiver<Response>) -> Self {
+        ResponseReceiver::from(rx).into()
+    }
+}
+
+impl<T: DeserializeOwned> Stream for Subscription<T> {
+    type Item = RecordEvent<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(response) => {
+                    if let Some(event) = RecordEvent::from_response(response) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use serde::Deserialize;
+    use tokio::sync::mpsc::channel;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Test {
+        a: i64,
+    }
+
+    #[tokio::test]
+    async fn typed_events() {
+        let (tx, rx) = channel(16);
+        let mut sub: Subscription<Test> = rx.into();
+
+        let responses = vec![
+            Response::Ok("test:a".to_string(), Payload::JSON("{\"a\": 1}".to_string())),
+            Response::Done,
+            Response::Success,
+            Response::New(
+                "test:b".to_string(),
+                Payload::JSON("{\"a\": 2, \"_meta\": {\"Created\": 10, \"Modified\": 20, \"Expires\": 30}}".to_string()),
+            ),
+            Response::Update("test:b".to_string(), Payload::JSON("{\"a\": \"x\"}".to_string())),
+            Response::Warning("slow".to_string()),
+            Response::Delete("test:a".to_string()),
+            Response::Ok("invalid".to_string(), Payload::JSON("{\"a\": 1}".to_string())),
+            Response::Error("gone".to_string()),
+        ];
+
+        for response in responses {
+            tx.send(response).await.unwrap();
+        }
+        drop(tx);
+
+        assert!(matches!(
+            sub.next().await,
+            Some(RecordEvent::Initial(r, Test { a: 1 })) if r.key.to_string() == "test:a" && r.modified == 0
+        ));
+        assert!(matches!(sub.next().await, Some(RecordEvent::SnapshotDone)));
+
+        match sub.next().await {
+            Some(RecordEvent::Created(record, Test { a: 2 })) => {
+                assert_eq!(record.key.to_string(), "test:b");
+                assert_eq!(record.created, 10);
+                assert_eq!(record.modified, 20);
+                assert_eq!(record.expires, 30);
+                assert!(record.is_expired());
+                assert!(!record.is_valid());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        assert!(matches!(
+            sub.next().await,
+            Some(RecordEvent::Error(SubscriptionError::Parse(k, ParseError::JSON(_)))) if k == "test:b"
+        ));
+        assert!(matches!(sub.next().await, Some(RecordEvent::Warning(w)) if w == "slow"));
+        assert!(matches!(sub.next().await, Some(RecordEvent::Deleted(k)) if k.to_string() == "test:a"));
+        assert!(matches!(
+            sub.next().await,
+            Some(RecordEvent::Error(SubscriptionError::Malformed(MessageError::InvalidKey(k)))) if k == "invalid"
+        ));
+        assert!(matches!(
+            sub.next().await,
+            Some(RecordEvent::Error(SubscriptionError::Server(e))) if e == "gone"
+        ));
+        assert!(sub.next().await.is_none());
+    }
+}
@@ -1,22 +1,40 @@
 
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::key::DbKey;
 use super::message::*;
+use super::query::Query;
 
 /// Request is a strongly typed request message
 /// that can be converted to a `portapi::message::Message`
 /// object for further use by the client (`portapi::client::PortAPI`).
 #[derive(PartialEq, Debug)]
 pub enum Request {
-    Get(String),
-    Query(String),
-    Subscribe(String),
-    QuerySubscribe(String),
-    Create(String, Payload),
-    Update(String, Payload),
-    Insert(String, Payload),
-    Delete(String),
+    Get(DbKey),
+    Query(Query),
+    Subscribe(Query),
+    QuerySubscribe(Query),
+    Create(DbKey, Payload),
+    Update(DbKey, Payload),
+    Insert(DbKey, Payload),
+    Delete(DbKey),
     Cancel,
 }
 
+impl Request {
+    /// Reports whether the request creates a subscription (`sub` or `qsub`) that stays
+    /// active after the initial `done` response.
+    pub fn is_subscription(&self) -> bool {
+        matches!(self, Request::Subscribe(_) | Request::QuerySubscribe(_))
+    }
+
+    /// Reports whether the request streams responses and may be canceled using
+    /// `Request::Cancel`.
+    pub fn is_cancelable(&self) -> bool {
+        matches!(self, Request::Query(_) | Request::Subscribe(_) | Request::QuerySubscribe(_))
+    }
+}
+
 /// Implementation to convert a internal `portapi::message::Message` to a valid
 /// `Request` variant.
 /// 
@@ -28,38 +46,38 @@ impl std::convert::TryFrom<Message> for Request {
         match value.cmd.as_str() {
             "get" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
-                Ok(Request::Get(key))
+                Ok(Request::Get(key.parse()?))
             },
             "query" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
-                Ok(Request::Query(key))
+                Ok(Request::Query(key.parse()?))
             },
             "sub" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
-                Ok(Request::Subscribe(key))
+                Ok(Request::Subscribe(key.parse()?))
             },
             "qsub" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
-                Ok(Request::QuerySubscribe(key))
+                Ok(Request::QuerySubscribe(key.parse()?))
             },
             "create" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
                 let payload = value.payload.ok_or(MessageError::MissingPayload)?;
-                Ok(Request::Create(key, payload))
+                Ok(Request::Create(key.parse()?, payload))
             },
             "update" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
                 let payload = value.payload.ok_or(MessageError::MissingPayload)?;
-                Ok(Request::Update(key, payload))
+                Ok(Request::Update(key.parse()?, payload))
             },
             "insert" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
                 let payload = value.payload.ok_or(MessageError::MissingPayload)?;
-                Ok(Request::Insert(key, payload))
+                Ok(Request::Insert(key.parse()?, payload))
             },
             "delete" => {
                 let key = value.key.ok_or(MessageError::MissingKey)?;
-                Ok(Request::Delete(key))
+                Ok(Request::Delete(key.parse()?))
             },
             "cancel" => {
                 Ok(Request::Cancel)
@@ -74,27 +92,33 @@ impl std::convert::TryFrom<Message> for Request {
 /// An implementation to try to convert a `Request` variant into a valid 
 /// `portapi::message::Message` struct.
 /// 
-/// While this implementation does not yet return any errors, it's expected that
-/// additional validation will be added in the future so users should already expect
-/// to receive `portapi::message::MessageError`s.
+/// Keys are already validated when constructing a `DbKey`. Queries built using
+/// `Query::new` are not, so `MessageError::InvalidQuery` is returned if the prefix
+/// is not a valid key or the rendered query contains `|` or control characters.
 impl std::convert::TryFrom<Request> for Message {
     type Error = MessageError;
 
     fn try_from(value: Request) -> Result<Self, Self::Error> {
         match value {
-            Request::Get(key) => Ok(Message { id: 0, cmd: "get".to_string(), key: Some(key), payload: None }),
-            Request::Query(key) => Ok(Message { id: 0, cmd: "query".to_string(), key: Some(key), payload: None }),
-            Request::Subscribe(key) => Ok(Message { id: 0, cmd: "sub".to_string(), key: Some(key), payload: None }),
-            Request::QuerySubscribe(key) => Ok(Message { id: 0, cmd: "qsub".to_string(), key: Some(key), payload: None }),
-            Request::Create(key, value) => Ok(Message{ id: 0, cmd: "create".to_string(), key: Some(key), payload: Some(value)}),
-            Request::Update(key, value) => Ok(Message{ id: 0, cmd: "update".to_string(), key: Some(key), payload: Some(value)}),
-            Request::Insert(key, value) => Ok(Message{ id: 0, cmd: "insert".to_string(), key: Some(key), payload: Some(value)}),
-            Request::Delete(key) => Ok(Message { id: 0, cmd: "delete".to_string(), key: Some(key), payload: None }),
+            Request::Get(key) => Ok(Message { id: 0, cmd: "get".to_string(), key: Some(key.to_string()), payload: None }),
+            Request::Query(query) => Ok(Message { id: 0, cmd: "query".to_string(), key: Some(render_query(query)?), payload: None }),
+            Request::Subscribe(query) => Ok(Message { id: 0, cmd: "sub".to_string(), key: Some(render_query(query)?), payload: None }),
+            Request::QuerySubscribe(query) => Ok(Message { id: 0, cmd: "qsub".to_string(), key: Some(render_query(query)?), payload: None }),
+            Request::Create(key, value) => Ok(Message{ id: 0, cmd: "create".to_string(), key: Some(key.to_string()), payload: Some(value)}),
+            Request::Update(key, value) => Ok(Message{ id: 0, cmd: "update".to_string(), key: Some(key.to_string()), payload: Some(value)}),
+            Request::Insert(key, value) => Ok(Message{ id: 0, cmd: "insert".to_string(), key: Some(key.to_string()), payload: Some(value)}),
+            Request::Delete(key) => Ok(Message { id: 0, cmd: "delete".to_string(), key: Some(key.to_string()), payload: None }),
             Request::Cancel => Ok(Message { id: 0, cmd: "cancel".to_string(), key: None, payload: None }),
         }
     }
 }
 
+/// Renders query for the key field of a message after making sure it can be sent.
+fn render_query(query: Query) -> Result<String, MessageError> {
+    query.validate()?;
+
+    Ok(query.to_string())
+}
 
 /// Response is strongly types PortAPI response message.
 /// that can be converted to a `portapi::message::Message`
@@ -108,7 +132,32 @@ pub enum Response {
     Success,
     Error(String),
     Warning(String),
-    Done
+    Done,
+
+    /// Malformed is never sent by the server but generated by the client if
+    /// a frame for the request could not be decoded.
+    Malformed(MessageError),
+
+    /// Reconnected is never sent by the server but generated by a resilient client
+    /// (see `portapi::client::connect_resilient`) after the connection has been
+    /// re-established and the subscription has been sent to the server again.
+    Reconnected,
+
+    /// ConnectionLost is never sent by the server but generated by the client if the
+    /// connection was lost before the request finished.
+    ConnectionLost,
+}
+
+impl Response {
+    /// Reports whether no further responses will follow for the request. Set subscription
+    /// for `sub` and `qsub` requests as those stay active after the initial `done`.
+    pub fn is_terminal(&self, subscription: bool) -> bool {
+        match self {
+            Response::Done => !subscription,
+            Response::Success | Response::Error(_) | Response::ConnectionLost => true,
+            _ => false,
+        }
+    }
 }
 
 /// Implementation to convert a internal `portapi::message::Message` to a valid
@@ -167,9 +216,7 @@ impl std::convert::TryFrom<Message> for Response {
 /// An implementation to try to convert a `Response` variant into a valid 
 /// `portapi::message::Message` struct.
 /// 
-/// While this implementation does not yet return any errors, it's expected that
-/// additional validation will be added in the future so users should already expect
-/// to receive `portapi::message::MessageError`s.
+/// `Response::Malformed` cannot be converted and returns the error it carries.
 impl std::convert::TryFrom<Response> for Message {
     type Error = MessageError;
 
@@ -183,17 +230,86 @@ impl std::convert::TryFrom<Response> for Message {
             Response::Warning(key) => Ok(Message{id: 0, cmd: "warning".to_string(), key: Some(key), payload: None}),
             Response::Error(key) => Ok(Message{id: 0, cmd: "error".to_string(), key: Some(key), payload: None}),
             Response::Done => Ok(Message{id: 0, cmd: "done".to_string(), key: None, payload: None}),
+            Response::Malformed(err) => Err(err),
+            Response::Reconnected => Err(MessageError::UnknownCommand("reconnected".to_string())),
+            Response::ConnectionLost => Err(MessageError::UnknownCommand("connection-lost".to_string())),
         }
     }
 }
 
 
-#[derive(serde::Serialize, serde::Deserialize)]
-#[serde(rename_all = "UPPERCASE")]
+/// Record holds the metadata portbase attaches to every database record as `_meta`.
+/// All timestamps are unix-epoch seconds and zero if unset.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
 pub struct Record {
+    #[serde(default)]
     pub created: u64,
+    #[serde(default)]
     pub deleted: u64,
+    #[serde(default)]
     pub expires: u64,
+    #[serde(default)]
     pub modified: u64,
-    pub key: String,
-}
\ No newline at end of file
+    pub key: DbKey,
+}
+
+impl Record {
+    /// Returns the time the record expires, if set.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        timestamp(self.expires)
+    }
+
+    /// Reports whether the record has been marked as deleted.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted > 0
+    }
+
+    /// Reports whether the record has an expiry time in the past.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at().is_some_and(|t| t <= SystemTime::now())
+    }
+
+    /// Reports whether the record is neither deleted nor expired. Portbase does not
+    /// return such records for new queries but may still send them to subscribers.
+    pub fn is_valid(&self) -> bool {
+        !self.is_deleted() && !self.is_expired()
+    }
+
+    /// Decodes the `_meta` block of the payload of the record stored at key. If the
+    /// payload does not carry any metadata, only the key is set.
+    pub fn from_payload(key: DbKey, payload: &Payload) -> Record {
+        #[derive(serde::Deserialize, Default)]
+        #[serde(rename_all = "PascalCase", default)]
+        struct Meta {
+            created: u64,
+            deleted: u64,
+            expires: u64,
+            modified: u64,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Envelope {
+            #[serde(rename = "_meta", default)]
+            meta: Meta,
+        }
+
+        let meta = payload
+            .parse::<Envelope>()
+            .map(|e| e.meta)
+            .unwrap_or_default();
+
+        Record {
+            created: meta.created,
+            deleted: meta.deleted,
+            expires: meta.expires,
+            modified: meta.modified,
+            key,
+        }
+    }
+}
+
+/// Converts a unix-epoch timestamp in seconds to a SystemTime. Zero means unset.
+fn timestamp(secs: u64) -> Option<SystemTime> {
+    (secs > 0).then(|| UNIX_EPOCH + Duration::from_secs(secs))
+}
@@ -1,4 +1,5 @@
-use super::PortmasterPlugin;
+use super::{PortmasterExt, PortmasterPlugin};
+use crate::portapi::http::HttpError;
 use crate::service::get_service_manager;
 use crate::service::ServiceManager;
 use log::debug;
@@ -40,6 +41,29 @@ pub fn should_handle_prompts<R: Runtime>(
     }
 }
 
+#[tauri::command]
+pub fn is_auth_required<R: Runtime>(
+    _window: Window<R>,
+    portmaster: State<'_, PortmasterPlugin<R>>,
+) -> Result {
+    if portmaster.is_auth_required() {
+        Ok("true".to_string())
+    } else {
+        Ok("false".to_string())
+    }
+}
+
+#[tauri::command]
+pub fn take_pending_navigation<R: Runtime>(
+    _window: Window<R>,
+    portmaster: State<'_, PortmasterPlugin<R>>,
+) -> Result {
+    match portmaster.take_pending_navigation() {
+        Some(navigation) => serde_json::to_string(&navigation).map_err(|err| err.to_string()),
+        None => Ok("".to_string()),
+    }
+}
+
 #[tauri::command]
 pub fn get_state<R: Runtime>(
     _window: Window<R>,
@@ -86,7 +110,7 @@ pub fn get_app_info<R: Runtime>(
         matching_path,
     };
 
-    if id == "" {
+    if id.is_empty() {
         id = uuid::Uuid::new_v4().to_string()
     }
     let cloned = id.clone();
@@ -137,7 +161,7 @@ pub fn get_app_info<R: Runtime>(
 pub fn get_service_manager_status<R: Runtime>(window: Window<R>, response_id: String) -> Result {
     let mut id = response_id;
 
-    if id == "" {
+    if id.is_empty() {
         id = uuid::Uuid::new_v4().to_string();
     }
     let cloned = id.clone();
@@ -161,7 +185,7 @@ pub fn get_service_manager_status<R: Runtime>(window: Window<R>, response_id: St
 pub fn start_service<R: Runtime>(window: Window<R>, response_id: String) -> Result {
     let mut id = response_id;
 
-    if id == "" {
+    if id.is_empty() {
         id = uuid::Uuid::new_v4().to_string();
     }
     let cloned = id.clone();
@@ -180,3 +204,54 @@ pub fn start_service<R: Runtime>(window: Window<R>, response_id: String) -> Resu
 
     Ok(cloned)
 }
+
+/// Spawns f with the HTTP API client on tauri's async runtime and emits the result
+/// to the window using a new event id that is returned to the caller.
+fn http_action<R, F, Fut>(window: Window<R>, response_id: String, f: F) -> Result
+where
+    R: Runtime,
+    F: FnOnce(crate::portapi::http::HttpClient) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = std::result::Result<String, HttpError>> + Send,
+{
+    let mut id = response_id;
+
+    if id.is_empty() {
+        id = uuid::Uuid::new_v4().to_string();
+    }
+    let cloned = id.clone();
+
+    let http = window.portmaster().http();
+
+    tauri::async_runtime::spawn(async move {
+        match f(http).await {
+            Ok(result) => window.emit(&id, &result),
+            Err(err) => window.emit(
+                &id,
+                Error {
+                    error: err.to_string(),
+                },
+            ),
+        }
+    });
+
+    Ok(cloned)
+}
+
+#[tauri::command]
+pub fn shutdown_portmaster<R: Runtime>(window: Window<R>, response_id: String) -> Result {
+    http_action(window, response_id, |http| async move {
+        http.shutdown().await.map(|_| String::new())
+    })
+}
+
+#[tauri::command]
+pub fn restart_portmaster<R: Runtime>(window: Window<R>, response_id: String) -> Result {
+    http_action(window, response_id, |http| async move {
+        http.restart().await.map(|_| String::new())
+    })
+}
+
+#[tauri::command]
+pub fn get_debug_info<R: Runtime>(window: Window<R>, response_id: String) -> Result {
+    http_action(window, response_id, |http| async move { http.debug_info().await })
+}
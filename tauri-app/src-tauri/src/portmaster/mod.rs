@@ -24,40 +24,85 @@ mod websocket;
 mod notifications;
 
 use crate::portapi::{
-    client::PortAPI, message::Payload, models::config::BooleanValue, types::Request,
+    auth::Credentials,
+    client::{ConnectionState, Keepalive, PortAPI},
+    endpoint::Endpoint,
+    http::HttpClient,
+    models::config::ConfigApi,
+    recording::{read_recording, ReplayServer},
 };
 use std::{
     collections::HashMap,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
 };
 
-use log::debug;
-use serde;
+use log::{debug, error, info};
 use std::sync::Mutex;
+use std::time::Duration;
 use tauri::{
     plugin::{Builder, TauriPlugin},
     AppHandle, Manager, Runtime,
 };
+use tauri_plugin_cli::CliExt;
+use tokio::sync::watch;
+use url::Url;
+
+/// Environment variables that override the API and UI URLs configured in the
+/// plugin configuration. The --api-url and --ui-url flags take precedence.
+const API_URL_ENV: &str = "PORTMASTER_API_URL";
+const UI_URL_ENV: &str = "PORTMASTER_UI_URL";
+
+/// The file in the data directory that holds the credentials for the Portmaster
+/// API if not passed using --api-key.
+const CREDENTIALS_FILE: &str = "api-key";
 
 pub trait Handler {
-    fn on_connect(&mut self, cli: PortAPI) -> ();
+    fn on_connect(&mut self, cli: PortAPI);
     fn on_disconnect(&mut self);
+
+    /// Called when the connection to the Portmaster API has been re-established
+    /// after on_disconnect. The client passed to on_connect stays valid and
+    /// replays all of it's subscriptions.
+    fn on_reconnect(&mut self) {}
 }
 
 pub struct PortmasterPlugin<R: Runtime> {
     #[allow(dead_code)]
     app: AppHandle<R>,
 
+    // the endpoint of the Portmaster API.
+    endpoint: Endpoint,
+
+    // the URL to load the user interface from, if configured.
+    ui_url: Option<Url>,
+
+    // the credentials used to authenticate against the Portmaster API.
+    credentials: Option<Credentials>,
+
+    // the file to record the PortAPI session to, if any.
+    record_path: Option<PathBuf>,
+
+    // whether or not the Portmaster API rejected our credentials.
+    auth_required: AtomicBool,
+
+    // the client for the Portmaster HTTP API. It shares the endpoint and
+    // credentials with the websocket client.
+    http: HttpClient,
+
     // state allows the angular application to store arbitrary values in the
     // tauri application memory using the get_state and set_state
     // tauri::commands.
     state: Mutex<HashMap<String, String>>,
 
-    // an atomic boolean that indicates if we're currently connected to
-    // portmaster or not.
-    is_reachable: AtomicBool,
+    // the current state of the connection to the Portmaster API.
+    connection_state: watch::Sender<ConnectionState>,
+
+    // the keepalive used to detect dead connections to the Portmaster API.
+    keepalive: Keepalive,
 
-    // holds the portapi client if any.
+    // holds the portapi client once we connected for the first time. The
+    // client reconnects on it's own so it's kept even if we're disconnected.
     api: Mutex<Option<PortAPI>>,
 
     // a vector of handlers that should be invoked on connect and disconnect of
@@ -73,6 +118,18 @@ pub struct PortmasterPlugin<R: Runtime> {
     // whether or not the angular application should call window.show after it
     // finished bootstrapping.
     should_show_after_bootstrap: AtomicBool,
+
+    // the route the angular application should navigate to after it finished
+    // bootstrapping, if any.
+    pending_navigation: Mutex<Option<Navigation>>,
+}
+
+/// Navigation is a route of the angular application, emitted as the payload of
+/// `portmaster:navigate`.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Navigation {
+    pub path: String,
+    pub query: HashMap<String, String>,
 }
 
 impl<R: Runtime> PortmasterPlugin<R> {
@@ -81,10 +138,7 @@ impl<R: Runtime> PortmasterPlugin<R> {
         let map = self.state.lock();
 
         if let Ok(map) = map {
-            match map.get(&key) {
-                Some(value) => Some(value.clone()),
-                None => None,
-            }
+            map.get(&key).cloned()
         } else {
             None
         }
@@ -99,9 +153,63 @@ impl<R: Runtime> PortmasterPlugin<R> {
         }
     }
 
-    /// Reports wheter or not we're currently connected to the Portmaster API.
+    /// Reports wheter or not we're currently connected to the Portmaster API. Use
+    /// watch_connection_state to get notified about changes.
     pub fn is_reachable(&self) -> bool {
-        self.is_reachable.load(Ordering::Relaxed)
+        *self.connection_state.borrow() == ConnectionState::Connected
+    }
+
+    /// Returns the endpoint of the Portmaster API.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Returns the URL the user interface should be loaded from.
+    ///
+    /// If not configured, this is the base URL of the Portmaster API. In
+    /// #[cfg(debug_assertions)] the TAURI_PM_URL environment variable or the
+    /// angular development server at http://localhost:4200 are used instead.
+    pub fn ui_url(&self) -> Url {
+        if let Some(url) = &self.ui_url {
+            return url.clone();
+        }
+
+        #[cfg(debug_assertions)]
+        {
+            if let Some(url) = std::env::var("TAURI_PM_URL")
+                .ok()
+                .and_then(|url| url.parse().ok())
+            {
+                return url;
+            }
+
+            "http://localhost:4200".parse().unwrap()
+        }
+
+        #[cfg(not(debug_assertions))]
+        self.endpoint.base().clone()
+    }
+
+    /// Returns the credentials used to authenticate against the Portmaster API.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Returns the client for the Portmaster HTTP API.
+    pub fn http(&self) -> HttpClient {
+        self.http.clone()
+    }
+
+    /// Reports whether or not the Portmaster API rejected our credentials. The
+    /// `portmaster:auth-required` event is emitted whenever this changes to true.
+    pub fn is_auth_required(&self) -> bool {
+        self.auth_required.load(Ordering::Relaxed)
+    }
+
+    /// Returns a receiver for the state of the connection to the Portmaster API. Unlike
+    /// handlers, the receiver is also notified about each reconnection attempt.
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection_state.subscribe()
     }
 
     /// Registers a new connection handler that is called on connect
@@ -113,6 +221,10 @@ impl<R: Runtime> PortmasterPlugin<R> {
         // Call the respective handler method immediately now.
         if let Some(api) = self.get_api() {
             handler.on_connect(api);
+
+            if !self.is_reachable() {
+                handler.on_disconnect();
+            }
         } else {
             handler.on_disconnect();
         }
@@ -124,11 +236,8 @@ impl<R: Runtime> PortmasterPlugin<R> {
 
     /// Returns the current portapi client.
     pub fn get_api(&self) -> Option<PortAPI> {
-        if let Ok(mut api) = self.api.lock() {
-            match &mut *api {
-                Some(api) => Some(api.clone()),
-                None => None,
-            }
+        if let Ok(api) = self.api.lock() {
+            (*api).clone()
         } else {
             None
         }
@@ -181,21 +290,43 @@ impl<R: Runtime> PortmasterPlugin<R> {
         let _ = self.app.emit("portmaster:show", "");
     }
 
+    /// Opens the main window and tells the angular application to navigate to
+    /// the given route. The route is also kept until the angular application
+    /// fetches it using `take_pending_navigation` in case it's still bootstrapping.
+    pub fn navigate(&self, navigation: Navigation) {
+        debug!("[tauri] navigating to {:?}", navigation);
+
+        if let Ok(mut pending) = self.pending_navigation.lock() {
+            *pending = Some(navigation.clone());
+        }
+
+        if let Err(err) = crate::window::open_window(&self.app) {
+            error!("failed to open window: {}", err);
+        }
+
+        let _ = self.app.emit("portmaster:navigate", navigation);
+    }
+
+    /// Returns and clears the route the angular application should navigate to
+    /// after bootstrapping.
+    pub fn take_pending_navigation(&self) -> Option<Navigation> {
+        self.pending_navigation
+            .lock()
+            .ok()
+            .and_then(|mut pending| pending.take())
+    }
+
     /// Enables or disables the SPN.
     pub fn set_spn_enabled(&self, enabled: bool) {
         if let Some(api) = self.get_api() {
-            let body: Result<Payload, serde_json::Error> = BooleanValue {
-                value: Some(enabled),
-            }
-            .try_into();
-
-            if let Ok(payload) = body {
-                tauri::async_runtime::spawn(async move {
-                    _ = api
-                        .request(Request::Update("config:spn/enable".to_string(), payload))
-                        .await;
-                });
-            }
+            tauri::async_runtime::spawn(async move {
+                if let Err(err) = api
+                    .set_config("spn/enable", enabled, Duration::from_secs(5))
+                    .await
+                {
+                    error!("failed to set spn/enable: {}", err);
+                }
+            });
         }
     }
 
@@ -203,16 +334,30 @@ impl<R: Runtime> PortmasterPlugin<R> {
     fn start_notification_handler(&self) {
         if let Some(api) = self.get_api() {
             let cli = api.clone();
+            let app = self.app.clone();
             tauri::async_runtime::spawn(async move {
-                notifications::notification_handler(cli).await;
+                notifications::notification_handler(app, cli).await;
             });
         }
     }
 
+    /// Internal method to publish the state of the connection to the Portmaster API.
+    fn set_connection_state(&self, state: ConnectionState) {
+        self.connection_state.send_replace(state);
+    }
+
+    /// Internal method to update whether or not the Portmaster API rejected our
+    /// credentials.
+    fn set_auth_required(&self, required: bool) {
+        let was_required = self.auth_required.swap(required, Ordering::Relaxed);
+
+        if required && !was_required {
+            let _ = self.app.emit("portmaster:auth-required", "");
+        }
+    }
+
     /// Internal method to call all on_connect handlers
     fn on_connect(&self, api: PortAPI) {
-        self.is_reachable.store(true, Ordering::Relaxed);
-
         // store the new api client.
         let mut guard = self.api.lock().unwrap();
         *guard = Some(api.clone());
@@ -230,15 +375,17 @@ impl<R: Runtime> PortmasterPlugin<R> {
         }
     }
 
+    /// Internal method to call all on_reconnect handlers
+    fn on_reconnect(&self) {
+        if let Ok(mut handlers) = self.handlers.lock() {
+            for handler in handlers.iter_mut() {
+                handler.on_reconnect();
+            }
+        }
+    }
+
     /// Internal method to call all on_disconnect handlers
     fn on_disconnect(&self) {
-        self.is_reachable.store(false, Ordering::Relaxed);
-
-        // clear the current api client reference.
-        let mut guard = self.api.lock().unwrap();
-        *guard = None;
-        drop(guard);
-
         if let Ok(mut handlers) = self.handlers.lock() {
             for handler in handlers.iter_mut() {
                 handler.on_disconnect();
@@ -251,8 +398,101 @@ pub trait PortmasterExt<R: Runtime> {
     fn portmaster(&self) -> &PortmasterPlugin<R>;
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub struct Config {}
+/// Config is the configuration of the portmaster plugin in tauri.conf.json.
+///
+/// Note that the user interface needs IPC access which is only granted to the
+/// domains listed in `dangerousRemoteDomainIpcAccess`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// The base URL of the Portmaster API. Defaults to http://localhost:817.
+    pub api_url: Option<String>,
+
+    /// The URL to load the user interface from. Defaults to api_url.
+    pub ui_url: Option<String>,
+
+    /// The number of seconds to wait for the Portmaster API to answer a
+    /// keepalive ping before reconnecting. Defaults to 10.
+    pub keepalive_timeout: Option<u64>,
+}
+
+/// Returns the value of the --<name> cli argument.
+fn cli_arg<R: Runtime>(app: &AppHandle<R>, name: &str) -> Option<String> {
+    let matches = app.cli().matches().ok()?;
+
+    matches.args.get(name)?.value.as_str().map(String::from)
+}
+
+/// Returns the Portmaster data directory passed using --data or the default
+/// installation directory.
+fn data_dir<R: Runtime>(app: &AppHandle<R>) -> PathBuf {
+    if let Some(dir) = cli_arg(app, "data") {
+        return PathBuf::from(dir);
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        std::env::var_os("ProgramData")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("C:\\ProgramData"))
+            .join("Safing")
+            .join("Portmaster")
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    PathBuf::from("/opt/safing/portmaster")
+}
+
+/// Returns the credentials passed using --api-key or, if not set, the credentials
+/// stored in the api-key file of the data directory.
+fn load_credentials<R: Runtime>(app: &AppHandle<R>) -> Option<Credentials> {
+    if let Some(flag) = cli_arg(app, "api-key") {
+        return match flag.parse() {
+            Ok(credentials) => Some(credentials),
+            Err(err) => {
+                error!("ignoring invalid --api-key: {}", err);
+                None
+            }
+        };
+    }
+
+    let path = data_dir(app).join(CREDENTIALS_FILE);
+
+    match Credentials::from_file(&path) {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            error!("failed to read credentials from {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Returns the first valid value of the --<flag> cli argument, the env environment
+/// variable and the plugin configuration.
+fn resolve<R: Runtime, T>(
+    app: &AppHandle<R>,
+    flag: &str,
+    env: &str,
+    config: Option<&String>,
+) -> Option<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let flag = cli_arg(app, flag);
+    let env_value = std::env::var(env).ok();
+
+    [flag, env_value, config.cloned()]
+        .into_iter()
+        .flatten()
+        .find_map(|value| match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                error!("ignoring invalid URL {:?}: {}", value, err);
+                None
+            }
+        })
+}
 
 impl<R: Runtime, T: Manager<R>> PortmasterExt<R> for T {
     fn portmaster(&self) -> &PortmasterPlugin<R> {
@@ -269,18 +509,74 @@ pub fn init<R: Runtime>() -> TauriPlugin<R, Option<Config>> {
             commands::get_state,
             commands::set_state,
             commands::should_show,
-            commands::should_handle_prompts
+            commands::should_handle_prompts,
+            commands::take_pending_navigation,
+            commands::is_auth_required,
+            commands::shutdown_portmaster,
+            commands::restart_portmaster,
+            commands::get_debug_info
         ])
-        .setup(|app, _api| {
+        .setup(|app, api| {
+            let config = api.config().as_ref();
+
+            let mut endpoint: Endpoint = resolve(
+                app,
+                "api-url",
+                API_URL_ENV,
+                config.and_then(|c| c.api_url.as_ref()),
+            )
+            .unwrap_or_default();
+
+            let ui_url = resolve(
+                app,
+                "ui-url",
+                UI_URL_ENV,
+                config.and_then(|c| c.ui_url.as_ref()),
+            );
+
+            let mut credentials = load_credentials(app);
+
+            // serve a recorded session instead of connecting to the Portmaster API.
+            if let Some(path) = cli_arg(app, "replay") {
+                let server = ReplayServer::start(read_recording(Path::new(&path))?)?;
+
+                info!("replaying the PortAPI session recorded in {}", path);
+
+                endpoint = server.base_url().parse()?;
+                credentials = None;
+            }
+
+            let record_path = cli_arg(app, "record").map(PathBuf::from);
+
+            debug!(
+                "using portmaster API at {} (credentials: {:?})",
+                endpoint, credentials
+            );
+
+            let http = HttpClient::new(endpoint.clone(), credentials.clone())?;
+
+            let mut keepalive = Keepalive::default();
+            if let Some(secs) = config.and_then(|c| c.keepalive_timeout) {
+                keepalive.timeout = Duration::from_secs(secs);
+            }
+
             let plugin = PortmasterPlugin {
                 app: app.clone(),
+                endpoint,
+                ui_url,
+                credentials,
+                record_path,
+                auth_required: AtomicBool::new(false),
+                http,
                 state: Mutex::new(HashMap::new()),
-                is_reachable: AtomicBool::new(false),
+                connection_state: watch::channel(ConnectionState::Connecting).0,
+                keepalive,
                 handlers: Mutex::new(Vec::new()),
                 api: Mutex::new(None),
                 handle_notifications: AtomicBool::new(false),
                 handle_prompts: AtomicBool::new(false),
                 should_show_after_bootstrap: AtomicBool::new(true),
+                pending_navigation: Mutex::new(None),
             };
 
             app.manage(plugin);
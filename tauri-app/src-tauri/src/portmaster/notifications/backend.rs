@@ -0,0 +1,580 @@
+use crate::portapi::models::notification::{Notification, NotificationType};
+use tauri::{AppHandle, Runtime};
+#[cfg(not(target_os = "linux"))]
+use tauri_plugin_notification::NotificationExt;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+#[cfg(target_os = "linux")]
+use log::{error, warn};
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::sync::mpsc::{channel, Receiver, Sender};
+#[cfg(target_os = "linux")]
+use std::thread;
+
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[cfg(target_os = "linux")]
+    #[error("notification server failed: {0}")]
+    Server(#[from] notify_rust::error::Error),
+
+    #[error("notification plugin failed: {0}")]
+    Plugin(#[from] tauri_plugin_notification::Error),
+}
+
+/// The features supported by a backend and the notification server behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    /// Popups can have buttons for the notification actions.
+    pub actions: bool,
+
+    /// The body is interpreted as markup, e.g. `<b>`, and must be escaped.
+    pub body_markup: bool,
+
+    /// Popups are kept, e.g. in a notification center, once they time out.
+    pub persistence: bool,
+}
+
+impl Capabilities {
+    /// Parses the capabilities reported by a freedesktop notification server.
+    pub fn from_server(capabilities: &[String]) -> Self {
+        let has = |name: &str| capabilities.iter().any(|c| c == name);
+
+        Capabilities {
+            actions: has("actions"),
+            body_markup: has("body-markup"),
+            persistence: has("persistence"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl From<NotificationType> for Urgency {
+    fn from(value: NotificationType) -> Self {
+        match value {
+            NotificationType::Info => Urgency::Low,
+            NotificationType::Warning | NotificationType::Unknown(_) => Urgency::Normal,
+            // prompts wait for the user, critical popups don't time out.
+            NotificationType::Prompt | NotificationType::Error => Urgency::Critical,
+        }
+    }
+}
+
+/// The content of a popup as shown by a backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Popup {
+    pub title: String,
+
+    pub body: String,
+
+    pub urgency: Urgency,
+
+    /// The buttons of the popup as (action ID, text) pairs.
+    pub actions: Vec<(String, String)>,
+
+    /// Whether the popup stays on screen until it is closed.
+    pub sticky: bool,
+}
+
+impl Popup {
+    /// Returns the popup for n, leaving out what is not supported by a backend with
+    /// capabilities.
+    pub fn new(n: &Notification, capabilities: Capabilities) -> Self {
+        let body = if capabilities.body_markup {
+            escape_markup(&n.message)
+        } else {
+            n.message.clone()
+        };
+
+        let actions = if capabilities.actions {
+            n.actions
+                .iter()
+                .map(|a| (a.id.clone(), a.text.clone()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Popup {
+            title: n.title.clone(),
+            body,
+            urgency: n.notification_type.into(),
+            actions,
+            // popups that time out are lost unless the server keeps them.
+            sticky: needs_answer(n) || !capabilities.persistence,
+        }
+    }
+}
+
+/// Reports whether the user is expected to answer n by selecting one of its
+/// actions.
+pub fn needs_answer(n: &Notification) -> bool {
+    n.notification_type == NotificationType::Prompt && !n.actions.is_empty()
+}
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// User interaction with a popup.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PopupEvent {
+    /// The user clicked the button of action_id.
+    Invoked { id: u32, action_id: String },
+
+    /// The popup has been closed, either by the user, the notification server or
+    /// by the backend.
+    Closed { id: u32 },
+
+    /// The popup could not be shown. Only reported by backends that show popups
+    /// in the background.
+    #[cfg(target_os = "linux")]
+    Failed { id: u32 },
+}
+
+impl PopupEvent {
+    /// Returns the ID of the popup the event belongs to.
+    pub fn id(&self) -> u32 {
+        match self {
+            PopupEvent::Invoked { id, .. } | PopupEvent::Closed { id } => *id,
+            #[cfg(target_os = "linux")]
+            PopupEvent::Failed { id } => *id,
+        }
+    }
+}
+
+/// NotificationBackend displays notifications as popups of the operating system.
+pub trait NotificationBackend: Send {
+    fn capabilities(&self) -> Capabilities;
+
+    /// Shows popup and returns its ID. Backends that support actions report
+    /// interactions as PopupEvent.
+    fn show(&mut self, popup: &Popup) -> Result<u32, BackendError>;
+
+    /// Replaces the content of the popup id.
+    fn update(&mut self, id: u32, popup: &Popup) -> Result<(), BackendError>;
+
+    /// Closes the popup id. Does nothing if it is already closed.
+    fn close(&mut self, id: u32);
+}
+
+/// Returns the backend for the current platform. Interactions with popups are
+/// sent to events. This may query the notification server and block, use it
+/// from a blocking context.
+#[cfg(target_os = "linux")]
+pub fn default_backend<R: Runtime>(
+    _app: &AppHandle<R>,
+    events: UnboundedSender<PopupEvent>,
+) -> Box<dyn NotificationBackend> {
+    Box::new(NotifyRustBackend::new(events))
+}
+
+/// Returns the backend for the current platform. Interactions with popups are
+/// sent to events.
+#[cfg(not(target_os = "linux"))]
+pub fn default_backend<R: Runtime>(
+    app: &AppHandle<R>,
+    events: UnboundedSender<PopupEvent>,
+) -> Box<dyn NotificationBackend> {
+    Box::new(TauriBackend::new(app.clone(), events))
+}
+
+/// Shows popups using a freedesktop notification server. All D-Bus calls are
+/// made by a worker thread so showing, updating and closing popups never blocks.
+#[cfg(target_os = "linux")]
+pub struct NotifyRustBackend {
+    capabilities: Capabilities,
+    next_id: u32,
+    worker: Sender<WorkerMessage>,
+}
+
+/// The messages handled by the worker of a `NotifyRustBackend`. Popups are
+/// identified by the IDs handed out by the backend, signals carry the IDs
+/// assigned by the notification server.
+#[cfg(target_os = "linux")]
+enum WorkerMessage {
+    Show(u32, Popup),
+    Update(u32, Popup),
+    Close(u32),
+    Signal(PopupEvent),
+}
+
+#[cfg(target_os = "linux")]
+impl NotifyRustBackend {
+    /// Queries the capabilities of the notification server and starts the worker
+    /// and signal listener threads. Blocks until the server answered.
+    pub fn new(events: UnboundedSender<PopupEvent>) -> Self {
+        let capabilities = match notify_rust::get_capabilities() {
+            Ok(capabilities) => Capabilities::from_server(&capabilities),
+            Err(err) => {
+                warn!("failed to get notification server capabilities: {}", err);
+
+                Capabilities::default()
+            }
+        };
+
+        let (worker, messages) = channel();
+
+        thread::spawn(move || run_worker(messages, events));
+
+        let signals = worker.clone();
+        thread::spawn(move || {
+            if let Err(err) = listen_for_signals(signals) {
+                error!("failed to listen for notification signals: {}", err);
+            }
+        });
+
+        NotifyRustBackend {
+            capabilities,
+            next_id: 0,
+            worker,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NotificationBackend for NotifyRustBackend {
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn show(&mut self, popup: &Popup) -> Result<u32, BackendError> {
+        self.next_id += 1;
+
+        // errors are reported as PopupEvent::Failed once the server answered.
+        let _ = self
+            .worker
+            .send(WorkerMessage::Show(self.next_id, popup.clone()));
+
+        Ok(self.next_id)
+    }
+
+    fn update(&mut self, id: u32, popup: &Popup) -> Result<(), BackendError> {
+        let _ = self.worker.send(WorkerMessage::Update(id, popup.clone()));
+
+        Ok(())
+    }
+
+    fn close(&mut self, id: u32) {
+        let _ = self.worker.send(WorkerMessage::Close(id));
+    }
+}
+
+/// Shows, updates and closes popups as requested by messages and translates the
+/// signals of the notification server into events. Returns once all senders are
+/// gone.
+#[cfg(target_os = "linux")]
+fn run_worker(messages: Receiver<WorkerMessage>, events: UnboundedSender<PopupEvent>) {
+    let mut popups: HashMap<u32, notify_rust::NotificationHandle> = HashMap::new();
+
+    for message in messages {
+        match message {
+            WorkerMessage::Show(id, popup) => {
+                let mut notif = notify_rust::Notification::new();
+                notif.icon("portmaster");
+                set_content(&mut notif, &popup);
+
+                match notif.show() {
+                    Ok(handle) => {
+                        popups.insert(id, handle);
+                    }
+                    Err(err) => {
+                        error!("failed to show popup: {}", err);
+
+                        let _ = events.send(PopupEvent::Failed { id });
+                    }
+                }
+            }
+            WorkerMessage::Update(id, popup) => {
+                if let Some(handle) = popups.get_mut(&id) {
+                    handle.actions.clear();
+                    set_content(handle, &popup);
+                    handle.update();
+                }
+            }
+            WorkerMessage::Close(id) => {
+                if let Some(handle) = popups.remove(&id) {
+                    handle.close();
+                }
+            }
+            WorkerMessage::Signal(event) => {
+                let server_id = event.id();
+
+                // signals of popups shown by other applications are ignored.
+                let id = match popups.iter().find(|(_, h)| h.id() == server_id) {
+                    Some((id, _)) => *id,
+                    None => continue,
+                };
+
+                let event = match event {
+                    PopupEvent::Invoked { action_id, .. } => PopupEvent::Invoked { id, action_id },
+                    _ => {
+                        popups.remove(&id);
+
+                        PopupEvent::Closed { id }
+                    }
+                };
+
+                let _ = events.send(event);
+            }
+        }
+    }
+}
+
+/// Forwards the `ActionInvoked` and `NotificationClosed` signals of all popups
+/// to worker. Returns once the worker is gone and the next signal arrived.
+#[cfg(target_os = "linux")]
+fn listen_for_signals(worker: Sender<WorkerMessage>) -> zbus::Result<()> {
+    let connection = zbus::blocking::Connection::session()?;
+    let proxy = zbus::blocking::fdo::DBusProxy::new(&connection)?;
+
+    for member in ["ActionInvoked", "NotificationClosed"] {
+        let rule = zbus::MatchRule::builder()
+            .msg_type(zbus::MessageType::Signal)
+            .interface("org.freedesktop.Notifications")?
+            .member(member)?
+            .build();
+
+        proxy.add_match_rule(rule)?;
+    }
+
+    for msg in zbus::blocking::MessageIterator::from(&connection) {
+        let msg = msg?;
+        let header = msg.header()?;
+
+        if header.message_type()? != zbus::MessageType::Signal {
+            continue;
+        }
+
+        let event = match header.member()? {
+            Some(name) if name == "ActionInvoked" => match msg.body::<(u32, String)>() {
+                Ok((id, action_id)) => PopupEvent::Invoked { id, action_id },
+                Err(_) => continue,
+            },
+            Some(name) if name == "NotificationClosed" => match msg.body::<(u32, u32)>() {
+                Ok((id, _reason)) => PopupEvent::Closed { id },
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+
+        if worker.send(WorkerMessage::Signal(event)).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_content(notif: &mut notify_rust::Notification, popup: &Popup) {
+    notif.summary(&popup.title);
+    notif.body(&popup.body);
+
+    notif.urgency(match popup.urgency {
+        Urgency::Low => notify_rust::Urgency::Low,
+        Urgency::Normal => notify_rust::Urgency::Normal,
+        Urgency::Critical => notify_rust::Urgency::Critical,
+    });
+
+    notif.timeout(if popup.sticky {
+        notify_rust::Timeout::Never
+    } else {
+        notify_rust::Timeout::Default
+    });
+
+    for (id, text) in &popup.actions {
+        notif.action(id, text);
+    }
+}
+
+/// Shows popups using tauri-plugin-notification. The plugin neither supports
+/// actions nor updating or closing popups once they are shown, so popups are
+/// reported as closed right away.
+#[cfg(not(target_os = "linux"))]
+pub struct TauriBackend<R: Runtime> {
+    app: AppHandle<R>,
+    next_id: u32,
+    events: UnboundedSender<PopupEvent>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<R: Runtime> TauriBackend<R> {
+    pub fn new(app: AppHandle<R>, events: UnboundedSender<PopupEvent>) -> Self {
+        TauriBackend {
+            app,
+            next_id: 0,
+            events,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<R: Runtime> NotificationBackend for TauriBackend<R> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    fn show(&mut self, popup: &Popup) -> Result<u32, BackendError> {
+        self.app
+            .notification()
+            .builder()
+            .title(&popup.title)
+            .body(&popup.body)
+            .show()?;
+
+        self.next_id += 1;
+
+        // the popup is out of reach once shown.
+        let _ = self.events.send(PopupEvent::Closed { id: self.next_id });
+
+        Ok(self.next_id)
+    }
+
+    fn update(&mut self, _id: u32, _popup: &Popup) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn close(&mut self, _id: u32) {}
+}
+
+/// Keeps popups in memory so tests can inspect them. Clones share the popups.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MemoryBackend {
+    pub capabilities: Capabilities,
+    state: std::sync::Arc<std::sync::Mutex<MemoryState>>,
+
+    // notified whenever a popup is shown, updated or closed.
+    changed: std::sync::Arc<tokio::sync::watch::Sender<()>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MemoryState {
+    next_id: u32,
+    popups: std::collections::BTreeMap<u32, Popup>,
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    pub fn new(capabilities: Capabilities) -> Self {
+        MemoryBackend {
+            capabilities,
+            state: Default::default(),
+            changed: std::sync::Arc::new(tokio::sync::watch::channel(()).0),
+        }
+    }
+
+    /// Returns the popups that are currently open.
+    pub fn popups(&self) -> std::collections::BTreeMap<u32, Popup> {
+        self.state.lock().unwrap().popups.clone()
+    }
+
+    /// Waits until f reports true for the popups that are currently open.
+    pub async fn wait_for<F>(&self, f: F)
+    where
+        F: Fn(&std::collections::BTreeMap<u32, Popup>) -> bool,
+    {
+        let mut changed = self.changed.subscribe();
+
+        while !f(&self.popups()) {
+            changed.changed().await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+impl NotificationBackend for MemoryBackend {
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn show(&mut self, popup: &Popup) -> Result<u32, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+
+        let id = state.next_id;
+        state.popups.insert(id, popup.clone());
+        self.changed.send_replace(());
+
+        Ok(id)
+    }
+
+    fn update(&mut self, id: u32, popup: &Popup) -> Result<(), BackendError> {
+        if let Some(existing) = self.state.lock().unwrap().popups.get_mut(&id) {
+            *existing = popup.clone();
+        }
+        self.changed.send_replace(());
+
+        Ok(())
+    }
+
+    fn close(&mut self, id: u32) {
+        self.state.lock().unwrap().popups.remove(&id);
+        self.changed.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn popup_content() {
+        let n: Notification = serde_json::from_value(json!({
+            "EventID": "filter:prompt", "GUID": "", "Type": 2, "Message": "Allow <example.com>?",
+            "Title": "Connection", "Category": "", "EventData": null, "Expires": 0, "State": "active",
+            "AvailableActions": [{"ID": "permit", "Text": "Allow", "Type": ""}],
+            "SelectedActionID": "", "ShowOnSystem": true,
+        }))
+        .unwrap();
+
+        let capabilities = Capabilities::from_server(&[
+            "actions".to_string(),
+            "body".to_string(),
+            "body-markup".to_string(),
+        ]);
+        assert!(capabilities.actions && capabilities.body_markup && !capabilities.persistence);
+
+        let popup = Popup::new(&n, capabilities);
+        assert_eq!(popup.body, "Allow &lt;example.com&gt;?");
+        assert_eq!(popup.urgency, Urgency::Critical);
+        assert_eq!(
+            popup.actions,
+            vec![("permit".to_string(), "Allow".to_string())]
+        );
+        assert!(popup.sticky);
+
+        let popup = Popup::new(&n, Capabilities::default());
+        assert_eq!(popup.body, "Allow <example.com>?");
+        assert!(popup.actions.is_empty());
+
+        let info = Notification {
+            notification_type: NotificationType::Info,
+            ..n
+        };
+        assert!(!needs_answer(&info));
+
+        let popup = Popup::new(
+            &info,
+            Capabilities {
+                persistence: true,
+                ..capabilities
+            },
+        );
+        assert_eq!(popup.urgency, Urgency::Low);
+        assert!(!popup.sticky);
+    }
+}
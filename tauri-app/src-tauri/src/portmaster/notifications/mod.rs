@@ -0,0 +1,857 @@
+mod backend;
+
+use super::{Navigation, PortmasterExt};
+use crate::portapi::client::*;
+use crate::portapi::http::HttpError;
+use crate::portapi::key::DbKey;
+use crate::portapi::message::*;
+use crate::portapi::models::notification::*;
+use crate::portapi::query::Query;
+use crate::portapi::subscription::*;
+use crate::portapi::types::*;
+use backend::*;
+use futures_util::StreamExt;
+use log::{debug, error, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::async_runtime;
+use tauri::{AppHandle, Runtime};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_shell::ShellExt;
+use thiserror::Error;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Error)]
+pub enum ActionError {
+    #[error("failed to open URL: {0}")]
+    Open(#[from] tauri_plugin_shell::Error),
+
+    #[error("webhook failed: {0}")]
+    Webhook(#[from] HttpError),
+
+    #[error("invalid webhook method {0:?}")]
+    Method(String),
+
+    #[error("unsupported page {0:?}")]
+    UnknownPage(String),
+
+    #[error("action type {0:?} is not supported")]
+    Unsupported(String),
+}
+
+/// Work the notification handler does on behalf of the manager.
+#[derive(Debug, PartialEq)]
+enum Effect {
+    /// Execute the action of the notification stored at key.
+    Execute(DbKey, Action),
+
+    /// Open the user interface on the route where the user can answer a
+    /// notification.
+    OpenUI(Navigation),
+}
+
+/// A notification that has been shown as a system notification.
+struct SystemNotification {
+    key: DbKey,
+
+    notification: Notification,
+
+    // the popup of the notification, None once the user dismissed it. The entry
+    // is kept so updates and replays after a reconnect don't show it again.
+    popup: Option<u32>,
+
+    // whether the notification has been replayed since the last reconnect.
+    // Notifications that are not replayed have been deleted in the meantime.
+    replayed: bool,
+}
+
+/// Keeps track of the system notifications shown for Portmaster notifications,
+/// keyed by EventID, and keeps them in sync with their records.
+struct NotificationManager {
+    backend: Box<dyn NotificationBackend>,
+    capabilities: Capabilities,
+    open: HashMap<String, SystemNotification>,
+
+    // whether connection prompts are left to the prompt window of the user
+    // interface, see `with_connection_prompts`.
+    skip_connection_prompts: bool,
+
+    // receives the EventID and expiry of notifications once they expire.
+    expired: UnboundedSender<(String, u64)>,
+}
+
+pub async fn notification_handler<R: Runtime>(app: AppHandle<R>, cli: PortAPI) {
+    let (events_tx, events) = unbounded_channel();
+
+    let handle = app.clone();
+    let backend =
+        match async_runtime::spawn_blocking(move || default_backend(&handle, events_tx)).await {
+            Ok(backend) => backend,
+            Err(err) => {
+                error!("failed to create notification backend: {}", err);
+                return;
+            }
+        };
+
+    let skip_connection_prompts = app.portmaster().handle_prompts.load(Ordering::Relaxed);
+
+    run_manager(
+        &cli,
+        backend,
+        skip_connection_prompts,
+        events,
+        |effect| match effect {
+            Effect::Execute(key, action) => {
+                let app = app.clone();
+                let cli = cli.clone();
+
+                async_runtime::spawn(async move {
+                    execute_action(&app, &cli, key, action).await;
+                });
+            }
+            Effect::OpenUI(navigation) => app.portmaster().navigate(navigation),
+        },
+    )
+    .await;
+}
+
+/// Shows the notifications of cli using backend until the subscription ends.
+/// Connection prompts are not shown if skip_connection_prompts is set.
+/// Interactions with popups are received from events, the resulting effects are
+/// passed to f.
+async fn run_manager<F: FnMut(Effect)>(
+    cli: &PortAPI,
+    backend: Box<dyn NotificationBackend>,
+    skip_connection_prompts: bool,
+    mut events: UnboundedReceiver<PopupEvent>,
+    mut f: F,
+) {
+    let res = cli
+        .subscribe::<Notification>(Query::new("notifications:"))
+        .await;
+
+    let mut sub = match res {
+        Ok(sub) => sub,
+        Err(err) => {
+            error!("failed to subscribe to notifications: {}", err);
+            return;
+        }
+    };
+
+    let (expired_tx, mut expired) = unbounded_channel();
+
+    let mut manager = NotificationManager::new(backend, skip_connection_prompts, expired_tx);
+
+    loop {
+        let effect = tokio::select! {
+            event = sub.next() => match event {
+                Some(event) => manager.handle_event(event),
+                None => break,
+            },
+            Some(event) = events.recv() => manager.handle_popup_event(event),
+            Some((event_id, expires)) = expired.recv() => {
+                manager.handle_expired(&event_id, expires);
+                None
+            }
+        };
+
+        if let Some(effect) = effect {
+            f(effect);
+        }
+    }
+
+    manager.close_all();
+}
+
+impl NotificationManager {
+    fn new(
+        backend: Box<dyn NotificationBackend>,
+        skip_connection_prompts: bool,
+        expired: UnboundedSender<(String, u64)>,
+    ) -> Self {
+        NotificationManager {
+            capabilities: backend.capabilities(),
+            backend,
+            open: HashMap::new(),
+            skip_connection_prompts,
+            expired,
+        }
+    }
+
+    fn handle_event(&mut self, event: RecordEvent<Notification>) -> Option<Effect> {
+        match event {
+            RecordEvent::Initial(record, n)
+            | RecordEvent::Created(record, n)
+            | RecordEvent::Updated(record, n) => {
+                if !record.is_valid() || is_expired(n.expires) {
+                    debug!("notification {} is stale", record.key);
+                    self.close(&n.event_id);
+                } else if !should_show(&n)
+                    || (self.skip_connection_prompts && is_connection_prompt(&n))
+                {
+                    self.close(&n.event_id);
+                } else {
+                    return self.show(record.key, n);
+                }
+            }
+            RecordEvent::Deleted(key) => {
+                debug!("notification {} deleted", key);
+                self.close(event_id(&key));
+            }
+            RecordEvent::Reconnected => {
+                for entry in self.open.values_mut() {
+                    entry.replayed = false;
+                }
+            }
+            RecordEvent::SnapshotDone => {
+                let deleted: Vec<String> = self
+                    .open
+                    .iter()
+                    .filter(|(_, entry)| !entry.replayed)
+                    .map(|(event_id, _)| event_id.clone())
+                    .collect();
+
+                for event_id in deleted {
+                    self.close(&event_id);
+                }
+            }
+            RecordEvent::Warning(msg) => {
+                warn!("notification subscription: {}", msg);
+            }
+            RecordEvent::Error(err) => {
+                error!("notification subscription: {}", err);
+            }
+        }
+
+        None
+    }
+
+    fn handle_popup_event(&mut self, event: PopupEvent) -> Option<Effect> {
+        let id = event.id();
+
+        let entry = self.open.values_mut().find(|e| e.popup == Some(id))?;
+
+        // the popup is gone in both cases, let the backend forget about it.
+        entry.popup = None;
+        self.backend.close(id);
+
+        match event {
+            PopupEvent::Invoked { action_id, .. } => {
+                match entry
+                    .notification
+                    .actions
+                    .iter()
+                    .find(|a| a.id == action_id)
+                {
+                    Some(action) => Some(Effect::Execute(entry.key.clone(), action.clone())),
+                    None => {
+                        warn!("notification {}: unknown action {}", entry.key, action_id);
+                        None
+                    }
+                }
+            }
+            PopupEvent::Closed { .. } => {
+                debug!("notification {} dismissed", entry.key);
+                None
+            }
+            #[cfg(target_os = "linux")]
+            PopupEvent::Failed { .. } => {
+                // the user cannot answer without a popup so open the user interface.
+                needs_answer(&entry.notification)
+                    .then(|| Effect::OpenUI(answer_route(&entry.notification)))
+            }
+        }
+    }
+
+    fn handle_expired(&mut self, event_id: &str, expires: u64) {
+        let current = self.open.get(event_id).map(|e| e.notification.expires);
+
+        // the expiry might have been changed by an update.
+        if current == Some(expires) {
+            self.close(event_id);
+        }
+    }
+
+    /// Shows n or updates the popup if it is already shown.
+    fn show(&mut self, key: DbKey, n: Notification) -> Option<Effect> {
+        if let Some(entry) = self.open.get_mut(&n.event_id) {
+            entry.replayed = true;
+            entry.key = key;
+
+            if entry.notification == n {
+                return None;
+            }
+
+            if entry.notification.expires != n.expires {
+                schedule_expiry(&self.expired, &n.event_id, n.expires);
+            }
+
+            if let Some(id) = entry.popup {
+                if let Err(err) = self.backend.update(id, &Popup::new(&n, self.capabilities)) {
+                    error!("failed to update notification {}: {}", entry.key, err);
+                }
+            }
+
+            entry.notification = n;
+
+            return None;
+        }
+
+        let popup = match self.backend.show(&Popup::new(&n, self.capabilities)) {
+            Ok(id) => Some(id),
+            Err(err) => {
+                error!("failed to display notification {}: {}", key, err);
+                None
+            }
+        };
+
+        // the user cannot answer without buttons so open the user interface.
+        let effect = (needs_answer(&n) && (popup.is_none() || !self.capabilities.actions))
+            .then(|| Effect::OpenUI(answer_route(&n)));
+
+        schedule_expiry(&self.expired, &n.event_id, n.expires);
+
+        self.open.insert(
+            n.event_id.clone(),
+            SystemNotification {
+                key,
+                notification: n,
+                popup,
+                replayed: true,
+            },
+        );
+
+        effect
+    }
+
+    /// Closes the popup of the notification event_id and forgets about it.
+    fn close(&mut self, event_id: &str) {
+        if let Some(SystemNotification {
+            popup: Some(id), ..
+        }) = self.open.remove(event_id)
+        {
+            self.backend.close(id);
+        }
+    }
+
+    fn close_all(&mut self) {
+        for (_, entry) in self.open.drain() {
+            if let Some(id) = entry.popup {
+                self.backend.close(id);
+            }
+        }
+    }
+}
+
+/// Reports whether n should be shown as a system notification.
+fn should_show(n: &Notification) -> bool {
+    n.show_on_system && n.selected_action_id.is_empty() && n.state == State::Active
+}
+
+/// Reports whether n asks the user to allow or block a connection.
+fn is_connection_prompt(n: &Notification) -> bool {
+    n.event_id.starts_with("filter:prompt")
+}
+
+/// Returns the route of the user interface where n can be answered. Connection
+/// prompts are answered on the page of the app that made the connection, all
+/// other notifications on the dashboard.
+fn answer_route(n: &Notification) -> Navigation {
+    let profile = &n.data["Profile"];
+
+    let path = match (profile["Source"].as_str(), profile["ID"].as_str()) {
+        (Some(source), Some(id)) if is_connection_prompt(n) => format!("/app/{}/{}", source, id),
+        _ => "/dashboard".to_string(),
+    };
+
+    Navigation {
+        path,
+        query: HashMap::new(),
+    }
+}
+
+/// Returns the EventID of the notification stored at key.
+fn event_id(key: &DbKey) -> &str {
+    key.path().strip_prefix("all/").unwrap_or(key.path())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn is_expired(expires: u64) -> bool {
+    expires > 0 && expires <= unix_now()
+}
+
+/// Sends event_id to expired once expires, a unix timestamp, passed.
+fn schedule_expiry(expired: &UnboundedSender<(String, u64)>, event_id: &str, expires: u64) {
+    if expires == 0 {
+        return;
+    }
+
+    let expired = expired.clone();
+    let event_id = event_id.to_string();
+
+    async_runtime::spawn(async move {
+        let delay = Duration::from_secs(expires.saturating_sub(unix_now()));
+        tokio::time::sleep(delay).await;
+
+        let _ = expired.send((event_id, expires));
+    });
+}
+
+/// Performs action and reports the result back to the core, see `report_action`.
+async fn execute_action<R: Runtime>(app: &AppHandle<R>, cli: &PortAPI, key: DbKey, action: Action) {
+    let result = perform_action(app, &action).await;
+
+    if let Err(err) = &result {
+        error!(
+            "notification {}: failed to perform action {}: {}",
+            key, action.id, err
+        );
+    }
+
+    if let Err(err) = report_action(cli, &key, &action, result).await {
+        error!(
+            "notification {}: failed to report action {}: {}",
+            key, action.id, err
+        );
+    }
+}
+
+/// Reports the result of performing action for the notification stored at key.
+/// Successful actions are selected on the notification. Failures are reported as
+/// an error notification instead, so the original notification stays active and
+/// the user can retry from within the user interface.
+async fn report_action(
+    cli: &PortAPI,
+    key: &DbKey,
+    action: &Action,
+    result: Result<(), ActionError>,
+) -> Result<(), ClientError> {
+    let timeout = Duration::from_secs(5);
+
+    let err = match result {
+        Ok(()) if action.id.is_empty() => return Ok(()),
+        Ok(()) => {
+            // only SelectedActionID is sent so other fields are not overwritten.
+            let payload = Payload::JSON(json!({ "SelectedActionID": action.id }).to_string());
+
+            return cli
+                .expect_success(Request::Insert(key.clone(), payload), timeout)
+                .await;
+        }
+        Err(err) => err,
+    };
+
+    let failure = failure_notification(event_id(key), action, &err);
+    let failure_key = DbKey::new("notifications", format!("all/{}", failure.event_id))?;
+    let payload = Payload::encode(&failure, Format::JSON)?;
+
+    // the action might have failed before. Portbase only applies the selected action
+    // when an existing notification is written, so the previous report is deleted
+    // to replace its message.
+    match cli
+        .expect_success(Request::Delete(failure_key.clone()), timeout)
+        .await
+    {
+        Ok(()) | Err(ClientError::NotFound) => {}
+        Err(err) => return Err(err),
+    }
+
+    cli.expect_success(Request::Create(failure_key, payload), timeout)
+        .await
+}
+
+/// Returns the error notification that reports that action of the notification
+/// event_id failed.
+fn failure_notification(event_id: &str, action: &Action, err: &ActionError) -> Notification {
+    Notification {
+        event_id: format!("{}:action-failed", event_id),
+        guid: String::new(),
+        notification_type: NotificationType::Error,
+        message: format!("\"{}\" failed: {}", action.text, err),
+        title: "Action failed".to_string(),
+        category: String::new(),
+        data: json!({ "EventID": event_id, "ActionID": action.id }),
+        expires: 0,
+        state: State::Active,
+        actions: Vec::new(),
+        selected_action_id: String::new(),
+        show_on_system: true,
+    }
+}
+
+/// Performs the native part of action. Actions that navigate inside the user
+/// interface open the main window on the matching route.
+async fn perform_action<R: Runtime>(
+    app: &AppHandle<R>,
+    action: &Action,
+) -> Result<(), ActionError> {
+    match &action.action_type {
+        ActionType::None => Ok(()),
+        ActionType::OpenURL(url) => Ok(app.shell().open(url, None)?),
+        ActionType::Webhook(webhook) => call_webhook(app, webhook).await,
+        action_type => match ui_route(action_type)? {
+            Some(navigation) => {
+                app.portmaster().navigate(navigation);
+
+                Ok(())
+            }
+            None => Err(ActionError::Unsupported(action_type.name().to_string())),
+        },
+    }
+}
+
+/// Calls the webhook of a `call-webhook` action using the HTTP API client. The
+/// response is shown as a notification if requested by the webhook.
+async fn call_webhook<R: Runtime>(
+    app: &AppHandle<R>,
+    webhook: &WebhookPayload,
+) -> Result<(), ActionError> {
+    let method = match webhook.method.as_str() {
+        "" if webhook.payload.is_null() => reqwest::Method::POST,
+        "" => reqwest::Method::PUT,
+        method => reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| ActionError::Method(method.to_string()))?,
+    };
+
+    let body = (!webhook.payload.is_null()).then_some(&webhook.payload);
+
+    let result = app
+        .portmaster()
+        .http()
+        .call_with_body(method, &webhook.url, body)
+        .await?;
+
+    if webhook.result_action == "display" && !result.is_empty() {
+        if let Err(err) = app
+            .notification()
+            .builder()
+            .title("Portmaster")
+            .body(&result)
+            .show()
+        {
+            error!("failed to display webhook result: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the route of the user interface an action navigates to, None if the
+/// action does not navigate.
+fn ui_route(action_type: &ActionType) -> Result<Option<Navigation>, ActionError> {
+    let route = |path: &str, query: HashMap<String, String>| {
+        Some(Navigation {
+            path: path.to_string(),
+            query,
+        })
+    };
+
+    let navigation = match action_type {
+        ActionType::OpenPage(page) => {
+            let path = match page.id.as_str() {
+                "monitor" => "/monitor",
+                "support" => "/support",
+                "settings" => "/settings",
+                "apps" => "/app/overview",
+                "spn" => "/spn",
+                other => return Err(ActionError::UnknownPage(other.to_string())),
+            };
+
+            route(path, page.query.clone())
+        }
+        ActionType::OpenSetting(setting) if setting.profile.is_empty() => route(
+            "/settings",
+            HashMap::from([("setting".to_string(), setting.key.clone())]),
+        ),
+        ActionType::OpenSetting(setting) => route(
+            &format!("/app/{}", setting.profile),
+            HashMap::from([
+                ("setting".to_string(), setting.key.clone()),
+                ("tab".to_string(), "settings".to_string()),
+            ]),
+        ),
+        ActionType::OpenProfile(profile) => route(&format!("/app/{}", profile), HashMap::new()),
+        ActionType::Netquery(query) => route(
+            "/monitor",
+            HashMap::from([("q".to_string(), query.clone())]),
+        ),
+        _ => None,
+    };
+
+    Ok(navigation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn created(n: &Notification) -> RecordEvent<Notification> {
+        RecordEvent::Created(record(&n.event_id), n.clone())
+    }
+
+    fn record(event_id: &str) -> Record {
+        Record {
+            created: 0,
+            deleted: 0,
+            expires: 0,
+            modified: 0,
+            key: format!("notifications:all/{}", event_id).parse().unwrap(),
+        }
+    }
+
+    fn notification(event_id: &str) -> Notification {
+        serde_json::from_value(json!({
+            "EventID": event_id, "GUID": "", "Type": 2, "Message": "Allow example.com?",
+            "Title": "Connection", "Category": "", "EventData": null, "Expires": 0, "State": "active",
+            "AvailableActions": [{"ID": "permit", "Text": "Allow", "Type": ""}],
+            "SelectedActionID": "", "ShowOnSystem": true,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn manager() {
+        let backend = MemoryBackend::new(Capabilities {
+            actions: true,
+            ..Default::default()
+        });
+        let (expired, _rx) = unbounded_channel();
+        let mut manager = NotificationManager::new(Box::new(backend.clone()), false, expired);
+
+        let mut first = notification("first");
+        assert_eq!(manager.handle_event(created(&first)), None);
+        assert_eq!(manager.handle_event(created(&notification("second"))), None);
+        assert_eq!(backend.popups().len(), 2);
+
+        // updates are applied in place.
+        first.message = "Allow example.org?".to_string();
+        manager.handle_event(RecordEvent::Updated(record("first"), first.clone()));
+        assert_eq!(backend.popups()[&1].body, "Allow example.org?");
+
+        // replays don't show notifications again and close the deleted ones.
+        manager.handle_event(RecordEvent::Reconnected);
+        manager.handle_event(RecordEvent::Initial(record("first"), first.clone()));
+        manager.handle_event(RecordEvent::SnapshotDone);
+        assert_eq!(backend.popups().keys().collect::<Vec<_>>(), vec![&1]);
+
+        let effect = manager.handle_popup_event(PopupEvent::Invoked {
+            id: 1,
+            action_id: "permit".to_string(),
+        });
+        assert_eq!(
+            effect,
+            Some(Effect::Execute(
+                record("first").key,
+                first.actions[0].clone()
+            ))
+        );
+        assert!(backend.popups().is_empty());
+
+        // answered notifications are not shown again.
+        first.selected_action_id = "permit".to_string();
+        manager.handle_event(RecordEvent::Updated(record("first"), first));
+        assert!(manager.open.is_empty());
+
+        let mut expiring = notification("expiring");
+        expiring.expires = unix_now() + 60;
+        manager.handle_event(created(&expiring));
+        manager.handle_expired("expiring", expiring.expires - 1);
+        assert_eq!(backend.popups().len(), 1);
+        manager.handle_expired("expiring", expiring.expires);
+        assert!(backend.popups().is_empty());
+
+        manager.handle_event(created(&notification("deleted")));
+        manager.handle_event(RecordEvent::Deleted(record("deleted").key));
+        assert!(backend.popups().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fallback_without_actions() {
+        let backend = MemoryBackend::new(Capabilities::default());
+        let (expired, _rx) = unbounded_channel();
+        let mut manager = NotificationManager::new(Box::new(backend.clone()), false, expired);
+
+        let dashboard = Navigation {
+            path: "/dashboard".to_string(),
+            query: HashMap::new(),
+        };
+
+        let prompt = notification("prompt");
+        assert_eq!(
+            manager.handle_event(created(&prompt)),
+            Some(Effect::OpenUI(dashboard))
+        );
+        assert!(backend.popups()[&1].actions.is_empty());
+
+        // the user interface is only opened once.
+        assert_eq!(manager.handle_event(created(&prompt)), None);
+
+        let info = Notification {
+            notification_type: NotificationType::Info,
+            ..notification("info")
+        };
+        assert_eq!(manager.handle_event(created(&info)), None);
+
+        let connection = Notification {
+            data: json!({"Profile": {"Source": "local", "ID": "firefox"}}),
+            ..notification("filter:prompt-42")
+        };
+        assert_eq!(
+            manager.handle_event(created(&connection)),
+            Some(Effect::OpenUI(Navigation {
+                path: "/app/local/firefox".to_string(),
+                query: HashMap::new(),
+            }))
+        );
+    }
+
+    // popups that could not be shown in the background fall back as well.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fallback_on_failed_popups() {
+        let backend = MemoryBackend::new(Capabilities {
+            actions: true,
+            ..Default::default()
+        });
+        let (expired, _rx) = unbounded_channel();
+        let mut manager = NotificationManager::new(Box::new(backend.clone()), false, expired);
+
+        assert_eq!(manager.handle_event(created(&notification("prompt"))), None);
+        assert_eq!(
+            manager.handle_popup_event(PopupEvent::Failed { id: 1 }),
+            Some(Effect::OpenUI(Navigation {
+                path: "/dashboard".to_string(),
+                query: HashMap::new(),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_connection_prompts() {
+        let backend = MemoryBackend::new(Capabilities::default());
+        let (expired, _rx) = unbounded_channel();
+        let mut manager = NotificationManager::new(Box::new(backend.clone()), true, expired);
+
+        // connection prompts are answered in the prompt window.
+        let connection = notification("filter:prompt-42");
+        assert_eq!(manager.handle_event(created(&connection)), None);
+        assert!(backend.popups().is_empty());
+
+        assert!(manager
+            .handle_event(created(&notification("prompt")))
+            .is_some());
+        assert_eq!(backend.popups().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handler() {
+        let server = crate::portapi::mock::MockServer::start().await;
+        let api = connect(server.uri()).await.unwrap();
+
+        let prompt = notification("prompt");
+        server.set("notifications:all/prompt", &prompt);
+
+        let backend = MemoryBackend::new(Capabilities {
+            actions: true,
+            ..Default::default()
+        });
+        let (events, events_rx) = unbounded_channel();
+        let (effects, mut effects_rx) = unbounded_channel();
+
+        let memory = Box::new(backend.clone());
+        tokio::spawn(async move {
+            run_manager(&api, memory, false, events_rx, |effect| {
+                let _ = effects.send(effect);
+            })
+            .await;
+        });
+
+        backend.wait_for(|popups| popups.len() == 1).await;
+        assert_eq!(backend.popups()[&1].title, "Connection");
+
+        events
+            .send(PopupEvent::Invoked {
+                id: 1,
+                action_id: "permit".to_string(),
+            })
+            .unwrap();
+        assert_eq!(
+            effects_rx.recv().await,
+            Some(Effect::Execute(
+                record("prompt").key,
+                prompt.actions[0].clone()
+            ))
+        );
+        backend.wait_for(|popups| popups.is_empty()).await;
+
+        let mut info = Notification {
+            notification_type: NotificationType::Info,
+            ..notification("info")
+        };
+        server.set("notifications:all/info", &info);
+        backend.wait_for(|popups| popups.len() == 1).await;
+
+        info.message = "Updated".to_string();
+        server.set("notifications:all/info", &info);
+        backend
+            .wait_for(|popups| popups.get(&2).is_some_and(|p| p.body == "Updated"))
+            .await;
+
+        server.delete("notifications:all/info");
+        backend.wait_for(|popups| popups.is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn report() {
+        let server = crate::portapi::mock::MockServer::start().await;
+        let api = connect(server.uri()).await.unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let prompt = notification("prompt");
+        server.set("notifications:all/prompt", &prompt);
+        let key = record("prompt").key;
+        let action = &prompt.actions[0];
+
+        let failure_key = "notifications:all/prompt:action-failed".parse().unwrap();
+
+        // a second failure replaces the previous report.
+        for page in ["x", "y"] {
+            report_action(
+                &api,
+                &key,
+                action,
+                Err(ActionError::UnknownPage(page.to_string())),
+            )
+            .await
+            .unwrap();
+
+            let (_, failure) = api
+                .get::<Notification>(&failure_key, timeout)
+                .await
+                .unwrap();
+            assert_eq!(failure.notification_type, NotificationType::Error);
+            assert_eq!(
+                failure.message,
+                format!("\"Allow\" failed: unsupported page \"{}\"", page)
+            );
+            assert!(should_show(&failure) && !needs_answer(&failure));
+        }
+
+        // the failed notification stays active.
+        let (_, n) = api.get::<Notification>(&key, timeout).await.unwrap();
+        assert!(n.selected_action_id.is_empty());
+
+        report_action(&api, &key, action, Ok(())).await.unwrap();
+        let (_, n) = api.get::<Notification>(&key, timeout).await.unwrap();
+        assert_eq!(n.selected_action_id, "permit");
+    }
+}
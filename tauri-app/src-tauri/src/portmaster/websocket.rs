@@ -1,45 +1,102 @@
 use super::PortmasterExt;
-use crate::portapi::client::connect;
+use crate::portapi::client::{connect_resilient, ConnectionState, ReconnectPolicy};
+use crate::portapi::recording::Recorder;
 use log::{debug, error, info, warn};
 use tauri::{AppHandle, Runtime};
-use tokio::time::{sleep, Duration};
 
 /// Starts a backround thread (via tauri::async_runtime) that connects to the Portmaster
 /// Websocket database API.
+///
+/// The client reconnects automatically and keeps all subscriptions alive so on_connect
+/// handlers are only invoked for the first successful connection. Any further connection
+/// changes are reported using on_disconnect and on_reconnect as soon as the connection
+/// state of the client changes.
 pub fn start_websocket_thread<R: Runtime>(app: AppHandle<R>) {
     let app = app.clone();
 
     tauri::async_runtime::spawn(async move {
-        loop {
-            debug!("Trying to connect to websocket endpoint");
+        debug!("Trying to connect to websocket endpoint");
 
-            let api = connect("ws://127.0.0.1:817/api/database/v1").await;
+        let uri = app.portmaster().endpoint().websocket_url();
+        let credentials = app.portmaster().credentials().cloned();
+        let keepalive = Some(app.portmaster().keepalive);
 
-            match api {
-                Ok(cli) => {
-                    let portmaster = app.portmaster();
+        // the recorder is attached before connecting so the recording starts with
+        // the first connection and can be replayed.
+        let recorder = app.portmaster().record_path.as_ref().and_then(|path| {
+            match Recorder::create(path) {
+                Ok(recorder) => {
+                    info!("recording PortAPI session to {}", path.display());
 
-                    info!("Successfully connected to portmaster");
+                    Some(recorder)
+                }
+                Err(err) => {
+                    error!("failed to create {}: {}", path.display(), err);
 
-                    portmaster.on_connect(cli.clone());
+                    None
+                }
+            }
+        });
+
+        let cli = match connect_resilient(
+            uri.as_str(),
+            ReconnectPolicy::default(),
+            credentials,
+            keepalive,
+            recorder,
+        )
+        .await
+        {
+            Ok(cli) => cli,
+            Err(err) => {
+                error!("failed to create portapi client: {}", err);
+
+                app.portmaster().set_connection_state(ConnectionState::Disconnected {
+                    reason: err.to_string(),
+                });
+                app.portmaster().on_disconnect();
+                return;
+            }
+        };
+
+        let mut state = cli.watch_state();
+        let mut has_connected = false;
+        let mut was_connected: Option<bool> = None;
 
-                    while !cli.is_closed() {
-                        let _ = sleep(Duration::from_secs(1)).await;
+        loop {
+            let current = state.borrow_and_update().clone();
+            let is_connected = current == ConnectionState::Connected;
+
+            let portmaster = app.portmaster();
+
+            portmaster.set_auth_required(cli.is_auth_required());
+            portmaster.set_connection_state(current);
+
+            if was_connected != Some(is_connected) {
+                was_connected = Some(is_connected);
+
+                if !is_connected {
+                    if has_connected {
+                        warn!("lost connection to portmaster, reconnecting ....")
                     }
 
                     portmaster.on_disconnect();
+                } else if has_connected {
+                    info!("Successfully reconnected to portmaster");
 
-                    warn!("lost connection to portmaster, retrying ....")
-                }
-                Err(err) => {
-                    error!("failed to create portapi client: {}", err);
-
-                    app.portmaster().on_disconnect();
+                    portmaster.on_reconnect();
+                } else {
+                    info!("Successfully connected to portmaster");
 
-                    // sleep and retry
-                    sleep(Duration::from_secs(2)).await;
+                    has_connected = true;
+                    portmaster.on_connect(cli.clone());
                 }
             }
+
+            // the client stopped reconnecting.
+            if state.changed().await.is_err() {
+                return;
+            }
         }
     });
 }
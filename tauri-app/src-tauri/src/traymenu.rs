@@ -1,27 +1,30 @@
 use std::collections::HashMap;
 use std::sync::Mutex;
 
-use log::{debug, error};
+use futures_util::StreamExt;
+use log::{debug, error, warn};
 use tauri::{
     menu::{
-        CheckMenuItem, CheckMenuItemBuilder, MenuBuilder, MenuItemBuilder, PredefinedMenuItem,
-        SubmenuBuilder,
+        CheckMenuItem, CheckMenuItemBuilder, MenuBuilder, MenuItem, MenuItemBuilder,
+        PredefinedMenuItem, SubmenuBuilder,
     },
     tray::{ClickType, TrayIcon, TrayIconBuilder},
     Icon, Manager, Wry,
 };
 use tauri_plugin_dialog::DialogExt;
+use tokio::sync::watch;
 
 use crate::{
     portapi::{
-        client::PortAPI,
-        message::ParseError,
+        client::{ConnectionState, PortAPI},
+        key::DbKey,
         models::{
             config::BooleanValue,
             spn::SPNStatus,
             subsystem::{self, Subsystem},
         },
-        types::{Request, Response},
+        query::Query,
+        subscription::RecordEvent,
     },
     portmaster::PortmasterExt,
     window::{create_main_window, may_navigate_to_ui, open_window},
@@ -32,17 +35,20 @@ pub type AppIcon = TrayIcon<Wry>;
 lazy_static! {
     // Set once setup_tray_menu executed.
     static ref SPN_BUTTON: Mutex<Option<CheckMenuItem<Wry>>> = Mutex::new(None);
+
+    // Set once setup_tray_menu executed.
+    static ref SPN_STATUS: Mutex<Option<MenuItem<Wry>>> = Mutex::new(None);
 }
 
 // Icons
 //
-const BLUE_ICON: &'static [u8] =
+const BLUE_ICON: &[u8] =
     include_bytes!("../../../notifier/icons/icons/pm_light_blue_512.ico");
-const RED_ICON: &'static [u8] =
+const RED_ICON: &[u8] =
     include_bytes!("../../../notifier/icons/icons/pm_light_red_512.ico");
-const YELLOW_ICON: &'static [u8] =
+const YELLOW_ICON: &[u8] =
     include_bytes!("../../../notifier/icons/icons/pm_light_yellow_512.ico");
-const GREEN_ICON: &'static [u8] =
+const GREEN_ICON: &[u8] =
     include_bytes!("../../../notifier/icons/icons/pm_light_green_512.ico");
 
 pub fn setup_tray_menu(
@@ -50,18 +56,24 @@ pub fn setup_tray_menu(
 ) -> core::result::Result<AppIcon, Box<dyn std::error::Error>> {
     // Tray menu
     let close_btn = MenuItemBuilder::with_id("close", "Exit").build(app);
+    let shutdown_btn = MenuItemBuilder::with_id("shutdown", "Shut Down Portmaster").build(app);
     let open_btn = MenuItemBuilder::with_id("open", "Open").build(app);
 
     let spn = CheckMenuItemBuilder::with_id("spn", "Use SPN").build(app);
+    let spn_status = MenuItemBuilder::with_id("spn-status", "SPN: unknown")
+        .enabled(false)
+        .build(app);
 
     // Store the SPN button reference
     let mut button_ref = SPN_BUTTON.lock().unwrap();
     *button_ref = Some(spn.clone());
+    *SPN_STATUS.lock().unwrap() = Some(spn_status.clone());
 
     let force_show_window = MenuItemBuilder::with_id("force-show", "Force Show UI").build(app);
     let reload_btn = MenuItemBuilder::with_id("reload", "Reload User Interface").build(app);
+    let restart_btn = MenuItemBuilder::with_id("restart", "Restart Portmaster").build(app);
     let developer_menu = SubmenuBuilder::new(app, "Developer")
-        .items(&[&reload_btn, &force_show_window])
+        .items(&[&reload_btn, &force_show_window, &restart_btn])
         .build()?;
 
     // Drop the reference now so we unlock immediately.
@@ -70,9 +82,11 @@ pub fn setup_tray_menu(
     let menu = MenuBuilder::new(app)
         .items(&[
             &spn,
+            &spn_status,
             &PredefinedMenuItem::separator(app),
             &open_btn,
             &close_btn,
+            &shutdown_btn,
             &developer_menu,
         ])
         .build()?;
@@ -95,6 +109,32 @@ pub fn setup_tray_menu(
                         }
                     });
             }
+            "shutdown" => {
+                let handle = app.clone();
+                app.dialog()
+                    .message("This stops the Portmaster system service and all network protection")
+                    .title("Do you really want to shut down Portmaster?")
+                    .ok_button_label("Yes, shut down")
+                    .cancel_button_label("No")
+                    .show(move |answer| {
+                        if answer {
+                            let http = handle.portmaster().http();
+                            tauri::async_runtime::spawn(async move {
+                                if let Err(err) = http.shutdown().await {
+                                    error!("failed to shut down portmaster: {}", err);
+                                }
+                            });
+                        }
+                    });
+            }
+            "restart" => {
+                let http = app.portmaster().http();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = http.restart().await {
+                        error!("failed to restart portmaster: {}", err);
+                    }
+                });
+            }
             "open" => {
                 let _ = open_window(app);
             }
@@ -140,33 +180,107 @@ pub fn setup_tray_menu(
     Ok(icon)
 }
 
-pub fn update_icon(icon: AppIcon, subsystems: HashMap<String, Subsystem>, spn_status: String) {
-    // iterate over the subsytems and check if there's a module failure
-    let failure = subsystems
-        .values()
-        .into_iter()
-        .map(|s| s.failure_status)
-        .fold(
-            subsystem::FAILURE_NONE,
-            |acc, s| {
-                if s > acc {
-                    s
-                } else {
-                    acc
-                }
+/// The state of the Portmaster as shown by the tray icon and menu.
+#[derive(Debug, Default, Clone)]
+pub struct TrayState {
+    /// subsystems are keyed by their database key so we can handle deletes.
+    pub subsystems: HashMap<DbKey, Subsystem>,
+
+    pub spn: Option<SPNStatus>,
+
+    /// Whether the SPN is enabled in the configuration.
+    pub spn_enabled: bool,
+
+    /// Whether we're connected to the Portmaster API. The other fields are kept
+    /// while disconnected but are not shown since they might be outdated.
+    pub connected: bool,
+}
+
+impl TrayState {
+    /// Returns the icon for the highest failure status of all subsystems. If there
+    /// is no failure, the icon shows whether the SPN is in use.
+    pub fn icon(&self) -> &'static [u8] {
+        if !self.connected {
+            return RED_ICON;
+        }
+
+        // iterate over the subsytems and check if there's a module failure
+        let failure = self
+            .subsystems
+            .values()
+            .map(|s| s.failure_status)
+            .fold(
+                subsystem::FAILURE_NONE,
+                |acc, s| {
+                    if s > acc {
+                        s
+                    } else {
+                        acc
+                    }
+                },
+            );
+
+        match failure {
+            subsystem::FAILURE_WARNING => YELLOW_ICON,
+            subsystem::FAILURE_ERROR => RED_ICON,
+            _ => match self.spn.as_ref().map(|s| s.status.as_str()) {
+                Some("connected" | "connecting") => BLUE_ICON,
+                _ => GREEN_ICON,
             },
-        );
+        }
+    }
 
-    let next_icon = match failure {
-        subsystem::FAILURE_WARNING => YELLOW_ICON,
-        subsystem::FAILURE_ERROR => RED_ICON,
-        _ => match spn_status.as_str() {
-            "connected" | "connecting" => BLUE_ICON,
-            _ => GREEN_ICON,
-        },
-    };
+    /// Returns the SPN status line of the tray menu. If the SPN failed, the failure
+    /// message of the SPN subsystem is shown as the reason.
+    pub fn spn_status_line(&self) -> String {
+        let status = match &self.spn {
+            Some(status) if self.connected => status,
+            _ => return "SPN: unknown".to_string(),
+        };
+
+        let line = status.describe();
+
+        if status.status != "failed" {
+            return line;
+        }
+
+        let reason = self
+            .subsystems
+            .values()
+            .filter(|s| s.id == "spn")
+            .flat_map(|s| s.module_status.iter())
+            .find(|m| m.failure_status > subsystem::FAILURE_NONE && !m.failure_msg.is_empty());
+
+        match reason {
+            Some(module) => format!("{}: {}", line, module.failure_msg),
+            None => line,
+        }
+    }
+}
+
+/// Updates the icon and the SPN status line of the tray.
+pub fn update_icon(icon: &AppIcon, state: &TrayState) {
+    _ = icon.set_icon(Some(Icon::Raw(state.icon().to_vec())));
 
-    _ = icon.set_icon(Some(Icon::Raw(next_icon.to_vec())));
+    if let Some(item) = &*(SPN_STATUS.lock().unwrap()) {
+        _ = item.set_text(state.spn_status_line());
+    }
+}
+
+fn set_spn_checked(checked: bool) {
+    if let Some(btn) = &mut *(SPN_BUTTON.lock().unwrap()) {
+        _ = btn.set_checked(checked);
+    }
+}
+
+/// Switches the tray icon to red and unchecks the SPN button since we don't know
+/// the state of the Portmaster while we're disconnected.
+fn set_disconnected(app: &tauri::AppHandle) {
+    set_spn_checked(false);
+
+    if let Some(icon) = app.tray() {
+        update_icon(&icon, &TrayState::default());
+    }
 }
 
 pub async fn tray_handler(cli: PortAPI, app: tauri::AppHandle) {
@@ -178,13 +292,40 @@ pub async fn tray_handler(cli: PortAPI, app: tauri::AppHandle) {
         }
     };
 
+    _ = icon.set_icon(Some(Icon::Raw(BLUE_ICON.to_vec())));
+
+    let connection = app.portmaster().watch_connection_state();
+    let mut spn_checked = None;
+
+    watch_state(&cli, connection, |state| {
+        update_icon(&icon, state);
+
+        // only touch the button if the setting changed so we don't revert a click
+        // that has not been saved yet.
+        let checked = state.connected && state.spn_enabled;
+        if spn_checked != Some(checked) {
+            spn_checked = Some(checked);
+            set_spn_checked(checked);
+        }
+    })
+    .await;
+
+    set_disconnected(&app);
+}
+
+/// Subscribes to the records shown in the tray and calls update with the new state
+/// whenever one of them or the connection state changed. Returns once the
+/// subscriptions end.
+pub async fn watch_state<F: FnMut(&TrayState)>(
+    cli: &PortAPI,
+    mut connection: watch::Receiver<ConnectionState>,
+    mut update: F,
+) {
     let mut subsystem_subscription = match cli
-        .request(Request::QuerySubscribe(
-            "query runtime:subsystems/".to_string(),
-        ))
+        .subscribe::<Subsystem>(Query::new("runtime:subsystems/"))
         .await
     {
-        Ok(rx) => rx,
+        Ok(sub) => sub,
         Err(err) => {
             error!(
                 "cancel try_handler: failed to subscribe to 'runtime:subsystems': {}",
@@ -195,12 +336,10 @@ pub async fn tray_handler(cli: PortAPI, app: tauri::AppHandle) {
     };
 
     let mut spn_status_subscription = match cli
-        .request(Request::QuerySubscribe(
-            "query runtime:spn/status".to_string(),
-        ))
+        .subscribe::<SPNStatus>(Query::new("runtime:spn/status"))
         .await
     {
-        Ok(rx) => rx,
+        Ok(sub) => sub,
         Err(err) => {
             error!(
                 "cancel try_handler: failed to subscribe to 'runtime:spn/status': {}",
@@ -211,12 +350,10 @@ pub async fn tray_handler(cli: PortAPI, app: tauri::AppHandle) {
     };
 
     let mut spn_config_subscription = match cli
-        .request(Request::QuerySubscribe(
-            "query config:spn/enable".to_string(),
-        ))
+        .subscribe::<BooleanValue>(Query::new("config:spn/enable"))
         .await
     {
-        Ok(rx) => rx,
+        Ok(sub) => sub,
         Err(err) => {
             error!(
                 "cancel try_handler: failed to subscribe to 'runtime:spn/enable': {}",
@@ -226,119 +363,173 @@ pub async fn tray_handler(cli: PortAPI, app: tauri::AppHandle) {
         }
     };
 
-    _ = icon.set_icon(Some(Icon::Raw(BLUE_ICON.to_vec())));
-
-    let mut subsystems: HashMap<String, Subsystem> = HashMap::new();
-    let mut spn_status: String = "".to_string();
+    let mut state = TrayState {
+        connected: *connection.borrow_and_update() == ConnectionState::Connected,
+        ..Default::default()
+    };
 
     loop {
         tokio::select! {
-            msg = subsystem_subscription.recv() => {
+            Ok(()) = connection.changed() => {
+                state.connected = *connection.borrow_and_update() == ConnectionState::Connected;
+            },
+            msg = subsystem_subscription.next() => {
                 let msg = match msg {
                     Some(m) => m,
                     None => { break }
                 };
 
-                let res = match msg {
-                    Response::Ok(key, payload) => Some((key, payload)),
-                    Response::New(key, payload) => Some((key, payload)),
-                    Response::Update(key, payload) => Some((key, payload)),
-                    _ => None,
-                };
-
-                if let Some((_, payload)) = res {
-                    match payload.parse::<Subsystem>() {
-                        Ok(n) => {
-                            subsystems.insert(n.id.clone(), n);
-
-                            update_icon(icon.clone(), subsystems.clone(), spn_status.clone());
-                        },
-                        Err(err) => match err {
-                            ParseError::JSON(err) => {
-                                error!("failed to parse subsystem: {}", err);
-                            }
-                            _ => {
-                                error!("unknown error when parsing notifications payload");
-                            }
-                        },
+                match msg {
+                    RecordEvent::Initial(record, n) | RecordEvent::Created(record, n) | RecordEvent::Updated(record, n) => {
+                        state.subsystems.insert(record.key, n);
+                    },
+                    RecordEvent::Deleted(key) => {
+                        state.subsystems.remove(&key);
+                    },
+                    RecordEvent::Reconnected => {
+                        // all subsystems are sent again.
+                        state.subsystems.clear();
+                        continue;
+                    },
+                    other => {
+                        log_event("subsystem", other);
+                        continue;
                     }
                 }
             },
-            msg = spn_status_subscription.recv() => {
+            msg = spn_status_subscription.next() => {
                 let msg = match msg {
                     Some(m) => m,
                     None => { break }
                 };
 
-                let res = match msg {
-                    Response::Ok(key, payload) => Some((key, payload)),
-                    Response::New(key, payload) => Some((key, payload)),
-                    Response::Update(key, payload) => Some((key, payload)),
-                    _ => None,
-                };
-
-                if let Some((_, payload)) = res {
-                    match payload.parse::<SPNStatus>() {
-                        Ok(value) => {
-                            debug!("SPN status update: {}", value.status);
-                            spn_status = value.status.clone();
-
-                            update_icon(icon.clone(), subsystems.clone(), spn_status.clone());
-                        },
-                        Err(err) => match err {
-                            ParseError::JSON(err) => {
-                                error!("failed to parse spn status value: {}", err)
-                            },
-                            _ => {
-                                error!("unknown error when parsing spn status value")
-                            }
-                        }
+                match msg {
+                    RecordEvent::Initial(_, value) | RecordEvent::Created(_, value) | RecordEvent::Updated(_, value) => {
+                        debug!("SPN status update: {}", value.status);
+                        state.spn = Some(value);
+                    },
+                    RecordEvent::Deleted(_) => {
+                        debug!("SPN status deleted");
+                        state.spn = None;
+                    },
+                    RecordEvent::Reconnected => {
+                        state.spn = None;
+                        continue;
+                    },
+                    other => {
+                        log_event("spn status", other);
+                        continue;
                     }
                 }
             },
-            msg = spn_config_subscription.recv() => {
+            msg = spn_config_subscription.next() => {
                 let msg = match msg {
                     Some(m) => m,
                     None => { break }
                 };
 
-                let res = match msg {
-                    Response::Ok(key, payload) => Some((key, payload)),
-                    Response::New(key, payload) => Some((key, payload)),
-                    Response::Update(key, payload) => Some((key, payload)),
-                    _ => None,
+                let value = match msg {
+                    RecordEvent::Initial(_, value) | RecordEvent::Created(_, value) | RecordEvent::Updated(_, value) => value.value,
+                    RecordEvent::Deleted(_) => None,
+                    other => {
+                        log_event("spn config", other);
+                        continue;
+                    }
                 };
 
-                if let Some((_, payload)) = res {
-                    match payload.parse::<BooleanValue>() {
-                        Ok(value) => {
-                            let mut btn = SPN_BUTTON.lock().unwrap();
-
-                            if let Some(btn) = &mut *btn {
-                                if let Some(value) = value.value {
-                                    _ = btn.set_checked(value);
-                                } else {
-                                    _ = btn.set_checked(false);
-                                }
-                            }
-                        },
-                        Err(err) => match err {
-                            ParseError::JSON(err) => {
-                                error!("failed to parse config value: {}", err)
-                            },
-                            _ => {
-                                error!("unknown error when parsing config value")
-                            }
-                        }
-                    }
-                }
+                state.spn_enabled = value.unwrap_or(false);
             }
         }
+
+        update(&state);
     }
+}
 
-    if let Some(btn) = &mut *(SPN_BUTTON.lock().unwrap()) {
-        _ = btn.set_checked(false);
+/// Logs record events that don't carry a record.
+fn log_event<T>(name: &str, event: RecordEvent<T>) {
+    match event {
+        RecordEvent::Error(err) => error!("{} subscription: {}", name, err),
+        RecordEvent::Warning(msg) => warn!("{} subscription: {}", name, msg),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portapi::{client::connect, mock::MockServer};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn subsystem(id: &str, failure_status: u8, failure_msg: &str) -> serde_json::Value {
+        json!({
+            "ID": id, "Name": id, "Description": "",
+            "Modules": [{
+                "Name": id, "Enabled": true, "Status": 2, "FailureStatus": failure_status,
+                "FailureID": "", "FailureMsg": failure_msg,
+            }],
+            "FailureStatus": failure_status,
+        })
+    }
+
+    async fn next(states: &mut UnboundedReceiver<TrayState>) -> TrayState {
+        tokio::time::timeout(Duration::from_secs(5), states.recv())
+            .await
+            .expect("timeout waiting for tray state")
+            .expect("watch_state returned")
     }
 
-    _ = icon.set_icon(Some(Icon::Raw(RED_ICON.to_vec())));
+    #[tokio::test]
+    async fn tray_state() {
+        let server = MockServer::start().await;
+        let api = connect(server.uri()).await.unwrap();
+
+        server.set("runtime:subsystems/core", &subsystem("core", 0, ""));
+
+        let (connection_tx, connection) = watch::channel(ConnectionState::Connected);
+        let (tx, mut states) = unbounded_channel();
+        tokio::spawn(async move {
+            watch_state(&api, connection, |state| {
+                let _ = tx.send(state.clone());
+            })
+            .await;
+        });
+
+        let state = next(&mut states).await;
+        assert_eq!(state.icon(), GREEN_ICON);
+        assert_eq!(state.spn_status_line(), "SPN: unknown");
+
+        server.set("config:spn/enable", &json!({"Value": true}));
+        assert!(next(&mut states).await.spn_enabled);
+
+        server.set("runtime:spn/status", &json!({"Status": "connecting"}));
+        assert_eq!(next(&mut states).await.icon(), BLUE_ICON);
+
+        server.set("runtime:spn/status", &json!({"Status": "failed"}));
+        next(&mut states).await;
+        server.set(
+            "runtime:subsystems/spn",
+            &subsystem("spn", subsystem::FAILURE_ERROR, "no home hub"),
+        );
+        let state = next(&mut states).await;
+        assert_eq!(state.icon(), RED_ICON);
+        assert_eq!(state.spn_status_line(), "SPN: failed: no home hub");
+
+        server.delete("runtime:subsystems/spn");
+        server.set("config:spn/enable", &json!({"Value": false}));
+        next(&mut states).await;
+        let state = next(&mut states).await;
+        assert_eq!(state.icon(), GREEN_ICON);
+        assert_eq!(state.subsystems.len(), 1);
+        assert!(!state.spn_enabled);
+
+        connection_tx.send_replace(ConnectionState::Reconnecting { attempt: 1 });
+        let state = next(&mut states).await;
+        assert_eq!(state.icon(), RED_ICON);
+        assert_eq!(state.spn_status_line(), "SPN: unknown");
+
+        connection_tx.send_replace(ConnectionState::Connected);
+        assert_eq!(next(&mut states).await.icon(), GREEN_ICON);
+    }
 }
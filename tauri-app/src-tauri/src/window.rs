@@ -1,5 +1,7 @@
 use log::{debug, error};
-use tauri::{AppHandle, Manager, Result, UserAttentionType, Window, WindowBuilder, WindowUrl};
+use tauri::{
+    AppHandle, Manager, Result, Runtime, UserAttentionType, Window, WindowBuilder, WindowUrl,
+};
 
 use crate::portmaster::PortmasterExt;
 
@@ -11,7 +13,7 @@ use crate::portmaster::PortmasterExt;
 /// if ::websocket::is_portapi_reachable returns true.
 ///
 /// Either the existing or the newly created window is returned.
-pub fn create_main_window(app: &AppHandle) -> Result<Window> {
+pub fn create_main_window<R: Runtime>(app: &AppHandle<R>) -> Result<Window<R>> {
     let mut window = if let Some(window) = app.get_window("main") {
         debug!("[tauri] main window already created");
 
@@ -43,7 +45,7 @@ pub fn create_main_window(app: &AppHandle) -> Result<Window> {
     may_navigate_to_ui(&mut window, false);
 
     #[cfg(debug_assertions)]
-    if let Ok(_) = std::env::var("TAURI_SHOW_IMMEDIATELY") {
+    if std::env::var("TAURI_SHOW_IMMEDIATELY").is_ok() {
         debug!("[tauri] TAURI_SHOW_IMMEDIATELY is set, opening window");
 
         if let Err(err) = window.show() {
@@ -54,7 +56,7 @@ pub fn create_main_window(app: &AppHandle) -> Result<Window> {
     Ok(window)
 }
 
-pub fn create_splash_window(app: &AppHandle) -> Result<Window> {
+pub fn create_splash_window<R: Runtime>(app: &AppHandle<R>) -> Result<Window<R>> {
     if let Some(window) = app.get_window("splash") {
         let _ = window.show();
         Ok(window)
@@ -75,11 +77,11 @@ pub fn create_splash_window(app: &AppHandle) -> Result<Window> {
     }
 }
 
-pub fn close_splash_window(app: &AppHandle) -> Result<()> {
+pub fn close_splash_window<R: Runtime>(app: &AppHandle<R>) -> Result<()> {
     if let Some(window) = app.get_window("splash") {
         return window.close();
     }
-    return Err(tauri::Error::WindowNotFound);
+    Err(tauri::Error::WindowNotFound)
 }
 
 /// Opens a window for the tauri application.
@@ -94,7 +96,7 @@ pub fn close_splash_window(app: &AppHandle) -> Result<()> {
 ///
 /// If the Portmaster API is unreachable and there's no main window yet, we show the
 /// splash-screen window.
-pub fn open_window(app: &AppHandle) -> Result<Window> {
+pub fn open_window<R: Runtime>(app: &AppHandle<R>) -> Result<Window<R>> {
     if app.portmaster().is_reachable() {
         match app.get_window("main") {
             Some(win) => {
@@ -119,33 +121,20 @@ pub fn open_window(app: &AppHandle) -> Result<Window> {
 ///
 /// Note that only happens if the window URL does not already point to the PM API.
 ///
-/// In #[cfg(debug_assertions)] the TAURI_PM_URL environment variable will be used
-/// if set.
-/// Otherwise or in release builds, it will be navigated to http://127.0.0.1:817.
-pub fn may_navigate_to_ui(win: &mut Window, force: bool) {
+/// The target URL is configured using the --ui-url flag, see
+/// `PortmasterPlugin::ui_url` for details.
+pub fn may_navigate_to_ui<R: Runtime>(win: &mut Window<R>, force: bool) {
     if !win.app_handle().portmaster().is_reachable() && !force {
         error!("[tauri] portmaster API is not reachable, not navigating");
 
         return;
     }
 
-    if force || cfg!(debug_assertions) || win.url().host_str() != Some("localhost") {
-        #[cfg(debug_assertions)]
-        if let Ok(target_url) = std::env::var("TAURI_PM_URL") {
-            debug!("[tauri] navigating to {}", target_url);
+    let target_url = win.app_handle().portmaster().ui_url();
 
-            win.navigate(target_url.parse().unwrap());
+    if force || cfg!(debug_assertions) || win.url().origin() != target_url.origin() {
+        debug!("[tauri] navigating to {}", target_url);
 
-            return;
-        }
-
-        #[cfg(debug_assertions)]
-        {
-            debug!("[tauri] navigating to http://localhost:4200");
-            win.navigate("http://localhost:4200".parse().unwrap());
-        }
-
-        #[cfg(not(debug_assertions))]
-        win.navigate("http://localhost:817".parse().unwrap());
+        win.navigate(target_url);
     }
 }